@@ -1,3 +1,12 @@
 fn main() {
     build_info_build::build_script();
+
+    // Updates are cloned for every subscriber, Bytes makes it cheap for the payload
+    let mut config = prost_build::Config::new();
+    config.bytes(["."]);
+
+    tonic_build::configure()
+        .build_client(false)
+        .compile_with_config(config, &["proto/geyser_neon.proto"], &["proto"])
+        .expect("Failed to compile the gRPC protos");
 }
@@ -1,5 +1,52 @@
+use serde_derive::Serialize;
+
 build_info::build_info!(fn build_info);
 
+/// Build details exported as the build info metric and served on /version
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub name: String,
+    pub version: String,
+    pub git_commit: String,
+    pub git_branch: String,
+    pub rustc_version: String,
+    pub opt_level: String,
+    pub build_timestamp: String,
+}
+
+impl VersionInfo {
+    pub fn labels(&self) -> Vec<(String, String)> {
+        vec![
+            ("version".to_string(), self.version.clone()),
+            ("git_commit".to_string(), self.git_commit.clone()),
+            ("git_branch".to_string(), self.git_branch.clone()),
+            ("rustc_version".to_string(), self.rustc_version.clone()),
+        ]
+    }
+}
+
+pub fn get_version_info() -> VersionInfo {
+    let build_info = build_info();
+    let git_info = build_info
+        .version_control
+        .as_ref()
+        .and_then(|version_control| version_control.git());
+
+    VersionInfo {
+        name: build_info.crate_info.name.to_string(),
+        version: build_info.crate_info.version.to_string(),
+        git_commit: git_info
+            .map(|git_info| git_info.commit_id.clone())
+            .unwrap_or_else(|| "unknown".to_string()),
+        git_branch: git_info
+            .and_then(|git_info| git_info.branch.clone())
+            .unwrap_or_else(|| "unknown".to_string()),
+        rustc_version: build_info.compiler.version.to_string(),
+        opt_level: build_info.optimization_level.to_string(),
+        build_timestamp: build_info.timestamp.to_rfc3339(),
+    }
+}
+
 pub fn get_build_info() -> String {
     let build_info = build_info();
     let mut build_info_string = String::new();
@@ -0,0 +1,229 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{error, log_enabled, Level};
+use solana_metrics::{datapoint::DataPoint, submit};
+
+use crate::kafka_producer_stats::{QueueStats, Stats};
+
+/// Periodically submits the plugin counters through solana-metrics, which sends them
+/// to the InfluxDB configured with the SOLANA_METRICS_CONFIG environment variable.
+/// The counters are cumulative since the plugin has been loaded.
+pub async fn report_datapoints(
+    stats: Arc<Stats>,
+    interval: Duration,
+    should_stop: Arc<AtomicBool>,
+) {
+    let mut interval = tokio::time::interval(interval);
+
+    while !should_stop.load(Ordering::Relaxed) {
+        interval.tick().await;
+        // Same as datapoint_info!, the datapoints are only submitted when info is logged
+        if log_enabled!(Level::Info) {
+            stats.refresh_queues();
+            for datapoint in datapoints(&stats) {
+                submit(datapoint, Level::Info);
+            }
+        }
+    }
+
+    // Flushing blocks until the points have been written to InfluxDB
+    if let Err(e) = tokio::task::spawn_blocking(solana_metrics::flush).await {
+        error!("Failed to flush the solana-metrics datapoints, error: {e}");
+    }
+}
+
+/// The datapoints of the message counters and of every internal queue
+fn datapoints(stats: &Stats) -> Vec<DataPoint> {
+    let mut datapoints = vec![messages_datapoint(stats)];
+    datapoints.extend(
+        stats
+            .queues()
+            .into_iter()
+            .map(|(stream, queue)| queue_datapoint(stream, queue)),
+    );
+    datapoints
+}
+
+fn messages_datapoint(stats: &Stats) -> DataPoint {
+    let fields = [
+        ("update_account_sent", stats.kafka_update_account.get()),
+        ("update_slot_sent", stats.kafka_update_slot.get()),
+        (
+            "notify_transaction_sent",
+            stats.kafka_notify_transaction.get(),
+        ),
+        ("notify_block_sent", stats.kafka_notify_block.get()),
+        (
+            "update_account_unsent",
+            stats.kafka_error_update_account.get(),
+        ),
+        ("update_slot_unsent", stats.kafka_error_update_slot.get()),
+        (
+            "notify_transaction_unsent",
+            stats.kafka_error_notify_transaction.get(),
+        ),
+        ("notify_block_unsent", stats.kafka_error_notify_block.get()),
+        ("serialize_errors", stats.kafka_error_serialize.get()),
+        ("compress_errors", stats.kafka_error_compress.get()),
+        ("bytes_sent", stats.kafka_bytes_tx.get()),
+        ("chunked_messages", stats.kafka_chunked_messages.get()),
+        (
+            "account_data_keyframes",
+            stats.kafka_account_data_keyframes.get(),
+        ),
+        ("account_data_deltas", stats.kafka_account_data_deltas.get()),
+    ];
+
+    let mut datapoint = DataPoint::new("geyser_neon_kafka_messages");
+    for (name, value) in fields {
+        datapoint.add_field_i64(name, value as i64);
+    }
+    datapoint
+}
+
+fn queue_datapoint(stream: &str, queue: &QueueStats) -> DataPoint {
+    DataPoint::new("geyser_neon_kafka_queue")
+        .add_tag("stream", stream)
+        .add_field_i64("queue_len", queue.queue_len.get() as i64)
+        .add_field_i64("queue_len_max", queue.queue_len_max.get() as i64)
+        .add_field_i64("in_flight", queue.in_flight.get() as i64)
+        .add_field_i64("in_flight_max", queue.in_flight_max.get() as i64)
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use log::{LevelFilter, Log, Metadata, Record};
+
+    use super::*;
+
+    struct TestLogger;
+
+    impl Log for TestLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, _record: &Record) {}
+
+        fn flush(&self) {}
+    }
+
+    /// Accepts the InfluxDB write requests and passes their bodies to the channel
+    fn start_influx_stub() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).unwrap();
+                stream
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+                let _ = tx.send(String::from_utf8_lossy(&body).into_owned());
+            }
+        });
+
+        (format!("http://{address}"), rx)
+    }
+
+    // solana-metrics reads its configuration once per process, run with
+    // `cargo test -- --ignored datapoints` so no other test submits datapoints first
+    #[tokio::test]
+    #[ignore]
+    async fn datapoints_are_written_to_influxdb() {
+        let (host, rx) = start_influx_stub();
+        std::env::set_var(
+            "SOLANA_METRICS_CONFIG",
+            format!("host={host},db=geyser,u=user,p=password"),
+        );
+        log::set_logger(&TestLogger).unwrap();
+        log::set_max_level(LevelFilter::Info);
+
+        let stats = Arc::new(Stats::default());
+        stats.kafka_update_account.inc_by(3);
+        let should_stop = Arc::new(AtomicBool::new(false));
+
+        let report = tokio::spawn(report_datapoints(
+            stats,
+            Duration::from_millis(10),
+            should_stop.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        should_stop.store(true, Ordering::Relaxed);
+        report.await.unwrap();
+
+        let body: String = rx.try_iter().collect();
+        assert!(body.contains("geyser_neon_kafka_messages"));
+        assert!(body.contains("update_account_sent=3i"));
+        assert!(body.contains("stream=update_account queue_len=0i"));
+    }
+
+    fn field<'a>(datapoint: &'a DataPoint, name: &str) -> Option<&'a str> {
+        datapoint
+            .fields
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn datapoints_carry_the_counters_and_queues() {
+        let stats = Stats::default();
+        stats.kafka_update_account.inc_by(3);
+        stats.kafka_error_notify_block.inc();
+        stats.kafka_bytes_tx.inc_by(1000);
+        stats.update_slot_queue.set_queue_len(5);
+        stats.update_slot_queue.task_started();
+
+        let datapoints = datapoints(&stats);
+        assert_eq!(datapoints.len(), 5);
+
+        let messages = &datapoints[0];
+        assert_eq!(messages.name, "geyser_neon_kafka_messages");
+        assert_eq!(field(messages, "update_account_sent"), Some("3i"));
+        assert_eq!(field(messages, "notify_block_unsent"), Some("1i"));
+        assert_eq!(field(messages, "bytes_sent"), Some("1000i"));
+        assert_eq!(field(messages, "update_slot_sent"), Some("0i"));
+
+        let queue = datapoints
+            .iter()
+            .find(|datapoint| datapoint.tags == [("stream", "update_slot".to_string())])
+            .unwrap();
+        assert_eq!(queue.name, "geyser_neon_kafka_queue");
+        assert_eq!(field(queue, "queue_len"), Some("5i"));
+        assert_eq!(field(queue, "queue_len_max"), Some("5i"));
+        assert_eq!(field(queue, "in_flight"), Some("1i"));
+    }
+}
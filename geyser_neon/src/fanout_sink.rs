@@ -0,0 +1,530 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+use kafka_common::message_type::{GetMessageType, MessageType};
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot, Semaphore},
+};
+
+use crate::{
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, SinkInstanceConfig, DEFAULT_SINK_MAX_IN_FLIGHT,
+        DEFAULT_SINK_QUEUE_CAPACITY,
+    },
+    kafka_producer_stats::{ContextWithStats, SinkStats, Stats},
+    sink::{create_sink_of_kind, Sink, SinkError, SinkHealth, SinkMessage, SinkRecord},
+};
+
+/// What happens to a message when the queue of a sink is full
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum BackpressurePolicy {
+    /// Wait until there is room in the queue, the other sinks get the message meanwhile
+    #[default]
+    Block,
+    /// Drop the message for this sink only, it counts as published by this sink
+    Drop,
+}
+
+struct SinkFilter {
+    streams: HashSet<MessageType>,
+    account_pubkeys: HashSet<Vec<u8>>,
+    account_owners: HashSet<Vec<u8>>,
+    include_vote_transactions: bool,
+}
+
+fn decode_pubkeys(sink: &str, pubkeys: &[String]) -> Result<HashSet<Vec<u8>>, SinkError> {
+    pubkeys
+        .iter()
+        .map(|pubkey| {
+            bs58::decode(pubkey).into_vec().map_err(|e| {
+                SinkError::Other(format!("Wrong pubkey {pubkey} of sink {sink}, error: {e}"))
+            })
+        })
+        .collect()
+}
+
+impl SinkFilter {
+    fn new(config: &SinkInstanceConfig) -> Result<Self, SinkError> {
+        Ok(SinkFilter {
+            streams: config.streams.iter().copied().collect(),
+            account_pubkeys: decode_pubkeys(&config.name, &config.account_pubkeys)?,
+            account_owners: decode_pubkeys(&config.name, &config.account_owners)?,
+            include_vote_transactions: config.include_vote_transactions,
+        })
+    }
+
+    fn matches(&self, message: &SinkMessage) -> bool {
+        if !self.streams.is_empty() && !self.streams.contains(&message.get_type()) {
+            return false;
+        }
+
+        match message {
+            SinkMessage::UpdateAccount(update_account) => {
+                (self.account_pubkeys.is_empty() && self.account_owners.is_empty())
+                    || self
+                        .account_pubkeys
+                        .contains(update_account.account.pubkey())
+                    || self.account_owners.contains(update_account.account.owner())
+            }
+            SinkMessage::NotifyTransaction(_) => {
+                self.include_vote_transactions || !message.is_vote_transaction()
+            }
+            SinkMessage::UpdateSlotStatus(_) | SinkMessage::NotifyBlockMetaData(_) => true,
+        }
+    }
+}
+
+// The account data is compressed and delta encoded once for all the sinks,
+// before the messages are fanned out
+const SHARED_SETTINGS_PREFIXES: [&str; 2] = ["account_data_compression", "account_data_delta_"];
+
+/// The plugin configuration with the settings of the sink instance applied on top of it
+fn instance_config(
+    config: &GeyserPluginKafkaConfig,
+    instance: &SinkInstanceConfig,
+) -> Result<GeyserPluginKafkaConfig, SinkError> {
+    if let Some(key) = instance.settings.keys().find(|key| {
+        SHARED_SETTINGS_PREFIXES
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+    }) {
+        return Err(SinkError::Other(format!(
+            "{key} of sink {} cannot be set per sink, it applies to all the sinks",
+            instance.name
+        )));
+    }
+
+    let wrong_settings = |e: serde_json::Error| {
+        SinkError::Other(format!("Wrong settings of sink {}: {e}", instance.name))
+    };
+
+    let mut value = serde_json::to_value(config).map_err(wrong_settings)?;
+    if let Value::Object(settings) = &mut value {
+        settings.remove("sinks");
+        settings.extend(instance.settings.clone());
+    }
+    serde_json::from_value(value).map_err(wrong_settings)
+}
+
+/// A record in the queue of a sink, with the sender of the result of its publish
+struct QueuedRecord {
+    record: Arc<SinkRecord>,
+    ack: oneshot::Sender<Result<(), SinkError>>,
+}
+
+struct SinkInstance {
+    name: String,
+    sink: Arc<dyn Sink>,
+    filter: SinkFilter,
+    backpressure: BackpressurePolicy,
+    tx: mpsc::Sender<QueuedRecord>,
+    // Messages queued or being published
+    pending: Arc<AtomicUsize>,
+}
+
+impl SinkInstance {
+    fn start(
+        instance: &SinkInstanceConfig,
+        sink: Arc<dyn Sink>,
+        stats: Arc<Stats>,
+        runtime: &Runtime,
+    ) -> Result<Self, SinkError> {
+        let queue_capacity = instance
+            .queue_capacity
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_SINK_QUEUE_CAPACITY)
+            .max(1);
+        let max_in_flight = instance
+            .max_in_flight
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_SINK_MAX_IN_FLIGHT)
+            .max(1);
+
+        let (tx, rx) = mpsc::channel(queue_capacity);
+        let pending = Arc::new(AtomicUsize::new(0));
+        runtime.spawn(publish_queued(
+            instance.name.clone(),
+            sink.clone(),
+            rx,
+            max_in_flight,
+            pending.clone(),
+            stats,
+        ));
+
+        Ok(SinkInstance {
+            name: instance.name.clone(),
+            sink,
+            filter: SinkFilter::new(instance)?,
+            backpressure: instance.backpressure,
+            tx,
+            pending,
+        })
+    }
+}
+
+/// Takes the messages from the queue of a sink and publishes up to `max_in_flight` of them concurrently
+async fn publish_queued(
+    name: String,
+    sink: Arc<dyn Sink>,
+    mut rx: mpsc::Receiver<QueuedRecord>,
+    max_in_flight: usize,
+    pending: Arc<AtomicUsize>,
+    stats: Arc<Stats>,
+) {
+    let in_flight = Arc::new(Semaphore::new(max_in_flight));
+    let sink_labels = SinkStats::sink_labels(&name);
+
+    while let Some(QueuedRecord { record, ack }) = rx.recv().await {
+        stats.sinks.queue_len.get_or_create(&sink_labels).dec();
+        let permit = match in_flight.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        stats.sinks.in_flight.get_or_create(&sink_labels).inc();
+
+        let name = name.clone();
+        let sink = sink.clone();
+        let pending = pending.clone();
+        let stats = stats.clone();
+        let sink_labels = sink_labels.clone();
+        tokio::spawn(async move {
+            let message_type = record.message_type();
+            let labels = SinkStats::labels(&name, &message_type);
+            let result = sink.publish(record).await;
+            match &result {
+                Ok(()) => {
+                    stats.sinks.published.get_or_create(&labels).inc();
+                }
+                Err(e) => {
+                    stats.sinks.failed.get_or_create(&labels).inc();
+                    error!("Sink {name} cannot send {message_type} message, error: {e}");
+                }
+            }
+            stats.sinks.in_flight.get_or_create(&sink_labels).dec();
+            pending.fetch_sub(1, Ordering::Relaxed);
+            drop(permit);
+            let _ = ack.send(result);
+        });
+    }
+}
+
+/// Publishes every message to several sinks. Every sink has its own filter, queue and
+/// workers, and a message is queued for all the sinks concurrently, so a slow sink doesn't
+/// delay the deliveries of the others. The publish of a message completes once all its sinks
+/// have published it, so the delivery metrics, the slot acknowledgements and the order of the
+/// account updates follow the slowest sink
+pub struct FanOutSink {
+    instances: Vec<SinkInstance>,
+    stats: Arc<Stats>,
+}
+
+impl FanOutSink {
+    pub fn new(
+        config: &GeyserPluginKafkaConfig,
+        ctx_stats: ContextWithStats,
+        runtime: &Runtime,
+    ) -> Result<Self, SinkError> {
+        let stats = ctx_stats.stats.clone();
+        let mut names = HashSet::new();
+        let mut instances = Vec::with_capacity(config.sinks.len());
+
+        for instance in &config.sinks {
+            if !names.insert(instance.name.as_str()) {
+                return Err(SinkError::Other(format!(
+                    "Sink name {} is used more than once",
+                    instance.name
+                )));
+            }
+
+            let sink = create_sink_of_kind(
+                instance.sink,
+                Arc::new(instance_config(config, instance)?),
+                ctx_stats.clone(),
+                runtime,
+            )?;
+
+            info!(
+                "Messages will be published to the {} sink {} with the {:?} backpressure policy",
+                sink.name(),
+                instance.name,
+                instance.backpressure
+            );
+
+            instances.push(SinkInstance::start(instance, sink, stats.clone(), runtime)?);
+        }
+
+        Ok(FanOutSink { instances, stats })
+    }
+
+    fn queued(&self, instance: &SinkInstance) {
+        instance.pending.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .sinks
+            .queue_len
+            .get_or_create(&SinkStats::sink_labels(&instance.name))
+            .inc();
+    }
+
+    fn not_queued(&self, instance: &SinkInstance) {
+        instance.pending.fetch_sub(1, Ordering::Relaxed);
+        self.stats
+            .sinks
+            .queue_len
+            .get_or_create(&SinkStats::sink_labels(&instance.name))
+            .dec();
+    }
+
+    /// Queues the record for a sink and waits until the sink has published it
+    async fn publish_to(
+        &self,
+        instance: &SinkInstance,
+        record: &Arc<SinkRecord>,
+    ) -> Result<(), SinkError> {
+        let stopped = || SinkError::Other(format!("Sink {} has stopped", instance.name));
+        let (ack, ack_rx) = oneshot::channel();
+        let queued = QueuedRecord {
+            record: record.clone(),
+            ack,
+        };
+
+        self.queued(instance);
+        let sent = match instance.backpressure {
+            BackpressurePolicy::Block => instance.tx.send(queued).await.is_ok(),
+            BackpressurePolicy::Drop => match instance.tx.try_send(queued) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    self.not_queued(instance);
+                    self.stats
+                        .sinks
+                        .dropped
+                        .get_or_create(&SinkStats::labels(&instance.name, &record.message_type()))
+                        .inc();
+                    return Ok(());
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            },
+        };
+
+        if !sent {
+            self.not_queued(instance);
+            return Err(stopped());
+        }
+        ack_rx.await.unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+#[async_trait]
+impl Sink for FanOutSink {
+    fn name(&self) -> &'static str {
+        "fanout"
+    }
+
+    /// Resolves once every sink the record matches has published it, or dropped it with the
+    /// `Drop` policy. The error of a single failed sink is returned as is, so the delivery
+    /// errors keep their reason
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let publishes = self
+            .instances
+            .iter()
+            .filter(|instance| instance.filter.matches(&record.message))
+            .map(|instance| self.publish_to(instance, &record));
+
+        let mut errors: Vec<SinkError> = join_all(publishes)
+            .await
+            .into_iter()
+            .filter_map(Result::err)
+            .collect();
+
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(SinkError::Other(
+                errors
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            )),
+        }
+    }
+
+    async fn flush(&self, timeout: Duration) -> Result<(), SinkError> {
+        let deadline = Instant::now() + timeout;
+
+        for instance in &self.instances {
+            while instance.pending.load(Ordering::Relaxed) > 0 {
+                if Instant::now() >= deadline {
+                    return Err(SinkError::Other(format!(
+                        "Sink {} still has {} messages to publish",
+                        instance.name,
+                        instance.pending.load(Ordering::Relaxed)
+                    )));
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            instance
+                .sink
+                .flush(deadline.saturating_duration_since(Instant::now()))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    fn health(&self) -> SinkHealth {
+        let problems: Vec<String> = self
+            .instances
+            .iter()
+            .filter_map(|instance| match instance.sink.health() {
+                SinkHealth::Healthy => None,
+                SinkHealth::Unhealthy(reason) => Some(format!("{}: {reason}", instance.name)),
+            })
+            .collect();
+
+        if problems.is_empty() {
+            SinkHealth::Healthy
+        } else {
+            SinkHealth::Unhealthy(problems.join("; "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        geyser_neon_config::tests::test_config,
+        sink::{tests::slot_record, SinkKind},
+    };
+
+    /// Publishes after a delay, and fails every publish when `fail` is set
+    struct SlowSink {
+        published: AtomicUsize,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl Sink for SlowSink {
+        fn name(&self) -> &'static str {
+            "slow"
+        }
+
+        async fn publish(&self, _record: Arc<SinkRecord>) -> Result<(), SinkError> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            if self.fail {
+                return Err(SinkError::Other("broken".to_string()));
+            }
+            self.published.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+            Ok(())
+        }
+
+        fn health(&self) -> SinkHealth {
+            SinkHealth::Healthy
+        }
+    }
+
+    fn fanout(runtime: &Runtime, sinks: &[(&str, &Arc<SlowSink>)]) -> FanOutSink {
+        let stats = Arc::new(Stats::default());
+        let instances = sinks
+            .iter()
+            .map(|(name, sink)| {
+                let mut config = instance(json!({}));
+                config.name = name.to_string();
+                SinkInstance::start(&config, (*sink).clone(), stats.clone(), runtime).unwrap()
+            })
+            .collect();
+        FanOutSink { instances, stats }
+    }
+
+    fn slow_sink(fail: bool) -> Arc<SlowSink> {
+        Arc::new(SlowSink {
+            published: AtomicUsize::new(0),
+            fail,
+        })
+    }
+
+    fn instance(settings: Value) -> SinkInstanceConfig {
+        serde_json::from_value(json!({
+            "name": "archive",
+            "sink": SinkKind::File,
+            "settings": settings,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn instance_settings_override_the_config() {
+        let config = test_config(json!({}));
+        let instance_config = instance_config(
+            &config,
+            &instance(json!({ "file_sink_directory": "/tmp/archive" })),
+        )
+        .unwrap();
+
+        assert_eq!(instance_config.file_sink_directory, "/tmp/archive");
+        assert_eq!(instance_config.brokers_list, config.brokers_list);
+    }
+
+    #[test]
+    fn shared_settings_are_rejected() {
+        let config = test_config(json!({}));
+        for settings in [
+            json!({ "account_data_compression": "Zstd" }),
+            json!({ "account_data_compression_level": "3" }),
+            json!({ "account_data_delta_keyframe_interval": "10" }),
+        ] {
+            assert!(instance_config(&config, &instance(settings)).is_err());
+        }
+    }
+
+    #[test]
+    fn publish_waits_for_every_sink() {
+        let runtime = Runtime::new().unwrap();
+        let (archive, replica) = (slow_sink(false), slow_sink(false));
+        let sink = fanout(&runtime, &[("archive", &archive), ("replica", &replica)]);
+
+        runtime.block_on(sink.publish(slot_record(1))).unwrap();
+        assert_eq!(archive.published.load(Ordering::Relaxed), 1);
+        assert_eq!(replica.published.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn publish_fails_when_a_sink_fails() {
+        let runtime = Runtime::new().unwrap();
+        let (archive, broken) = (slow_sink(false), slow_sink(true));
+        let sink = fanout(&runtime, &[("archive", &archive), ("broken", &broken)]);
+
+        let error = runtime.block_on(sink.publish(slot_record(1))).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            SinkError::Other("broken".to_string()).to_string()
+        );
+        assert_eq!(archive.published.load(Ordering::Relaxed), 1);
+
+        let broken_again = slow_sink(true);
+        let sink = fanout(
+            &runtime,
+            &[("broken", &broken), ("broken_again", &broken_again)],
+        );
+        assert!(runtime.block_on(sink.publish(slot_record(2))).is_err());
+    }
+}
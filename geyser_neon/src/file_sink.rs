@@ -0,0 +1,483 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use kafka_common::message_type::MessageType;
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+
+use crate::{
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, DEFAULT_FILE_SINK_MAX_SEGMENT_BYTES,
+        DEFAULT_FILE_SINK_SEGMENT_SLOTS,
+    },
+    kafka_producer_stats::Stats,
+    s3_uploader::S3Uploader,
+    sink::{BlockingWriter, LastError, Sink, SinkError, SinkHealth, SinkRecord, WriterThread},
+};
+
+/// Layout of the records in the segment files
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FileFormat {
+    /// One JSON object per line: `{"type":"UpdateAccount","message":{...}}`
+    #[default]
+    Ndjson,
+    /// A message type byte, the payload length as u32 LE and the JSON payload
+    LengthPrefixed,
+}
+
+impl FileFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Ndjson => "ndjson",
+            FileFormat::LengthPrefixed => "bin",
+        }
+    }
+}
+
+fn message_type_tag(message_type: &MessageType) -> u8 {
+    match message_type {
+        MessageType::UpdateAccount => 0,
+        MessageType::UpdateSlot => 1,
+        MessageType::NotifyTransaction => 2,
+        MessageType::NotifyBlock => 3,
+    }
+}
+
+/// A segment file together with its index. The index is a sequence of (slot, offset) pairs,
+/// both u64 LE, pointing to the first record of every slot in the segment.
+///
+/// The messages are published concurrently, so the slots are not in order: the records of a
+/// slot can be interleaved with other slots and the index is in the order the slots were first
+/// written, not sorted by slot
+struct Segment {
+    path: PathBuf,
+    index_path: PathBuf,
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    size: u64,
+    start_slot: u64,
+    first_slot: u64,
+    end_slot: u64,
+    created_ms: i64,
+    extension: &'static str,
+    indexed_slots: HashSet<u64>,
+}
+
+impl Segment {
+    fn create(directory: &Path, format: FileFormat, start_slot: u64) -> io::Result<Self> {
+        let created_ms = Utc::now().timestamp_millis();
+        let name = format!("{start_slot:012}-{created_ms}");
+        let path = directory.join(format!("{name}.{}", format.extension()));
+        let index_path = directory.join(format!("{name}.idx"));
+        let data = BufWriter::new(File::create(&path)?);
+        let index = BufWriter::new(File::create(&index_path)?);
+        info!("Writing segment {}", path.display());
+
+        Ok(Segment {
+            path,
+            index_path,
+            data,
+            index,
+            size: 0,
+            start_slot,
+            first_slot: u64::MAX,
+            end_slot: start_slot,
+            created_ms,
+            extension: format.extension(),
+            indexed_slots: HashSet::new(),
+        })
+    }
+
+    fn write(&mut self, slot: u64, record: &[u8]) -> io::Result<()> {
+        if self.indexed_slots.insert(slot) {
+            self.index.write_all(&slot.to_le_bytes())?;
+            self.index.write_all(&self.size.to_le_bytes())?;
+        }
+        self.data.write_all(record)?;
+        self.size += record.len() as u64;
+        self.first_slot = self.first_slot.min(slot);
+        self.end_slot = self.end_slot.max(slot);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.data.flush()?;
+        self.index.flush()?;
+        self.data.get_ref().sync_data()?;
+        self.index.get_ref().sync_data()
+    }
+
+    /// Closes the segment and hands it over to the uploader, if there is one. The uploaded
+    /// objects are named after the slot range of the segment and its creation time, the range
+    /// starts earlier than the aligned start when a late slot went into the segment
+    fn finish(mut self, uploader: Option<&S3Uploader>) -> io::Result<()> {
+        self.flush()?;
+        info!(
+            "Finished segment {} with {} bytes",
+            self.path.display(),
+            self.size
+        );
+
+        if let Some(uploader) = uploader {
+            let name = format!(
+                "{:012}-{:012}-{}",
+                self.start_slot.min(self.first_slot),
+                self.end_slot,
+                self.created_ms
+            );
+            uploader.submit(&self.path, &format!("{name}.{}", self.extension))?;
+            uploader.submit(&self.index_path, &format!("{name}.idx"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Owns the current segment, and runs the writes, rotations and hand-overs to the uploader
+struct SegmentWriter {
+    directory: PathBuf,
+    format: FileFormat,
+    max_segment_bytes: u64,
+    segment_slots: u64,
+    segment: Option<Segment>,
+    uploader: Option<Arc<S3Uploader>>,
+}
+
+impl SegmentWriter {
+    // Aligned, so the segments of different runs start at the same slots
+    fn segment_start(&self, slot: u64) -> u64 {
+        if self.segment_slots > 0 {
+            slot - slot % self.segment_slots
+        } else {
+            slot
+        }
+    }
+
+    // Late slots below the range of the current segment go into it and never rotate it
+    fn should_rotate(&self, segment: &Segment, slot: u64) -> bool {
+        let size_exceeded = self.max_segment_bytes > 0 && segment.size >= self.max_segment_bytes;
+        let range_exceeded =
+            self.segment_slots > 0 && slot >= segment.start_slot + self.segment_slots;
+        size_exceeded || range_exceeded
+    }
+
+    fn encode(&self, record: &SinkRecord) -> Vec<u8> {
+        let message_type = record.message_type();
+        match self.format {
+            FileFormat::Ndjson => {
+                let mut line = format!("{{\"type\":\"{message_type}\",\"message\":").into_bytes();
+                line.extend_from_slice(&record.payload);
+                line.extend_from_slice(b"}\n");
+                line
+            }
+            FileFormat::LengthPrefixed => {
+                let mut frame = Vec::with_capacity(record.payload.len() + 5);
+                frame.push(message_type_tag(&message_type));
+                frame.extend_from_slice(&(record.payload.len() as u32).to_le_bytes());
+                frame.extend_from_slice(&record.payload);
+                frame
+            }
+        }
+    }
+
+    fn write_encoded(&mut self, slot: u64, record: &[u8]) -> io::Result<()> {
+        if let Some(current) = self.segment.take() {
+            if self.should_rotate(&current, slot) {
+                current.finish(self.uploader.as_deref())?;
+            } else {
+                self.segment = Some(current);
+            }
+        }
+
+        match self.segment.as_mut() {
+            Some(current) => current.write(slot, record),
+            None => {
+                let mut current =
+                    Segment::create(&self.directory, self.format, self.segment_start(slot))?;
+                current.write(slot, record)?;
+                self.segment = Some(current);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl BlockingWriter for SegmentWriter {
+    fn write(&mut self, record: &SinkRecord) -> Result<(), SinkError> {
+        let encoded = self.encode(record);
+        self.write_encoded(record.message.slot(), &encoded)?;
+        Ok(())
+    }
+
+    // When the segments are uploaded, the current one is finished so it is staged for upload
+    fn flush(&mut self) -> Result<(), SinkError> {
+        match self.uploader.as_deref() {
+            Some(uploader) => {
+                if let Some(current) = self.segment.take() {
+                    current.finish(Some(uploader))?;
+                }
+            }
+            None => {
+                if let Some(current) = self.segment.as_mut() {
+                    current.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the messages into local segment files, rotated by size or by slot range
+pub struct FileSink {
+    writer: WriterThread,
+    last_error: LastError,
+}
+
+impl FileSink {
+    pub fn new(
+        config: &GeyserPluginKafkaConfig,
+        stats: Arc<Stats>,
+        runtime: &Runtime,
+    ) -> io::Result<Self> {
+        if config.file_sink_directory.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file_sink_directory is not set",
+            ));
+        }
+
+        let directory = PathBuf::from(&config.file_sink_directory);
+        fs::create_dir_all(&directory)?;
+        let uploader = S3Uploader::from_config(config, &directory, stats, runtime)?;
+
+        let writer = SegmentWriter {
+            directory,
+            format: config.file_sink_format,
+            max_segment_bytes: config
+                .file_sink_max_segment_bytes
+                .parse::<u64>()
+                .unwrap_or(DEFAULT_FILE_SINK_MAX_SEGMENT_BYTES),
+            segment_slots: config
+                .file_sink_segment_slots
+                .parse::<u64>()
+                .unwrap_or(DEFAULT_FILE_SINK_SEGMENT_SLOTS),
+            segment: None,
+            uploader,
+        };
+
+        Ok(FileSink {
+            writer: WriterThread::spawn("file", writer)?,
+            last_error: LastError::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let result = self.writer.write(record).await;
+        self.last_error.set(&result);
+        result
+    }
+
+    async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+        self.writer.flush().await
+    }
+
+    fn health(&self) -> SinkHealth {
+        self.last_error.health()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::sink::tests::slot_record;
+
+    fn segment_writer(
+        name: &str,
+        format: FileFormat,
+        max_segment_bytes: u64,
+        segment_slots: u64,
+    ) -> SegmentWriter {
+        let directory = env::temp_dir().join(format!(
+            "geyser_neon_file_{name}_{}",
+            Utc::now().timestamp_nanos()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+
+        SegmentWriter {
+            directory,
+            format,
+            max_segment_bytes,
+            segment_slots,
+            segment: None,
+            uploader: None,
+        }
+    }
+
+    // The data files of the directory with their indexes, in the order they were created
+    fn segments(directory: &Path) -> Vec<(Vec<u8>, Vec<(u64, u64)>)> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().unwrap() != "idx")
+            .collect();
+        paths.sort();
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let index = fs::read(path.with_extension("idx")).unwrap();
+                let index = index
+                    .chunks(16)
+                    .map(|entry| {
+                        (
+                            u64::from_le_bytes(entry[..8].try_into().unwrap()),
+                            u64::from_le_bytes(entry[8..].try_into().unwrap()),
+                        )
+                    })
+                    .collect();
+                (fs::read(path).unwrap(), index)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ndjson_lines_wrap_the_payload() {
+        let mut writer = segment_writer("ndjson", FileFormat::Ndjson, 0, 0);
+        let record = slot_record(7);
+        writer.write(&record).unwrap();
+        writer.flush().unwrap();
+
+        let segments = segments(&writer.directory);
+        assert_eq!(segments.len(), 1);
+        let line: serde_json::Value = serde_json::from_slice(&segments[0].0).unwrap();
+        assert_eq!(line["type"], "UpdateSlot");
+        assert_eq!(
+            line["message"],
+            serde_json::from_slice::<serde_json::Value>(&record.payload).unwrap()
+        );
+        assert_eq!(segments[0].0.last(), Some(&b'\n'));
+
+        fs::remove_dir_all(&writer.directory).unwrap();
+    }
+
+    #[test]
+    fn length_prefixed_frames_carry_the_type_and_length() {
+        let mut writer = segment_writer("frames", FileFormat::LengthPrefixed, 0, 0);
+        let record = slot_record(7);
+        writer.write(&record).unwrap();
+        writer.flush().unwrap();
+
+        let data = &segments(&writer.directory)[0].0;
+        assert_eq!(data[0], message_type_tag(&MessageType::UpdateSlot));
+        assert_eq!(
+            u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize,
+            record.payload.len()
+        );
+        assert_eq!(&data[5..], record.payload.as_slice());
+
+        fs::remove_dir_all(&writer.directory).unwrap();
+    }
+
+    #[test]
+    fn index_points_to_the_first_record_of_every_slot() {
+        let mut writer = segment_writer("index", FileFormat::LengthPrefixed, 0, 0);
+        for slot in [10, 10, 11, 10, 12] {
+            writer.write(&slot_record(slot)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let (data, index) = &segments(&writer.directory)[0];
+        let slots: Vec<u64> = index.iter().map(|(slot, _)| *slot).collect();
+        assert_eq!(slots, vec![10, 11, 12]);
+        for (slot, offset) in index {
+            let offset = *offset as usize;
+            let length = u32::from_le_bytes(data[offset + 1..offset + 5].try_into().unwrap());
+            let payload = &data[offset + 5..offset + 5 + length as usize];
+            assert_eq!(payload, slot_record(*slot).payload.as_slice());
+        }
+
+        fs::remove_dir_all(&writer.directory).unwrap();
+    }
+
+    #[test]
+    fn segments_rotate_by_size() {
+        let record_size = slot_record(1).payload.len() as u64 + 5;
+        let mut writer = segment_writer("size", FileFormat::LengthPrefixed, record_size * 2, 0);
+        for slot in 1..=5 {
+            writer.write(&slot_record(slot)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let sizes: Vec<usize> = segments(&writer.directory)
+            .iter()
+            .map(|(_, index)| index.len())
+            .collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+
+        fs::remove_dir_all(&writer.directory).unwrap();
+    }
+
+    #[test]
+    fn segments_rotate_by_aligned_slot_range() {
+        let mut writer = segment_writer("range", FileFormat::Ndjson, 0, 10);
+        for slot in [13, 19, 20, 35] {
+            writer.write(&slot_record(slot)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(&writer.directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".ndjson"))
+            .map(|name| name[..12].to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["000000000010", "000000000020", "000000000030"]);
+
+        let slots: Vec<Vec<u64>> = segments(&writer.directory)
+            .iter()
+            .map(|(_, index)| index.iter().map(|(slot, _)| *slot).collect())
+            .collect();
+        assert_eq!(slots, vec![vec![13, 19], vec![20], vec![35]]);
+
+        fs::remove_dir_all(&writer.directory).unwrap();
+    }
+
+    #[test]
+    fn late_slots_go_into_the_current_segment() {
+        let mut writer = segment_writer("late", FileFormat::Ndjson, 0, 10);
+        for slot in [20, 25, 3, 29] {
+            writer.write(&slot_record(slot)).unwrap();
+        }
+
+        let segment = writer.segment.as_ref().unwrap();
+        assert_eq!(segment.start_slot, 20);
+        assert_eq!(segment.first_slot, 3);
+        assert_eq!(segment.end_slot, 29);
+        writer.flush().unwrap();
+
+        let segments = segments(&writer.directory);
+        assert_eq!(segments.len(), 1);
+        let slots: Vec<u64> = segments[0].1.iter().map(|(slot, _)| *slot).collect();
+        assert_eq!(slots, vec![20, 25, 3, 29]);
+
+        fs::remove_dir_all(&writer.directory).unwrap();
+    }
+}
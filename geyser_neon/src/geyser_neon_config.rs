@@ -1,7 +1,16 @@
+use kafka_common::compression::DataCompression;
 use log::LevelFilter;
 use rdkafka::config::RDKafkaLogLevel;
 use serde_derive::{Deserialize, Serialize};
 
+use kafka_common::message_type::MessageType;
+use serde_json::{Map, Value};
+
+use crate::{
+    fanout_sink::BackpressurePolicy, file_sink::FileFormat, kafka_producer::KafkaCluster,
+    kafka_sink::MirrorMode, sink::SinkKind,
+};
+
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum LogLevel {
     /// Higher priority then [`Level::Error`](log::Level::Error) from the log
@@ -78,6 +87,20 @@ pub struct GeyserPluginKafkaConfig {
     pub sasl_password: String,
     pub sasl_mechanism: String,
     pub security_protocol: String,
+    // Servers list of the Kafka cluster the messages are mirrored to. If empty, nothing is mirrored
+    #[serde(default)]
+    pub secondary_brokers_list: String,
+    #[serde(default)]
+    pub secondary_sasl_username: String,
+    #[serde(default)]
+    pub secondary_sasl_password: String,
+    #[serde(default = "default_secondary_sasl_mechanism")]
+    pub secondary_sasl_mechanism: String,
+    #[serde(default = "default_secondary_security_protocol")]
+    pub secondary_security_protocol: String,
+    // Whether a message must be delivered to the secondary cluster too before it counts as sent
+    #[serde(default)]
+    pub kafka_mirror_mode: MirrorMode,
     pub update_account_topic: String,
     pub update_slot_topic: String,
     pub notify_transaction_topic: String,
@@ -105,4 +128,426 @@ pub struct GeyserPluginKafkaConfig {
     pub message_timeout_ms: String,
     pub kafka_log_level: LogLevel,
     pub global_log_level: GlobalLogLevel,
+    // Application-level compression of account data, Zstd or Lz4. Disabled if not set
+    #[serde(default)]
+    pub account_data_compression: Option<DataCompression>,
+    // Account data shorter than this number of bytes is sent uncompressed
+    #[serde(default = "default_account_data_compression_threshold")]
+    pub account_data_compression_threshold: String,
+    // Only used by Zstd, from 1 to 22
+    #[serde(default = "default_account_data_compression_level")]
+    pub account_data_compression_level: String,
+    // Messages larger than this number of bytes are split into chunks of this size.
+    // Should be less than message.max.bytes of the brokers, at least 1024. A value of 0 disables chunking
+    #[serde(default = "default_message_chunk_size")]
+    pub message_chunk_size: String,
+    // Maximum number of account data diffs between two full updates of an account.
+    // A value of 0 disables delta encoding
+    #[serde(default = "default_account_data_delta_keyframe_interval")]
+    pub account_data_delta_keyframe_interval: String,
+    // Accounts with less data are always sent in full
+    #[serde(default = "default_account_data_delta_min_size")]
+    pub account_data_delta_min_size: String,
+    // Maximum number of accounts whose last data is kept in memory for delta encoding
+    #[serde(default = "default_account_data_delta_max_accounts")]
+    pub account_data_delta_max_accounts: String,
+    // Maximum number of bytes of account data kept in memory for delta encoding,
+    // the least recently updated accounts are sent as keyframes again once it is exceeded
+    #[serde(default = "default_account_data_delta_max_bytes")]
+    pub account_data_delta_max_bytes: String,
+    // /readyz fails while any internal queue is filled above this percentage of internal_queue_capacity
+    #[serde(default = "default_readiness_queue_threshold_percent")]
+    pub readiness_queue_threshold_percent: String,
+    // Number of owner programs with their own label in the per-owner account update metrics,
+    // updates of the other programs are counted under the "other" label
+    #[serde(default = "default_owner_metrics_max_programs")]
+    pub owner_metrics_max_programs: String,
+    // A warning is logged when a geyser callback takes longer than this number of microseconds
+    #[serde(default = "default_callback_warn_threshold_us")]
+    pub callback_warn_threshold_us: String,
+    // Interval of the solana-metrics datapoints with the plugin counters. A value of 0 disables them
+    #[serde(default = "default_solana_metrics_interval_ms")]
+    pub solana_metrics_interval_ms: String,
+    // Backend the messages are published to, Kafka if not set
+    #[serde(default)]
+    pub sink: SinkKind,
+    // Time to wait on unload for the messages which have not been delivered yet
+    #[serde(default = "default_sink_flush_timeout_ms")]
+    pub sink_flush_timeout_ms: String,
+    // Directory of the segment files written by the file sink
+    #[serde(default)]
+    pub file_sink_directory: String,
+    // Layout of the records in the segment files, Ndjson if not set
+    #[serde(default)]
+    pub file_sink_format: FileFormat,
+    // A new segment file is started once the current one reaches this size. A value of 0 disables it
+    #[serde(default = "default_file_sink_max_segment_bytes")]
+    pub file_sink_max_segment_bytes: String,
+    // A new segment file is started for every range of this many slots. A value of 0 disables it
+    #[serde(default = "default_file_sink_segment_slots")]
+    pub file_sink_segment_slots: String,
+    // Interval of the throughput log lines of the null sink. A value of 0 disables them
+    #[serde(default = "default_null_sink_report_interval_ms")]
+    pub null_sink_report_interval_ms: String,
+    // Address the gRPC server of the gRPC sink listens on
+    #[serde(default = "default_grpc_listen_address")]
+    pub grpc_listen_address: String,
+    // Number of messages buffered for every gRPC subscriber, slower subscribers are disconnected
+    #[serde(default = "default_grpc_subscriber_buffer")]
+    pub grpc_subscriber_buffer: String,
+    // Address the WebSocket server of the WebSocket sink listens on
+    #[serde(default = "default_websocket_listen_address")]
+    pub websocket_listen_address: String,
+    // Number of notifications buffered for every WebSocket connection, slower clients are disconnected
+    #[serde(default = "default_websocket_connection_buffer")]
+    pub websocket_connection_buffer: String,
+    // Maximum number of WebSocket connections, further connections are refused
+    #[serde(default = "default_websocket_max_connections")]
+    pub websocket_max_connections: String,
+    // Maximum number of subscriptions of a WebSocket connection
+    #[serde(default = "default_websocket_max_subscriptions_per_connection")]
+    pub websocket_max_subscriptions_per_connection: String,
+    // Time a WebSocket client has to complete its handshake
+    #[serde(default = "default_websocket_handshake_timeout_ms")]
+    pub websocket_handshake_timeout_ms: String,
+    // Redis server of the Redis sink, the streams are named after the topics
+    #[serde(default = "default_redis_url")]
+    pub redis_url: String,
+    // Approximate maximum length of the Redis streams. A value of 0 disables trimming
+    #[serde(default = "default_redis_stream_maxlen")]
+    pub redis_stream_maxlen: String,
+    // Maximum number of XADD commands sent to Redis in a single pipeline
+    #[serde(default = "default_redis_pipeline_size")]
+    pub redis_pipeline_size: String,
+    // Connection string of the database of the Postgres sink
+    #[serde(default = "default_postgres_connection_str")]
+    pub postgres_connection_str: String,
+    // Maximum number of messages written to Postgres in a batch, the batch is split by slot
+    #[serde(default = "default_postgres_batch_size")]
+    pub postgres_batch_size: String,
+    // Directory of the Parquet files written by the Parquet sink
+    #[serde(default)]
+    pub parquet_sink_directory: String,
+    // A new Parquet file is started for every range of this many slots
+    #[serde(default = "default_parquet_sink_segment_slots")]
+    pub parquet_sink_segment_slots: String,
+    // Maximum number of rows buffered before they are written as a row group
+    #[serde(default = "default_parquet_sink_row_group_size")]
+    pub parquet_sink_row_group_size: String,
+    // Bucket the finished files of the File and Parquet sinks are uploaded to. If empty, nothing is uploaded
+    #[serde(default)]
+    pub s3_bucket: String,
+    // Prepended to the keys of the uploaded objects
+    #[serde(default)]
+    pub s3_key_prefix: String,
+    // Region of the bucket, taken from the AWS environment if empty
+    #[serde(default)]
+    pub s3_region: String,
+    // Endpoint of an S3-compatible service such as MinIO, the AWS endpoint of the region if empty
+    #[serde(default)]
+    pub s3_endpoint: String,
+    // Credentials taken from the AWS environment if empty
+    #[serde(default)]
+    pub s3_access_key_id: String,
+    #[serde(default)]
+    pub s3_secret_access_key: String,
+    // Directory the finished files wait in until they are uploaded, `s3-staging` in the sink directory if empty.
+    // Each sink directory gets a subdirectory of it. On another file system than the sink directory the files are copied
+    #[serde(default)]
+    pub s3_staging_directory: String,
+    // Files larger than this are uploaded in parts of this size, at least 5 MiB
+    #[serde(default = "default_s3_multipart_part_size")]
+    pub s3_multipart_part_size: String,
+    // Attempts to upload a file before it is moved to the failed directory next to the staging directory
+    #[serde(default = "default_s3_max_upload_attempts")]
+    pub s3_max_upload_attempts: String,
+    // Sink instances the messages are fanned out to. If empty, the messages are published to `sink` only
+    #[serde(default)]
+    pub sinks: Vec<SinkInstanceConfig>,
+}
+
+impl GeyserPluginKafkaConfig {
+    pub fn primary_cluster(&self) -> KafkaCluster<'_> {
+        KafkaCluster {
+            brokers_list: &self.brokers_list,
+            sasl_username: &self.sasl_username,
+            sasl_password: &self.sasl_password,
+            sasl_mechanism: &self.sasl_mechanism,
+            security_protocol: &self.security_protocol,
+        }
+    }
+
+    pub fn secondary_cluster(&self) -> Option<KafkaCluster<'_>> {
+        if self.secondary_brokers_list.is_empty() {
+            return None;
+        }
+
+        Some(KafkaCluster {
+            brokers_list: &self.secondary_brokers_list,
+            sasl_username: &self.secondary_sasl_username,
+            sasl_password: &self.secondary_sasl_password,
+            sasl_mechanism: &self.secondary_sasl_mechanism,
+            security_protocol: &self.secondary_security_protocol,
+        })
+    }
+}
+
+/// One of the sinks the messages are fanned out to
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SinkInstanceConfig {
+    // Name of the sink in the logs and metrics
+    pub name: String,
+    pub sink: SinkKind,
+    // Message types published to this sink, all of them if empty
+    #[serde(default)]
+    pub streams: Vec<MessageType>,
+    // Base58 pubkeys of the accounts published to this sink, all accounts if both lists are empty
+    #[serde(default)]
+    pub account_pubkeys: Vec<String>,
+    // Base58 pubkeys of the owner programs whose accounts are published to this sink
+    #[serde(default)]
+    pub account_owners: Vec<String>,
+    #[serde(default = "default_include_vote_transactions")]
+    pub include_vote_transactions: bool,
+    // What to do with a message when the queue of this sink is full
+    #[serde(default)]
+    pub backpressure: BackpressurePolicy,
+    #[serde(default = "default_sink_queue_capacity")]
+    pub queue_capacity: String,
+    // Number of messages this sink publishes concurrently
+    #[serde(default = "default_sink_max_in_flight")]
+    pub max_in_flight: String,
+    // Plugin settings overridden for this sink, e.g. brokers_list or file_sink_directory
+    #[serde(default)]
+    pub settings: Map<String, Value>,
+}
+
+pub const DEFAULT_INTERNAL_QUEUE_CAPACITY: usize = 30000;
+pub const DEFAULT_READINESS_QUEUE_THRESHOLD_PERCENT: usize = 90;
+pub const DEFAULT_OWNER_METRICS_MAX_PROGRAMS: usize = 100;
+pub const DEFAULT_CALLBACK_WARN_THRESHOLD_US: u64 = 10000;
+pub const DEFAULT_SINK_FLUSH_TIMEOUT_MS: u64 = 10000;
+pub const DEFAULT_FILE_SINK_MAX_SEGMENT_BYTES: u64 = 1073741824;
+pub const DEFAULT_FILE_SINK_SEGMENT_SLOTS: u64 = 0;
+pub const DEFAULT_NULL_SINK_REPORT_INTERVAL_MS: u64 = 10000;
+pub const DEFAULT_GRPC_LISTEN_ADDRESS: &str = "0.0.0.0:10000";
+pub const DEFAULT_GRPC_SUBSCRIBER_BUFFER: usize = 50000;
+pub const DEFAULT_WEBSOCKET_LISTEN_ADDRESS: &str = "0.0.0.0:8900";
+pub const DEFAULT_WEBSOCKET_CONNECTION_BUFFER: usize = 10000;
+pub const DEFAULT_WEBSOCKET_MAX_CONNECTIONS: usize = 1000;
+pub const DEFAULT_WEBSOCKET_MAX_SUBSCRIPTIONS_PER_CONNECTION: usize = 1000;
+pub const DEFAULT_WEBSOCKET_HANDSHAKE_TIMEOUT_MS: u64 = 10000;
+pub const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
+pub const DEFAULT_REDIS_STREAM_MAXLEN: usize = 1000000;
+pub const DEFAULT_REDIS_PIPELINE_SIZE: usize = 100;
+pub const DEFAULT_POSTGRES_CONNECTION_STR: &str = "host=127.0.0.1 user=postgres";
+pub const DEFAULT_POSTGRES_BATCH_SIZE: usize = 1000;
+pub const DEFAULT_SINK_QUEUE_CAPACITY: usize = 30000;
+pub const DEFAULT_SINK_MAX_IN_FLIGHT: usize = 10000;
+pub const DEFAULT_PARQUET_SINK_SEGMENT_SLOTS: u64 = 1000;
+pub const DEFAULT_PARQUET_SINK_ROW_GROUP_SIZE: usize = 10000;
+pub const DEFAULT_S3_MULTIPART_PART_SIZE: usize = 16777216;
+pub const DEFAULT_S3_MAX_UPLOAD_ATTEMPTS: u32 = 10;
+pub const DEFAULT_SECONDARY_SASL_MECHANISM: &str = "PLAIN";
+pub const DEFAULT_SECONDARY_SECURITY_PROTOCOL: &str = "plaintext";
+
+pub const DEFAULT_ACCOUNT_DATA_COMPRESSION_THRESHOLD: usize = 65536;
+pub const DEFAULT_ACCOUNT_DATA_COMPRESSION_LEVEL: i32 = 3;
+pub const DEFAULT_ACCOUNT_DATA_DELTA_MIN_SIZE: usize = 4096;
+pub const DEFAULT_ACCOUNT_DATA_DELTA_MAX_ACCOUNTS: usize = 100000;
+pub const DEFAULT_ACCOUNT_DATA_DELTA_MAX_BYTES: usize = 1073741824;
+
+fn default_readiness_queue_threshold_percent() -> String {
+    DEFAULT_READINESS_QUEUE_THRESHOLD_PERCENT.to_string()
+}
+
+fn default_include_vote_transactions() -> bool {
+    true
+}
+
+fn default_sink_queue_capacity() -> String {
+    DEFAULT_SINK_QUEUE_CAPACITY.to_string()
+}
+
+fn default_sink_max_in_flight() -> String {
+    DEFAULT_SINK_MAX_IN_FLIGHT.to_string()
+}
+
+fn default_parquet_sink_segment_slots() -> String {
+    DEFAULT_PARQUET_SINK_SEGMENT_SLOTS.to_string()
+}
+
+fn default_parquet_sink_row_group_size() -> String {
+    DEFAULT_PARQUET_SINK_ROW_GROUP_SIZE.to_string()
+}
+
+fn default_s3_multipart_part_size() -> String {
+    DEFAULT_S3_MULTIPART_PART_SIZE.to_string()
+}
+
+fn default_s3_max_upload_attempts() -> String {
+    DEFAULT_S3_MAX_UPLOAD_ATTEMPTS.to_string()
+}
+
+fn default_secondary_sasl_mechanism() -> String {
+    DEFAULT_SECONDARY_SASL_MECHANISM.to_string()
+}
+
+fn default_secondary_security_protocol() -> String {
+    DEFAULT_SECONDARY_SECURITY_PROTOCOL.to_string()
+}
+
+fn default_postgres_connection_str() -> String {
+    DEFAULT_POSTGRES_CONNECTION_STR.to_string()
+}
+
+fn default_postgres_batch_size() -> String {
+    DEFAULT_POSTGRES_BATCH_SIZE.to_string()
+}
+
+fn default_redis_url() -> String {
+    DEFAULT_REDIS_URL.to_string()
+}
+
+fn default_redis_stream_maxlen() -> String {
+    DEFAULT_REDIS_STREAM_MAXLEN.to_string()
+}
+
+fn default_redis_pipeline_size() -> String {
+    DEFAULT_REDIS_PIPELINE_SIZE.to_string()
+}
+
+fn default_websocket_listen_address() -> String {
+    DEFAULT_WEBSOCKET_LISTEN_ADDRESS.to_string()
+}
+
+fn default_websocket_connection_buffer() -> String {
+    DEFAULT_WEBSOCKET_CONNECTION_BUFFER.to_string()
+}
+
+fn default_websocket_max_connections() -> String {
+    DEFAULT_WEBSOCKET_MAX_CONNECTIONS.to_string()
+}
+
+fn default_websocket_max_subscriptions_per_connection() -> String {
+    DEFAULT_WEBSOCKET_MAX_SUBSCRIPTIONS_PER_CONNECTION.to_string()
+}
+
+fn default_websocket_handshake_timeout_ms() -> String {
+    DEFAULT_WEBSOCKET_HANDSHAKE_TIMEOUT_MS.to_string()
+}
+
+fn default_grpc_listen_address() -> String {
+    DEFAULT_GRPC_LISTEN_ADDRESS.to_string()
+}
+
+fn default_grpc_subscriber_buffer() -> String {
+    DEFAULT_GRPC_SUBSCRIBER_BUFFER.to_string()
+}
+
+fn default_null_sink_report_interval_ms() -> String {
+    DEFAULT_NULL_SINK_REPORT_INTERVAL_MS.to_string()
+}
+
+fn default_file_sink_max_segment_bytes() -> String {
+    DEFAULT_FILE_SINK_MAX_SEGMENT_BYTES.to_string()
+}
+
+fn default_file_sink_segment_slots() -> String {
+    DEFAULT_FILE_SINK_SEGMENT_SLOTS.to_string()
+}
+
+fn default_sink_flush_timeout_ms() -> String {
+    DEFAULT_SINK_FLUSH_TIMEOUT_MS.to_string()
+}
+
+fn default_solana_metrics_interval_ms() -> String {
+    "0".to_string()
+}
+
+fn default_callback_warn_threshold_us() -> String {
+    DEFAULT_CALLBACK_WARN_THRESHOLD_US.to_string()
+}
+
+fn default_owner_metrics_max_programs() -> String {
+    DEFAULT_OWNER_METRICS_MAX_PROGRAMS.to_string()
+}
+
+fn default_message_chunk_size() -> String {
+    "0".to_string()
+}
+
+fn default_account_data_delta_keyframe_interval() -> String {
+    "0".to_string()
+}
+
+fn default_account_data_delta_min_size() -> String {
+    DEFAULT_ACCOUNT_DATA_DELTA_MIN_SIZE.to_string()
+}
+
+fn default_account_data_delta_max_accounts() -> String {
+    DEFAULT_ACCOUNT_DATA_DELTA_MAX_ACCOUNTS.to_string()
+}
+
+fn default_account_data_delta_max_bytes() -> String {
+    DEFAULT_ACCOUNT_DATA_DELTA_MAX_BYTES.to_string()
+}
+
+fn default_account_data_compression_threshold() -> String {
+    DEFAULT_ACCOUNT_DATA_COMPRESSION_THRESHOLD.to_string()
+}
+
+fn default_account_data_compression_level() -> String {
+    DEFAULT_ACCOUNT_DATA_COMPRESSION_LEVEL.to_string()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A configuration with the required settings, overridden by `settings`
+    pub(crate) fn test_config(settings: Value) -> GeyserPluginKafkaConfig {
+        let mut config = json!({
+            "brokers_list": "127.0.0.1:9092",
+            "sasl_username": "",
+            "sasl_password": "",
+            "sasl_mechanism": "PLAIN",
+            "security_protocol": "PLAINTEXT",
+            "update_account_topic": "update_account",
+            "update_slot_topic": "update_slot",
+            "notify_transaction_topic": "notify_transaction",
+            "notify_block_topic": "notify_block",
+            "producer_send_max_retries": "100",
+            "producer_queue_max_messages": "125000",
+            "producer_message_max_bytes": "104857600",
+            "producer_request_timeout_ms": "100000",
+            "producer_retry_backoff_ms": "1000",
+            "max_in_flight_requests_per_connection": "5",
+            "internal_queue_capacity": "30000",
+            "compression_codec": "lz4",
+            "compression_level": "12",
+            "batch_size": "104857600",
+            "batch_num_messages": "10000",
+            "linger_ms": "20",
+            "acks": "-1",
+            "statistics_interval_ms": "0",
+            "prometheus_port": "9090",
+            "message_timeout_ms": "100000",
+            "kafka_log_level": "Info",
+            "global_log_level": "Info"
+        });
+        if let (Value::Object(config), Value::Object(settings)) = (&mut config, settings) {
+            config.extend(settings);
+        }
+        serde_json::from_value(config).expect("Test configuration is invalid")
+    }
+
+    #[test]
+    fn test_config_is_valid() {
+        let config = test_config(json!({ "redis_url": "redis://127.0.0.1:6380" }));
+        assert_eq!(config.redis_url, "redis://127.0.0.1:6380");
+        assert_eq!(config.sink, SinkKind::Kafka);
+    }
 }
@@ -2,6 +2,7 @@ use std::{
     fs::File,
     io::Read,
     sync::{atomic::AtomicBool, Arc},
+    time::Duration,
 };
 
 use chrono::Utc;
@@ -11,8 +12,10 @@ use kafka_common::kafka_structs::{
     KafkaReplicaTransactionInfoVersions, KafkaSlotStatus, NotifyBlockMetaData, NotifyTransaction,
     UpdateAccount, UpdateSlotStatus,
 };
+use prometheus_client::metrics::histogram::Histogram;
 use rdkafka::config::RDKafkaLogLevel;
 use solana_geyser_plugin_interface::geyser_plugin_interface::GeyserPluginError;
+use solana_measure::measure::Measure;
 use thiserror::Error;
 use tokio::{
     runtime::{self, Runtime},
@@ -38,24 +41,33 @@ use flume::Receiver;
 
 use crate::{
     build_info::get_build_info,
-    geyser_neon_config::GeyserPluginKafkaConfig,
-    kafka_producer_stats::ContextWithStats,
+    datapoints::report_datapoints,
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, DEFAULT_CALLBACK_WARN_THRESHOLD_US,
+        DEFAULT_INTERNAL_QUEUE_CAPACITY, DEFAULT_SINK_FLUSH_TIMEOUT_MS,
+    },
+    kafka_producer_stats::{ContextWithStats, Stats},
     prometheus::start_prometheus,
     receivers::{
         notify_block_loop, notify_transaction_loop, update_account_loop, update_slot_status_loop,
     },
+    sink::{create_sink, Sink},
 };
 
 pub struct GeyserPluginKafka {
     runtime: Arc<Runtime>,
     config: Option<Arc<GeyserPluginKafkaConfig>>,
     logger: &'static Logger,
+    stats: Arc<Stats>,
+    callback_warn_threshold_us: u64,
+    sink: Option<Arc<dyn Sink>>,
     account_tx: Option<Sender<UpdateAccount>>,
     slot_status_tx: Option<Sender<UpdateSlotStatus>>,
     transaction_tx: Option<Sender<NotifyTransaction>>,
     block_metadata_tx: Option<Sender<NotifyBlockMetaData>>,
     should_stop: Arc<AtomicBool>,
     prometheus_jhandle: Option<JoinHandle<()>>,
+    datapoints_jhandle: Option<JoinHandle<()>>,
     update_account_jhandle: Option<JoinHandle<()>>,
     update_slot_status_jhandle: Option<JoinHandle<()>>,
     notify_transaction_jhandle: Option<JoinHandle<()>>,
@@ -91,6 +103,9 @@ impl GeyserPluginKafka {
             runtime,
             config: None,
             logger,
+            stats: Arc::new(Stats::default()),
+            callback_warn_threshold_us: DEFAULT_CALLBACK_WARN_THRESHOLD_US,
+            sink: None,
             account_tx: None,
             slot_status_tx: None,
             transaction_tx: None,
@@ -101,6 +116,7 @@ impl GeyserPluginKafka {
             notify_transaction_jhandle: None,
             notify_block_jhandle: None,
             prometheus_jhandle: None,
+            datapoints_jhandle: None,
         }
     }
 
@@ -112,7 +128,7 @@ impl GeyserPluginKafka {
         transaction_rx: Receiver<NotifyTransaction>,
         block_metadata_rx: Receiver<NotifyBlockMetaData>,
         should_stop: Arc<AtomicBool>,
-    ) {
+    ) -> Result<()> {
         info!(
             "Rdkafka logging level will be set to {:?}",
             Into::<RDKafkaLogLevel>::into(&config.kafka_log_level)
@@ -127,7 +143,14 @@ impl GeyserPluginKafka {
 
         info!("{}", get_build_info());
 
-        let ctx_stats = ContextWithStats::default();
+        let ctx_stats = ContextWithStats {
+            stats: self.stats.clone(),
+            secondary: false,
+        };
+
+        let sink = create_sink(config.clone(), ctx_stats, &self.runtime)
+            .map_err(|e| GeyserPluginError::Custom(Box::new(e)))?;
+        info!("Messages will be published to the {} sink", sink.name());
 
         let prometheus_port = config
             .prometheus_port
@@ -135,48 +158,78 @@ impl GeyserPluginKafka {
             .unwrap_or_else(|e| panic!("Wrong prometheus port number, error: {e}"));
 
         let prometheus_jhandle = Some(self.runtime.spawn(start_prometheus(
-            ctx_stats.stats.clone(),
+            self.stats.clone(),
+            sink.clone(),
             config.clone(),
             prometheus_port,
         )));
 
+        let solana_metrics_interval_ms = config
+            .solana_metrics_interval_ms
+            .parse::<u64>()
+            .unwrap_or(0);
+
+        let datapoints_jhandle = (solana_metrics_interval_ms > 0).then(|| {
+            self.runtime.spawn(report_datapoints(
+                self.stats.clone(),
+                Duration::from_millis(solana_metrics_interval_ms),
+                should_stop,
+            ))
+        });
+
         let update_account_jhandle = Some(self.runtime.spawn(update_account_loop(
             self.runtime.clone(),
             config.clone(),
             account_rx,
-            ctx_stats.clone(),
-            should_stop.clone(),
+            sink.clone(),
+            self.stats.clone(),
         )));
 
         let update_slot_status_jhandle = Some(self.runtime.spawn(update_slot_status_loop(
             self.runtime.clone(),
-            config.clone(),
             slot_status_rx,
-            ctx_stats.clone(),
-            should_stop.clone(),
+            sink.clone(),
+            self.stats.clone(),
         )));
 
         let notify_transaction_jhandle = Some(self.runtime.spawn(notify_transaction_loop(
             self.runtime.clone(),
-            config.clone(),
             transaction_rx,
-            ctx_stats.clone(),
-            should_stop.clone(),
+            sink.clone(),
+            self.stats.clone(),
         )));
 
         let notify_block_jhandle = Some(self.runtime.spawn(notify_block_loop(
             self.runtime.clone(),
-            config,
             block_metadata_rx,
-            ctx_stats,
-            should_stop,
+            sink.clone(),
+            self.stats.clone(),
         )));
 
         self.prometheus_jhandle = prometheus_jhandle;
+        self.datapoints_jhandle = datapoints_jhandle;
         self.update_account_jhandle = update_account_jhandle;
         self.update_slot_status_jhandle = update_slot_status_jhandle;
         self.notify_transaction_jhandle = notify_transaction_jhandle;
         self.notify_block_jhandle = notify_block_jhandle;
+        self.sink = Some(sink);
+
+        Ok(())
+    }
+
+    // Callbacks run on the validator threads, every microsecond spent here delays the replay stage
+    fn callback_finished(&self, name: &str, histogram: &Histogram, mut measure: Measure) {
+        measure.stop();
+        let elapsed_us = measure.as_us();
+        histogram.observe(elapsed_us as f64 / 1_000_000.0);
+        if elapsed_us > self.callback_warn_threshold_us {
+            if let Some(slow_calls) = self.stats.callbacks.slow_call() {
+                warn!(
+                    "{name} took {elapsed_us}us, more than {}us, {slow_calls} slow calls since the last warning",
+                    self.callback_warn_threshold_us
+                );
+            }
+        }
     }
 }
 
@@ -212,10 +265,14 @@ impl GeyserPlugin for GeyserPluginKafka {
             Ok(config) => {
                 let config = Arc::new(config);
                 self.config = Some(config.clone());
+                self.callback_warn_threshold_us = config
+                    .callback_warn_threshold_us
+                    .parse::<u64>()
+                    .unwrap_or(DEFAULT_CALLBACK_WARN_THRESHOLD_US);
                 let internal_queue_capacity = config
                     .internal_queue_capacity
                     .parse::<usize>()
-                    .unwrap_or(30000);
+                    .unwrap_or(DEFAULT_INTERNAL_QUEUE_CAPACITY);
 
                 let (account_tx, account_rx) = flume::bounded(internal_queue_capacity);
                 let (slot_status_tx, slot_status_rx) = flume::bounded(internal_queue_capacity);
@@ -235,7 +292,7 @@ impl GeyserPlugin for GeyserPluginKafka {
                     transaction_rx,
                     block_metadata_rx,
                     self.should_stop.clone(),
-                );
+                )?;
             }
         }
 
@@ -246,10 +303,24 @@ impl GeyserPlugin for GeyserPluginKafka {
         self.should_stop
             .store(true, std::sync::atomic::Ordering::SeqCst);
         info!("Unloading plugin: {}", self.name());
+        // Dropping the senders ends the receiver loops once they have drained the queues
+        // and their messages have been sent
+        self.account_tx.take();
+        self.slot_status_tx.take();
+        self.transaction_tx.take();
+        self.block_metadata_tx.take();
+
+        let sink = self.sink.take();
+        let flush_timeout = self
+            .config
+            .as_ref()
+            .and_then(|config| config.sink_flush_timeout_ms.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_SINK_FLUSH_TIMEOUT_MS);
         let update_account_jhandle = self.update_account_jhandle.take();
         let update_slot_status_jhandle = self.update_slot_status_jhandle.take();
         let notify_transaction_jhandle = self.notify_transaction_jhandle.take();
         let notify_block_jhandle = self.notify_block_jhandle.take();
+        let datapoints_jhandle = self.datapoints_jhandle.take();
 
         self.runtime.block_on(async move {
             if let Some(handle) = update_account_jhandle {
@@ -267,6 +338,16 @@ impl GeyserPlugin for GeyserPluginKafka {
             if let Some(handle) = notify_block_jhandle {
                 let _ = handle.await;
             }
+
+            if let Some(handle) = datapoints_jhandle {
+                let _ = handle.await;
+            }
+
+            if let Some(sink) = sink {
+                if let Err(e) = sink.flush(Duration::from_millis(flush_timeout)).await {
+                    error!("Failed to flush the {} sink, error: {e}", sink.name());
+                }
+            }
         });
 
         self.logger.flush();
@@ -278,6 +359,7 @@ impl GeyserPlugin for GeyserPluginKafka {
         slot: u64,
         is_startup: bool,
     ) -> Result<()> {
+        let measure = Measure::start("update_account");
         let account: KafkaReplicaAccountInfoVersions = account.into();
         let account_tx = self.account_tx.clone();
         let retrieved_time = Utc::now().naive_utc();
@@ -287,6 +369,8 @@ impl GeyserPlugin for GeyserPluginKafka {
             slot,
             is_startup,
             retrieved_time,
+            data_compression: None,
+            data_delta: None,
         };
 
         match account_tx
@@ -296,6 +380,12 @@ impl GeyserPlugin for GeyserPluginKafka {
             Ok(_) => (),
             Err(e) => error!("Failed to send UpdateAccount, error: {e}"),
         }
+
+        self.callback_finished(
+            "update_account",
+            &self.stats.callbacks.update_account,
+            measure,
+        );
         Ok(())
     }
 
@@ -305,7 +395,9 @@ impl GeyserPlugin for GeyserPluginKafka {
         parent: Option<u64>,
         status: SlotStatus,
     ) -> Result<()> {
+        let measure = Measure::start("update_slot_status");
         let status: KafkaSlotStatus = status.into();
+        self.stats.slots.slot_seen(status, slot);
         let slot_status_tx = self.slot_status_tx.clone();
         let retrieved_time = Utc::now().naive_utc();
 
@@ -324,11 +416,18 @@ impl GeyserPlugin for GeyserPluginKafka {
             Err(e) => error!("Failed to send UpdateSlotStatus, error: {e}"),
         }
 
+        self.callback_finished(
+            "update_slot_status",
+            &self.stats.callbacks.update_slot_status,
+            measure,
+        );
+
         Ok(())
     }
 
     fn notify_end_of_startup(&mut self) -> Result<()> {
         info!("Notifying the end of startup for accounts notifications");
+        self.stats.health.set_startup_finished();
 
         Ok(())
     }
@@ -338,6 +437,7 @@ impl GeyserPlugin for GeyserPluginKafka {
         transaction_info: ReplicaTransactionInfoVersions,
         slot: u64,
     ) -> Result<()> {
+        let measure = Measure::start("notify_transaction");
         let transaction_info: KafkaReplicaTransactionInfoVersions = transaction_info.into();
         let transaction_tx = self.transaction_tx.clone();
         let retrieved_time = Utc::now().naive_utc();
@@ -356,10 +456,17 @@ impl GeyserPlugin for GeyserPluginKafka {
             Err(e) => error!("Failed to send NotifyTransaction, error: {e}"),
         }
 
+        self.callback_finished(
+            "notify_transaction",
+            &self.stats.callbacks.notify_transaction,
+            measure,
+        );
+
         Ok(())
     }
 
     fn notify_block_metadata(&mut self, block_info: ReplicaBlockInfoVersions) -> Result<()> {
+        let measure = Measure::start("notify_block_metadata");
         let block_info: KafkaReplicaBlockInfoVersions = block_info.into();
         let block_metadata_tx = self.block_metadata_tx.clone();
         let retrieved_time = Utc::now().naive_utc();
@@ -377,6 +484,12 @@ impl GeyserPlugin for GeyserPluginKafka {
             Err(e) => error!("Failed to send NotifyBlockMetaData, error: {e}"),
         }
 
+        self.callback_finished(
+            "notify_block_metadata",
+            &self.stats.callbacks.notify_block_metadata,
+            measure,
+        );
+
         Ok(())
     }
 
@@ -0,0 +1,471 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use kafka_common::{kafka_structs::KafkaReplicaAccountInfoVersions, message_type::MessageType};
+use log::{error, info, warn};
+use prometheus_client::metrics::{counter::Counter, gauge::Gauge};
+use prost::bytes::Bytes;
+use tokio::{runtime::Runtime, sync::mpsc};
+use tokio_stream::Stream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    geyser_neon_config::{GeyserPluginKafkaConfig, DEFAULT_GRPC_SUBSCRIBER_BUFFER},
+    kafka_producer_stats::Stats,
+    sink::{Sink, SinkError, SinkHealth, SinkMessage, SinkRecord},
+};
+
+pub mod proto {
+    tonic::include_proto!("geyser_neon");
+}
+
+use proto::{
+    geyser_neon_server::{GeyserNeon, GeyserNeonServer},
+    SubscribeRequest, Update,
+};
+
+/// Subscribers of the gRPC server
+#[derive(Default)]
+pub struct GrpcStats {
+    pub subscribers: Gauge<u64, AtomicU64>,
+    pub subscribers_lagged: Counter<u64, AtomicU64>,
+}
+
+struct AccountsFilter {
+    pubkeys: HashSet<Vec<u8>>,
+    owners: HashSet<Vec<u8>>,
+}
+
+impl AccountsFilter {
+    fn matches(&self, account: &KafkaReplicaAccountInfoVersions) -> bool {
+        (self.pubkeys.is_empty() && self.owners.is_empty())
+            || self.pubkeys.contains(account.pubkey())
+            || self.owners.contains(account.owner())
+    }
+}
+
+fn decode_pubkeys(pubkeys: Vec<String>) -> Result<HashSet<Vec<u8>>, String> {
+    pubkeys
+        .into_iter()
+        .map(|pubkey| {
+            bs58::decode(&pubkey)
+                .into_vec()
+                .map_err(|e| format!("Invalid pubkey {pubkey}: {e}"))
+        })
+        .collect()
+}
+
+/// Server-side filter of a subscription
+struct SubscriptionFilter {
+    accounts: Option<AccountsFilter>,
+    slots: bool,
+    transactions_include_votes: Option<bool>,
+    blocks: bool,
+}
+
+impl SubscriptionFilter {
+    fn new(request: SubscribeRequest) -> Result<Self, String> {
+        let accounts = match request.accounts {
+            Some(accounts) => Some(AccountsFilter {
+                pubkeys: decode_pubkeys(accounts.pubkeys)?,
+                owners: decode_pubkeys(accounts.owners)?,
+            }),
+            None => None,
+        };
+
+        Ok(SubscriptionFilter {
+            accounts,
+            slots: request.slots,
+            transactions_include_votes: request
+                .transactions
+                .map(|transactions| transactions.include_votes),
+            blocks: request.blocks,
+        })
+    }
+
+    fn matches(&self, message: &SinkMessage) -> bool {
+        match message {
+            SinkMessage::UpdateAccount(update_account) => matches!(
+                &self.accounts,
+                Some(accounts) if accounts.matches(&update_account.account)
+            ),
+            SinkMessage::UpdateSlotStatus(_) => self.slots,
+            SinkMessage::NotifyTransaction(_) => matches!(
+                self.transactions_include_votes,
+                Some(include_votes) if include_votes || !message.is_vote_transaction()
+            ),
+            SinkMessage::NotifyBlockMetaData(_) => self.blocks,
+        }
+    }
+}
+
+struct Subscriber {
+    filter: SubscriptionFilter,
+    tx: mpsc::Sender<Update>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// Subscribers shared by the sink and the gRPC service
+#[derive(Default)]
+struct Subscribers {
+    next_id: AtomicU64,
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+    server_error: Mutex<Option<String>>,
+}
+
+impl Subscribers {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Subscriber>> {
+        self.subscribers
+            .lock()
+            .expect("Subscribers lock is poisoned")
+    }
+}
+
+/// Receiving end of a subscription. Once a lagging subscriber has received
+/// the buffered updates, the stream ends with RESOURCE_EXHAUSTED.
+/// The subscriber is removed when the stream is dropped, i.e. when the client disconnects
+pub struct SubscriberStream {
+    id: u64,
+    rx: mpsc::Receiver<Update>,
+    lagged: Arc<AtomicBool>,
+    finished: bool,
+    subscribers: Arc<Subscribers>,
+    stats: Arc<Stats>,
+}
+
+impl Drop for SubscriberStream {
+    fn drop(&mut self) {
+        // A lagging subscriber has already been removed by the sink
+        if self.subscribers.lock().remove(&self.id).is_some() {
+            info!("gRPC subscriber {} disconnected", self.id);
+            self.stats.grpc.subscribers.dec();
+        }
+    }
+}
+
+impl Stream for SubscriberStream {
+    type Item = Result<Update, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(update)) => Poll::Ready(Some(Ok(update))),
+            Poll::Ready(None) if !self.finished && self.lagged.load(Ordering::Relaxed) => {
+                self.finished = true;
+                Poll::Ready(Some(Err(Status::resource_exhausted(
+                    "Subscriber is too slow, the buffer is full",
+                ))))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+struct GeyserNeonService {
+    subscribers: Arc<Subscribers>,
+    stats: Arc<Stats>,
+    subscriber_buffer: usize,
+}
+
+#[tonic::async_trait]
+impl GeyserNeon for GeyserNeonService {
+    type SubscribeStream = SubscriberStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let remote_addr = request.remote_addr();
+        let filter =
+            SubscriptionFilter::new(request.into_inner()).map_err(Status::invalid_argument)?;
+        let (tx, rx) = mpsc::channel(self.subscriber_buffer);
+        let lagged = Arc::new(AtomicBool::new(false));
+
+        let id = self.subscribers.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.lock().insert(
+            id,
+            Subscriber {
+                filter,
+                tx,
+                lagged: lagged.clone(),
+            },
+        );
+        self.stats.grpc.subscribers.inc();
+        info!("gRPC subscriber {id} connected from {remote_addr:?}");
+
+        Ok(Response::new(SubscriberStream {
+            id,
+            rx,
+            lagged,
+            finished: false,
+            subscribers: self.subscribers.clone(),
+            stats: self.stats.clone(),
+        }))
+    }
+}
+
+/// Streams the messages to the gRPC subscribers, every subscriber has its own buffer
+/// and is disconnected when the buffer is full
+pub struct GrpcSink {
+    subscribers: Arc<Subscribers>,
+    stats: Arc<Stats>,
+}
+
+impl GrpcSink {
+    pub fn new(
+        config: &GeyserPluginKafkaConfig,
+        stats: Arc<Stats>,
+        runtime: &Runtime,
+    ) -> Result<Self, SinkError> {
+        let listen_address: SocketAddr = config.grpc_listen_address.parse().map_err(|e| {
+            SinkError::Other(format!(
+                "Wrong gRPC listen address {}, error: {e}",
+                config.grpc_listen_address
+            ))
+        })?;
+
+        let subscriber_buffer = config
+            .grpc_subscriber_buffer
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_GRPC_SUBSCRIBER_BUFFER)
+            .max(1);
+
+        let subscribers = Arc::new(Subscribers::default());
+        let service = GeyserNeonServer::new(GeyserNeonService {
+            subscribers: subscribers.clone(),
+            stats: stats.clone(),
+            subscriber_buffer,
+        });
+
+        let server_subscribers = subscribers.clone();
+        runtime.spawn(async move {
+            info!("Starting gRPC server on {listen_address}");
+            if let Err(e) = Server::builder()
+                .add_service(service)
+                .serve(listen_address)
+                .await
+            {
+                error!("gRPC server on {listen_address} failed, error: {e}");
+                *server_subscribers
+                    .server_error
+                    .lock()
+                    .expect("Server error lock is poisoned") = Some(e.to_string());
+            }
+        });
+
+        Ok(GrpcSink { subscribers, stats })
+    }
+}
+
+#[async_trait]
+impl Sink for GrpcSink {
+    fn name(&self) -> &'static str {
+        "grpc"
+    }
+
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let mut subscribers = self.subscribers.lock();
+        if subscribers.is_empty() {
+            return Ok(());
+        }
+
+        let message_type = match record.message_type() {
+            MessageType::UpdateAccount => proto::MessageType::UpdateAccount,
+            MessageType::UpdateSlot => proto::MessageType::UpdateSlot,
+            MessageType::NotifyTransaction => proto::MessageType::NotifyTransaction,
+            MessageType::NotifyBlock => proto::MessageType::NotifyBlock,
+        };
+        // The payload is only copied once a subscriber wants the message, the subscribers
+        // share the copy
+        let mut update = None;
+        subscribers.retain(|id, subscriber| {
+            if !subscriber.filter.matches(&record.message) {
+                return true;
+            }
+
+            let update = update.get_or_insert_with(|| Update {
+                message_type: message_type as i32,
+                slot: record.message.slot(),
+                payload: Bytes::from(record.payload.clone()),
+            });
+            match subscriber.tx.try_send(update.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("gRPC subscriber {id} is lagging behind, disconnecting it");
+                    subscriber.lagged.store(true, Ordering::Relaxed);
+                    self.stats.grpc.subscribers_lagged.inc();
+                    self.stats.grpc.subscribers.dec();
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    info!("gRPC subscriber {id} disconnected");
+                    self.stats.grpc.subscribers.dec();
+                    false
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn health(&self) -> SinkHealth {
+        match self
+            .subscribers
+            .server_error
+            .lock()
+            .expect("Server error lock is poisoned")
+            .as_ref()
+        {
+            Some(e) => SinkHealth::Unhealthy(format!("gRPC server failed: {e}")),
+            None => SinkHealth::Healthy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use kafka_common::kafka_structs::{
+        KafkaReplicaBlockInfo, KafkaReplicaBlockInfoVersions, NotifyBlockMetaData,
+    };
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::sink::tests::{account_record, slot_record};
+
+    fn filter(request: SubscribeRequest) -> SubscriptionFilter {
+        SubscriptionFilter::new(request).unwrap()
+    }
+
+    fn accounts_filter(pubkeys: &[[u8; 32]], owners: &[[u8; 32]]) -> SubscriptionFilter {
+        let encode = |keys: &[[u8; 32]]| {
+            keys.iter()
+                .map(|key| bs58::encode(key).into_string())
+                .collect()
+        };
+        filter(SubscribeRequest {
+            accounts: Some(proto::AccountsFilter {
+                pubkeys: encode(pubkeys),
+                owners: encode(owners),
+            }),
+            ..Default::default()
+        })
+    }
+
+    fn block_message(slot: u64) -> SinkMessage {
+        NotifyBlockMetaData {
+            block_info: KafkaReplicaBlockInfoVersions::V0_0_1(KafkaReplicaBlockInfo {
+                slot,
+                blockhash: String::new(),
+                rewards: Vec::new(),
+                block_time: None,
+                block_height: None,
+            }),
+            retrieved_time: NaiveDateTime::default(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn filters_match_their_messages() {
+        // The owner of the test accounts is the zero pubkey
+        let account = account_record(&[1; 32], 10, 1, b"data");
+        assert!(accounts_filter(&[], &[]).matches(&account.message));
+        assert!(accounts_filter(&[[1; 32]], &[]).matches(&account.message));
+        assert!(accounts_filter(&[], &[[0; 32]]).matches(&account.message));
+        assert!(!accounts_filter(&[[2; 32]], &[[2; 32]]).matches(&account.message));
+        assert!(!filter(SubscribeRequest::default()).matches(&account.message));
+
+        let slot = slot_record(10);
+        let slots = filter(SubscribeRequest {
+            slots: true,
+            ..Default::default()
+        });
+        assert!(slots.matches(&slot.message));
+        assert!(!slots.matches(&account.message));
+        assert!(!slots.matches(&block_message(10)));
+        assert!(!accounts_filter(&[], &[]).matches(&slot.message));
+
+        let blocks = filter(SubscribeRequest {
+            blocks: true,
+            ..Default::default()
+        });
+        assert!(blocks.matches(&block_message(10)));
+        assert!(!blocks.matches(&slot.message));
+
+        assert!(SubscriptionFilter::new(SubscribeRequest {
+            accounts: Some(proto::AccountsFilter {
+                pubkeys: vec!["0OIl".to_string()],
+                owners: Vec::new(),
+            }),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn lagging_subscribers_are_disconnected() {
+        let runtime = Runtime::new().unwrap();
+        let stats = Arc::new(Stats::default());
+        let sink = GrpcSink {
+            subscribers: Arc::new(Subscribers::default()),
+            stats: stats.clone(),
+        };
+
+        let (tx, rx) = mpsc::channel(1);
+        let lagged = Arc::new(AtomicBool::new(false));
+        sink.subscribers.lock().insert(
+            0,
+            Subscriber {
+                filter: filter(SubscribeRequest {
+                    slots: true,
+                    ..Default::default()
+                }),
+                tx,
+                lagged: lagged.clone(),
+            },
+        );
+        stats.grpc.subscribers.inc();
+        let mut stream = SubscriberStream {
+            id: 0,
+            rx,
+            lagged,
+            finished: false,
+            subscribers: sink.subscribers.clone(),
+            stats: stats.clone(),
+        };
+
+        runtime.block_on(async {
+            sink.publish(slot_record(1)).await.unwrap();
+            sink.publish(slot_record(2)).await.unwrap();
+            assert!(sink.subscribers.lock().is_empty());
+            assert_eq!(stats.grpc.subscribers_lagged.get(), 1);
+            assert_eq!(stats.grpc.subscribers.get(), 0);
+
+            // The buffered update is delivered before the stream ends
+            let update = stream.next().await.unwrap().unwrap();
+            assert_eq!(update.slot, 1);
+            assert_eq!(update.payload, slot_record(1).payload);
+            let status = stream.next().await.unwrap().unwrap_err();
+            assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+            assert!(stream.next().await.is_none());
+        });
+
+        drop(stream);
+        assert_eq!(stats.grpc.subscribers.get(), 0);
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    kafka_producer_stats::Stats,
+    sink::{Sink, SinkHealth},
+};
+
+/// State behind the /readyz endpoint
+#[derive(Default)]
+pub struct Health {
+    startup_finished: AtomicBool,
+    brokers_down: AtomicBool,
+}
+
+impl Health {
+    pub fn set_startup_finished(&self) {
+        self.startup_finished.store(true, Ordering::Relaxed);
+    }
+
+    pub fn startup_finished(&self) -> bool {
+        self.startup_finished.load(Ordering::Relaxed)
+    }
+
+    /// Set when librdkafka reports that all brokers are down, cleared when
+    /// a broker is up again or a message has been delivered
+    pub fn set_brokers_down(&self, brokers_down: bool) {
+        self.brokers_down.store(brokers_down, Ordering::Relaxed);
+    }
+
+    pub fn brokers_down(&self) -> bool {
+        self.brokers_down.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns the reasons the plugin is not ready, the list is empty if it is ready
+pub fn readiness_problems(stats: &Stats, sink: &dyn Sink, max_queue_len: u64) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if !stats.health.startup_finished() {
+        problems.push("startup has not ended".to_string());
+    }
+
+    if let SinkHealth::Unhealthy(reason) = sink.health() {
+        problems.push(format!("{} sink is unhealthy: {reason}", sink.name()));
+    }
+
+    stats.refresh_queues();
+    for (stream, queue) in stats.queues() {
+        let queue_len = queue.queue_len.get();
+        if queue_len > max_queue_len {
+            problems.push(format!(
+                "{stream} queue has {queue_len} messages, more than {max_queue_len}"
+            ));
+        }
+        // The messages taken from the queue wait in the send tasks until the sink has them
+        let in_flight = queue.in_flight.get();
+        if in_flight > max_queue_len {
+            problems.push(format!(
+                "{stream} has {in_flight} messages in flight, more than {max_queue_len}"
+            ));
+        }
+    }
+
+    problems
+}
@@ -1,9 +1,14 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use kafka_common::chunking::{self, ChunkHeader};
 use rdkafka::{
-    error::KafkaResult,
+    error::{KafkaError, KafkaResult, RDKafkaErrorCode},
     message::OwnedHeaders,
-    producer::{future_producer::OwnedDeliveryResult, FutureProducer, FutureRecord},
+    producer::{future_producer::OwnedDeliveryResult, FutureProducer, FutureRecord, Producer},
     util::Timeout,
     ClientConfig,
 };
@@ -13,26 +18,46 @@ use crate::{
     kafka_producer_stats::{ContextWithStats, Stats},
 };
 
+/// Brokers and credentials of one of the Kafka clusters the messages are sent to
+#[derive(Clone, Copy, Debug)]
+pub struct KafkaCluster<'a> {
+    pub brokers_list: &'a str,
+    pub sasl_username: &'a str,
+    pub sasl_password: &'a str,
+    pub sasl_mechanism: &'a str,
+    pub security_protocol: &'a str,
+}
+
 #[derive(Clone)]
 pub struct KafkaProducer {
     pub future_producer: FutureProducer<ContextWithStats>,
     pub config: Arc<GeyserPluginKafkaConfig>,
     pub stats: Arc<Stats>,
+    pub message_chunk_size: usize,
+    // Number of partitions of every topic a record with a partition key has been sent to
+    partition_counts: Arc<Mutex<HashMap<String, usize>>>,
 }
 
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl KafkaProducer {
     pub fn new(
         config: Arc<GeyserPluginKafkaConfig>,
+        cluster: KafkaCluster<'_>,
         context_with_stats: ContextWithStats,
     ) -> KafkaResult<Self> {
         let stats = context_with_stats.stats.clone();
+        let message_chunk_size = match config.message_chunk_size.parse::<usize>().unwrap_or(0) {
+            0 => 0,
+            size => size.max(chunking::MIN_CHUNK_SIZE),
+        };
         let future_producer: FutureProducer<ContextWithStats> = ClientConfig::new()
-            .set("bootstrap.servers", &config.brokers_list)
+            .set("bootstrap.servers", cluster.brokers_list)
             .set("message.timeout.ms", &config.message_timeout_ms)
-            .set("security.protocol", &config.security_protocol)
-            .set("sasl.mechanism", &config.sasl_mechanism)
-            .set("sasl.username", &config.sasl_username)
-            .set("sasl.password", &config.sasl_password)
+            .set("security.protocol", cluster.security_protocol)
+            .set("sasl.mechanism", cluster.sasl_mechanism)
+            .set("sasl.username", cluster.sasl_username)
+            .set("sasl.password", cluster.sasl_password)
             .set_log_level((&config.kafka_log_level).into())
             .set(
                 "message.send.max.retries",
@@ -62,6 +87,8 @@ impl KafkaProducer {
             future_producer,
             config,
             stats,
+            message_chunk_size,
+            partition_counts: Arc::default(),
         })
     }
 
@@ -69,19 +96,108 @@ impl KafkaProducer {
         self.stats.clone()
     }
 
+    /// Picks the partition of the records with the partition key, so the records of an account
+    /// end up in the same partition while their keys stay unique. The number of partitions of a
+    /// topic is fetched once, so the plugin has to be restarted after partitions are added.
+    pub async fn partition(&self, topic: &str, partition_key: &str) -> KafkaResult<i32> {
+        let cached = self
+            .partition_counts
+            .lock()
+            .expect("Partition counts mutex is poisoned")
+            .get(topic)
+            .copied();
+
+        let partition_count = match cached {
+            Some(partition_count) => partition_count,
+            None => {
+                let producer = self.future_producer.clone();
+                let topic_name = topic.to_string();
+                let metadata = tokio::task::spawn_blocking(move || {
+                    producer
+                        .client()
+                        .fetch_metadata(Some(&topic_name), METADATA_TIMEOUT)
+                })
+                .await
+                .map_err(|_| KafkaError::MetadataFetch(RDKafkaErrorCode::Fail))??;
+
+                let partition_count = metadata
+                    .topics()
+                    .iter()
+                    .find(|metadata_topic| metadata_topic.name() == topic)
+                    .map(|metadata_topic| metadata_topic.partitions().len())
+                    .filter(|partition_count| *partition_count > 0)
+                    .ok_or(KafkaError::MetadataFetch(
+                        RDKafkaErrorCode::UnknownTopicOrPartition,
+                    ))?;
+
+                self.partition_counts
+                    .lock()
+                    .expect("Partition counts mutex is poisoned")
+                    .insert(topic.to_string(), partition_count);
+                partition_count
+            }
+        };
+
+        let hash = blake3::hash(partition_key.as_bytes());
+        let hash = u64::from_le_bytes(hash.as_bytes()[..8].try_into().expect("Slice has 8 bytes"));
+        Ok((hash % partition_count as u64) as i32)
+    }
+
     pub async fn send(
         &mut self,
         topic: &str,
-        message: &str,
+        message: &[u8],
         key: &str,
+        partition: Option<i32>,
         headers: Option<OwnedHeaders>,
     ) -> OwnedDeliveryResult {
         let mut future_record = FutureRecord::to(topic).payload(message).key(key);
 
+        future_record.partition = partition;
         future_record.headers = headers;
 
         self.future_producer
             .send(future_record, Timeout::Never)
             .await
     }
+
+    /// Sends every chunk of the message as a separate record with the same key.
+    /// The chunk id is the hash of the message, since several messages may share a key.
+    /// Stops at the first chunk that could not be delivered.
+    pub async fn send_chunked(
+        &mut self,
+        topic: &str,
+        message: &[u8],
+        key: &str,
+        partition: Option<i32>,
+        chunk_size: usize,
+    ) -> OwnedDeliveryResult {
+        let chunks = chunking::split(message, chunk_size);
+        let count = chunks.len() as u32;
+        if count < 2 {
+            return self.send(topic, message, key, partition, None).await;
+        }
+
+        let chunk_id = blake3::hash(message).to_string();
+        let mut delivery = (0, 0);
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let chunk_header = ChunkHeader {
+                id: chunk_id.clone(),
+                index: index as u32,
+                count,
+            };
+            let headers = chunk_header
+                .to_headers()
+                .into_iter()
+                .fold(OwnedHeaders::new(), |headers, (name, value)| {
+                    headers.add(name, value.as_str())
+                });
+
+            delivery = self
+                .send(topic, chunk, key, partition, Some(headers))
+                .await?;
+        }
+
+        Ok(delivery)
+    }
 }
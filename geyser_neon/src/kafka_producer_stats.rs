@@ -1,8 +1,35 @@
-use log::info;
-use prometheus_client::metrics::counter::Counter;
-use rdkafka::{ClientContext, Statistics};
-use std::sync::atomic::AtomicU64;
-use std::sync::Arc;
+use chrono::{NaiveDateTime, Utc};
+use flume::Receiver;
+use kafka_common::{kafka_structs::KafkaSlotStatus, message_type::MessageType};
+use log::{debug, error};
+use prometheus_client::{
+    encoding::text::{EncodeMetric, Encoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+        MetricType, TypedMetric,
+    },
+};
+use rdkafka::{
+    error::KafkaError,
+    statistics::{Broker, Partition},
+    types::RDKafkaErrorCode,
+    ClientContext, Statistics,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::{
+    grpc_sink::GrpcStats, health::Health, s3_uploader::UploadStats, sink::SinkError,
+    websocket_sink::WebSocketStats,
+};
+
+pub type Labels = Vec<(String, String)>;
 
 #[derive(Default)]
 pub struct Stats {
@@ -15,16 +42,782 @@ pub struct Stats {
     pub kafka_error_notify_transaction: Counter<u64, AtomicU64>,
     pub kafka_error_notify_block: Counter<u64, AtomicU64>,
     pub kafka_error_serialize: Counter<u64, AtomicU64>,
+    pub kafka_error_compress: Counter<u64, AtomicU64>,
+    pub kafka_error_delivery: Family<Labels, Counter<u64, AtomicU64>>,
     pub kafka_bytes_tx: Counter<u64, AtomicU64>,
+    pub kafka_chunked_messages: Counter<u64, AtomicU64>,
+    pub kafka_account_data_keyframes: Counter<u64, AtomicU64>,
+    pub kafka_account_data_deltas: Counter<u64, AtomicU64>,
+    pub kafka_secondary: SecondaryClusterStats,
+    pub grpc: GrpcStats,
+    pub websocket: WebSocketStats,
+    pub s3_uploads: UploadStats,
+    pub update_account_queue: QueueStats,
+    pub update_slot_queue: QueueStats,
+    pub notify_transaction_queue: QueueStats,
+    pub notify_block_queue: QueueStats,
+    pub update_account_latency: LatencyStats,
+    pub update_slot_latency: LatencyStats,
+    pub notify_transaction_latency: LatencyStats,
+    pub notify_block_latency: LatencyStats,
+    pub callbacks: CallbackStats,
+    pub slots: SlotStats,
+    pub owners: OwnerStats,
+    pub sinks: SinkStats,
+    pub rdkafka: RdKafkaStats,
+    pub health: Health,
+}
+
+impl Stats {
+    /// The internal queues of the streams with their names
+    pub fn queues(&self) -> [(&'static str, &QueueStats); 4] {
+        [
+            ("update_account", &self.update_account_queue),
+            ("update_slot", &self.update_slot_queue),
+            ("notify_transaction", &self.notify_transaction_queue),
+            ("notify_block", &self.notify_block_queue),
+        ]
+    }
+
+    /// Reads the lengths of the internal queues, called when the metrics are scraped
+    pub fn refresh_queues(&self) {
+        for (_, queue) in self.queues() {
+            queue.refresh();
+        }
+    }
+
+    pub fn delivery_failed(&self, message_type: &MessageType, error: &SinkError) {
+        let reason = match error {
+            SinkError::Kafka(error) => delivery_error_reason(error),
+            SinkError::Io(_) => "io",
+            SinkError::Other(_) => "other",
+        };
+        let labels = vec![
+            ("message_type".to_string(), message_type.to_string()),
+            ("reason".to_string(), reason.to_string()),
+        ];
+        self.kafka_error_delivery.get_or_create(&labels).inc();
+    }
+}
+
+/// Groups the delivery errors by their cause, so a misconfiguration can be told from an outage
+fn delivery_error_reason(error: &KafkaError) -> &'static str {
+    match error.rdkafka_error_code() {
+        Some(RDKafkaErrorCode::MessageSizeTooLarge | RDKafkaErrorCode::InvalidMessageSize) => {
+            "message_too_large"
+        }
+        Some(RDKafkaErrorCode::QueueFull) => "queue_full",
+        Some(
+            RDKafkaErrorCode::MessageTimedOut
+            | RDKafkaErrorCode::RequestTimedOut
+            | RDKafkaErrorCode::TimedOutQueue,
+        ) => "timed_out",
+        Some(
+            RDKafkaErrorCode::TopicAuthorizationFailed
+            | RDKafkaErrorCode::ClusterAuthorizationFailed
+            | RDKafkaErrorCode::TransactionalIdAuthorizationFailed
+            | RDKafkaErrorCode::SaslAuthenticationFailed
+            | RDKafkaErrorCode::Authentication,
+        ) => "authorization_failed",
+        Some(
+            RDKafkaErrorCode::UnknownTopic
+            | RDKafkaErrorCode::UnknownTopicOrPartition
+            | RDKafkaErrorCode::UnknownPartition,
+        ) => "unknown_topic",
+        Some(RDKafkaErrorCode::AllBrokersDown | RDKafkaErrorCode::BrokerTransportFailure) => {
+            "broker_down"
+        }
+        _ => "other",
+    }
+}
+
+/// Deliveries to the secondary Kafka cluster the messages are mirrored to, by topic
+#[derive(Default)]
+pub struct SecondaryClusterStats {
+    pub sent: Family<Labels, Counter<u64, AtomicU64>>,
+    pub unsent: Family<Labels, Counter<u64, AtomicU64>>,
+    brokers_down: AtomicBool,
+}
+
+impl SecondaryClusterStats {
+    pub fn delivered(&self, topic: &str, delivered: bool) {
+        let labels = vec![("topic".to_string(), topic.to_string())];
+        if delivered {
+            self.sent.get_or_create(&labels).inc();
+        } else {
+            self.unsent.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn set_brokers_down(&self, brokers_down: bool) {
+        self.brokers_down.store(brokers_down, Ordering::Relaxed);
+    }
+
+    pub fn brokers_down(&self) -> bool {
+        self.brokers_down.load(Ordering::Relaxed)
+    }
+}
+
+type QueueLen = Box<dyn Fn() -> usize + Send + Sync>;
+
+/// Backpressure of one of the streams from the geyser callbacks to Kafka
+#[derive(Default)]
+pub struct QueueStats {
+    pub queue_len: Gauge<u64, AtomicU64>,
+    pub queue_len_max: Gauge<u64, AtomicU64>,
+    pub in_flight: Gauge<u64, AtomicU64>,
+    pub in_flight_max: Gauge<u64, AtomicU64>,
+    channel_len: RwLock<Option<QueueLen>>,
+}
+
+impl QueueStats {
+    /// Lets `refresh` read the length of the channel, so the gauge is also
+    /// up to date while no message is taken from it
+    pub fn watch<T: Send + 'static>(&self, rx: &Receiver<T>) {
+        let rx = rx.clone();
+        *self.channel_len.write().expect("Queue lock is poisoned") =
+            Some(Box::new(move || rx.len()));
+    }
+
+    /// Records the current length of the watched channel
+    pub fn refresh(&self) {
+        if let Some(channel_len) = &*self.channel_len.read().expect("Queue lock is poisoned") {
+            self.set_queue_len(channel_len());
+        }
+    }
+
+    /// Records how many messages are left in the channel
+    pub fn set_queue_len(&self, len: usize) {
+        let len = len as u64;
+        self.queue_len.set(len);
+        self.queue_len_max.inner().fetch_max(len, Ordering::Relaxed);
+    }
+
+    /// Called before a message is handed over to a send task
+    pub fn task_started(&self) {
+        let in_flight = self.in_flight.inc() + 1;
+        self.in_flight_max
+            .inner()
+            .fetch_max(in_flight, Ordering::Relaxed);
+    }
+
+    /// Called when the send task is done, whatever the result is
+    pub fn task_finished(&self) {
+        self.in_flight.dec();
+    }
+}
+
+/// Time in seconds a message spends in every stage of its way to Kafka
+pub struct LatencyStats {
+    pub callback_to_dequeue: Histogram,
+    pub dequeue_to_serialized: Histogram,
+    pub serialized_to_ack: Histogram,
+    /// From the block time to the delivery, only known for NotifyBlock messages
+    pub slot_to_publish: Histogram,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        // From 100us to about 52 seconds
+        let buckets = || exponential_buckets(0.0001, 2.0, 20);
+        LatencyStats {
+            callback_to_dequeue: Histogram::new(buckets()),
+            dequeue_to_serialized: Histogram::new(buckets()),
+            serialized_to_ack: Histogram::new(buckets()),
+            // Block time has a resolution of one second
+            slot_to_publish: Histogram::new(exponential_buckets(1.0, 2.0, 10)),
+        }
+    }
+}
+
+impl LatencyStats {
+    /// Records the time since the geyser callback has received the message
+    pub fn dequeued(&self, retrieved_time: NaiveDateTime) {
+        let elapsed = Utc::now().naive_utc() - retrieved_time;
+        let elapsed = elapsed.num_microseconds().unwrap_or(i64::MAX).max(0);
+        self.callback_to_dequeue
+            .observe(elapsed as f64 / 1_000_000.0);
+    }
+
+    /// Records the time since the block was produced, `block_time` is a Unix timestamp in seconds
+    pub fn published(&self, block_time: i64) {
+        let lag = Utc::now().timestamp_millis() as f64 / 1000.0 - block_time as f64;
+        self.slot_to_publish.observe(lag.max(0.0));
+    }
+}
+
+/// Latest slots per commitment level, labelled with the slot status. The acknowledged slot is
+/// a watermark, every UpdateSlotStatus message up to it has been acknowledged or has failed
+#[derive(Default)]
+pub struct SlotStats {
+    pub seen: Family<Labels, Gauge<u64, AtomicU64>>,
+    pub acknowledged: Family<Labels, Gauge<u64, AtomicU64>>,
+    outstanding: Mutex<HashMap<String, OutstandingSlots>>,
+}
+
+/// UpdateSlotStatus messages of a slot status which are being sent
+#[derive(Default)]
+struct OutstandingSlots {
+    // Number of messages being sent for every slot
+    in_flight: BTreeMap<u64, usize>,
+    // Acknowledged slots above the lowest slot in flight
+    acknowledged: BTreeSet<u64>,
+}
+
+impl OutstandingSlots {
+    fn sent(&mut self, slot: u64) {
+        *self.in_flight.entry(slot).or_default() += 1;
+    }
+
+    /// Returns the highest acknowledged slot below the lowest slot still in flight
+    fn completed(&mut self, slot: u64, acknowledged: bool) -> Option<u64> {
+        if let Some(count) = self.in_flight.get_mut(&slot) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(&slot);
+            }
+        }
+        if acknowledged {
+            self.acknowledged.insert(slot);
+        }
+
+        let watermark = match self.in_flight.keys().next() {
+            Some(lowest_in_flight) => self.acknowledged.range(..lowest_in_flight).next_back(),
+            None => self.acknowledged.iter().next_back(),
+        }
+        .copied()?;
+
+        self.acknowledged = self.acknowledged.split_off(&watermark);
+        Some(watermark)
+    }
+}
+
+impl SlotStats {
+    fn status_labels(status: KafkaSlotStatus) -> Labels {
+        vec![("status".to_string(), status.to_string())]
+    }
+
+    /// Called when the validator notifies the plugin about the slot status
+    pub fn slot_seen(&self, status: KafkaSlotStatus, slot: u64) {
+        self.seen
+            .get_or_create(&Self::status_labels(status))
+            .inner()
+            .fetch_max(slot, Ordering::Relaxed);
+    }
+
+    /// Called before the UpdateSlotStatus message is sent
+    pub fn slot_sent(&self, status: KafkaSlotStatus, slot: u64) {
+        self.outstanding
+            .lock()
+            .expect("Outstanding slots lock is poisoned")
+            .entry(status.to_string())
+            .or_default()
+            .sent(slot);
+    }
+
+    /// Called when Kafka has acknowledged the UpdateSlotStatus message
+    pub fn slot_acknowledged(&self, status: KafkaSlotStatus, slot: u64) {
+        self.slot_completed(status, slot, true);
+    }
+
+    /// Called when the UpdateSlotStatus message could not be delivered. The failure is counted
+    /// by the delivery errors, and the slot no longer holds back the acknowledged slot
+    pub fn slot_failed(&self, status: KafkaSlotStatus, slot: u64) {
+        self.slot_completed(status, slot, false);
+    }
+
+    // Sends complete out of order, the gauge only moves to a slot once every lower slot
+    // has completed, and never goes back
+    fn slot_completed(&self, status: KafkaSlotStatus, slot: u64, acknowledged: bool) {
+        let watermark = self
+            .outstanding
+            .lock()
+            .expect("Outstanding slots lock is poisoned")
+            .entry(status.to_string())
+            .or_default()
+            .completed(slot, acknowledged);
+
+        if let Some(watermark) = watermark {
+            self.acknowledged
+                .get_or_create(&Self::status_labels(status))
+                .inner()
+                .fetch_max(watermark, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Delivered account updates per owner program. Only the first `max_owners` programs
+/// get a label of their own, updates of the other programs are counted as "other"
+#[derive(Default)]
+pub struct OwnerStats {
+    pub messages: Family<Labels, Counter<u64, AtomicU64>>,
+    pub bytes: Family<Labels, Counter<u64, AtomicU64>>,
+    owners: Mutex<HashSet<String>>,
+}
+
+impl OwnerStats {
+    pub fn labels(&self, owner: &[u8], max_owners: usize) -> Labels {
+        let owner = bs58::encode(owner).into_string();
+        let mut owners = self.owners.lock().expect("Owners lock is poisoned");
+        let owner = if owners.contains(&owner) || owners.len() < max_owners {
+            owners.insert(owner.clone());
+            owner
+        } else {
+            "other".to_string()
+        };
+        vec![("owner".to_string(), owner)]
+    }
+
+    pub fn delivered(&self, labels: &Labels, bytes: usize) {
+        self.messages.get_or_create(labels).inc();
+        self.bytes.get_or_create(labels).inc_by(bytes as u64);
+    }
+}
+
+/// Messages of every sink instance when the messages are fanned out to several sinks.
+/// The counters are labelled by sink and message type, the gauges by sink
+#[derive(Default)]
+pub struct SinkStats {
+    pub published: Family<Labels, Counter<u64, AtomicU64>>,
+    pub failed: Family<Labels, Counter<u64, AtomicU64>>,
+    pub dropped: Family<Labels, Counter<u64, AtomicU64>>,
+    pub queue_len: Family<Labels, Gauge<u64, AtomicU64>>,
+    pub in_flight: Family<Labels, Gauge<u64, AtomicU64>>,
+}
+
+impl SinkStats {
+    pub fn labels(sink: &str, message_type: &MessageType) -> Labels {
+        vec![
+            ("sink".to_string(), sink.to_string()),
+            ("message_type".to_string(), message_type.to_string()),
+        ]
+    }
+
+    pub fn sink_labels(sink: &str) -> Labels {
+        vec![("sink".to_string(), sink.to_string())]
+    }
+}
+
+/// Minimum time between two warnings about slow geyser callbacks
+const SLOW_CALL_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time in seconds spent inside the geyser callbacks on the validator threads
+pub struct CallbackStats {
+    pub update_account: Histogram,
+    pub update_slot_status: Histogram,
+    pub notify_transaction: Histogram,
+    pub notify_block_metadata: Histogram,
+    started: Instant,
+    // Microseconds since `started` before which no warning is logged
+    next_warning_us: AtomicU64,
+    slow_calls: AtomicU64,
+}
+
+impl Default for CallbackStats {
+    fn default() -> Self {
+        // From 1us to about 8 seconds
+        let buckets = || exponential_buckets(0.000001, 2.0, 24);
+        CallbackStats {
+            update_account: Histogram::new(buckets()),
+            update_slot_status: Histogram::new(buckets()),
+            notify_transaction: Histogram::new(buckets()),
+            notify_block_metadata: Histogram::new(buckets()),
+            started: Instant::now(),
+            next_warning_us: AtomicU64::new(0),
+            slow_calls: AtomicU64::new(0),
+        }
+    }
+}
+
+impl CallbackStats {
+    /// Records a slow call, returns the number of slow calls since the last warning
+    /// if a warning should be logged for it. At most one warning is due per
+    /// `SLOW_CALL_WARNING_INTERVAL`, so a stalled queue does not flood the log
+    /// from the validator threads
+    pub fn slow_call(&self) -> Option<u64> {
+        self.slow_call_at(self.started.elapsed())
+    }
+
+    fn slow_call_at(&self, elapsed: Duration) -> Option<u64> {
+        let now_us = elapsed.as_micros() as u64;
+        let next_warning_us = self.next_warning_us.load(Ordering::Relaxed);
+        self.slow_calls.fetch_add(1, Ordering::Relaxed);
+        if now_us < next_warning_us
+            || self
+                .next_warning_us
+                .compare_exchange(
+                    next_warning_us,
+                    now_us + SLOW_CALL_WARNING_INTERVAL.as_micros() as u64,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return None;
+        }
+        Some(self.slow_calls.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// A metric whose value is copied from the librdkafka statistics
+pub trait StatisticsMetric: EncodeMetric + TypedMetric {
+    fn with_value(value: u64) -> Self;
+}
+
+impl StatisticsMetric for Gauge<u64, AtomicU64> {
+    fn with_value(value: u64) -> Self {
+        let gauge = Gauge::default();
+        gauge.set(value);
+        gauge
+    }
+}
+
+impl StatisticsMetric for Counter<u64, AtomicU64> {
+    fn with_value(value: u64) -> Self {
+        let counter = Counter::default();
+        counter.inc_by(value);
+        counter
+    }
+}
+
+/// Metric family replaced for a client on every statistics update, so the brokers and
+/// partitions missing from the latest statistics are no longer exported.
+/// The `Family` of prometheus-client cannot remove label sets
+pub struct StatisticsFamily<M> {
+    values: Arc<RwLock<HashMap<Labels, u64>>>,
+    metric: PhantomData<M>,
+}
+
+impl<M> Default for StatisticsFamily<M> {
+    fn default() -> Self {
+        StatisticsFamily {
+            values: Arc::default(),
+            metric: PhantomData,
+        }
+    }
+}
+
+impl<M> Clone for StatisticsFamily<M> {
+    fn clone(&self) -> Self {
+        StatisticsFamily {
+            values: self.values.clone(),
+            metric: PhantomData,
+        }
+    }
+}
+
+impl<M> StatisticsFamily<M> {
+    /// Replaces the values of the client, the first label of every label set is the client name
+    fn update<I: IntoIterator<Item = (Labels, u64)>>(&self, client: &(String, String), values: I) {
+        let mut guard = self.values.write().expect("Statistics mutex is poisoned");
+        guard.retain(|labels, _| labels.first() != Some(client));
+        guard.extend(values);
+    }
+
+    pub fn get(&self, labels: &Labels) -> Option<u64> {
+        self.values
+            .read()
+            .expect("Statistics mutex is poisoned")
+            .get(labels)
+            .copied()
+    }
+}
+
+impl<M: StatisticsMetric> EncodeMetric for StatisticsFamily<M> {
+    fn encode(&self, mut encoder: Encoder) -> Result<(), std::io::Error> {
+        let guard = self.values.read().expect("Statistics mutex is poisoned");
+        for (labels, value) in guard.iter() {
+            M::with_value(*value).encode(encoder.with_label_set(labels))?;
+        }
+        Ok(())
+    }
+
+    fn metric_type(&self) -> MetricType {
+        M::TYPE
+    }
+}
+
+type StatisticsGauge = StatisticsFamily<Gauge<u64, AtomicU64>>;
+type StatisticsCounter = StatisticsFamily<Counter<u64, AtomicU64>>;
+
+/// Metrics filled from the librdkafka statistics, emitted every statistics_interval_ms.
+/// The cumulative librdkafka values are exported as counters
+#[derive(Default)]
+pub struct RdKafkaStats {
+    pub queue_messages: StatisticsGauge,
+    pub queue_bytes: StatisticsGauge,
+    pub broker_up: StatisticsGauge,
+    pub broker_rtt_avg: StatisticsGauge,
+    pub broker_rtt_p99: StatisticsGauge,
+    pub broker_outbuf_requests: StatisticsGauge,
+    pub broker_waitresp_requests: StatisticsGauge,
+    pub broker_tx_errors: StatisticsCounter,
+    pub broker_request_timeouts: StatisticsCounter,
+    pub partition_queued_messages: StatisticsGauge,
+    pub partition_inflight_messages: StatisticsGauge,
+    pub partition_tx_messages: StatisticsCounter,
+    pub partition_tx_bytes: StatisticsCounter,
+}
+
+// librdkafka reports some of the values as signed integers, negative values are not expected
+fn non_negative(value: i64) -> u64 {
+    value.max(0) as u64
+}
+
+impl RdKafkaStats {
+    pub fn update(&self, statistics: &Statistics) {
+        let client = ("client".to_string(), statistics.name.clone());
+        let client_labels = vec![client.clone()];
+
+        self.queue_messages
+            .update(&client, [(client_labels.clone(), statistics.msg_cnt)]);
+        self.queue_bytes
+            .update(&client, [(client_labels, statistics.msg_size)]);
+
+        let brokers: Vec<_> = statistics
+            .brokers
+            .values()
+            .map(|broker| {
+                let labels = vec![client.clone(), ("broker".to_string(), broker.name.clone())];
+                (labels, broker)
+            })
+            .collect();
+        let broker_values = |value: fn(&Broker) -> Option<u64>| {
+            brokers
+                .iter()
+                .filter_map(move |(labels, broker)| Some((labels.clone(), value(broker)?)))
+        };
+
+        self.broker_up.update(
+            &client,
+            broker_values(|broker| Some((broker.state == "UP") as u64)),
+        );
+        self.broker_outbuf_requests.update(
+            &client,
+            broker_values(|broker| Some(non_negative(broker.outbuf_cnt))),
+        );
+        self.broker_waitresp_requests.update(
+            &client,
+            broker_values(|broker| Some(non_negative(broker.waitresp_cnt))),
+        );
+        self.broker_tx_errors
+            .update(&client, broker_values(|broker| Some(broker.txerrs)));
+        self.broker_request_timeouts
+            .update(&client, broker_values(|broker| Some(broker.req_timeouts)));
+        self.broker_rtt_avg.update(
+            &client,
+            broker_values(|broker| broker.rtt.as_ref().map(|rtt| non_negative(rtt.avg))),
+        );
+        self.broker_rtt_p99.update(
+            &client,
+            broker_values(|broker| broker.rtt.as_ref().map(|rtt| non_negative(rtt.p99))),
+        );
+
+        let partitions: Vec<_> = statistics
+            .topics
+            .values()
+            .flat_map(|topic| {
+                let client = client.clone();
+                topic.partitions.values().map(move |partition| {
+                    let labels = vec![
+                        client.clone(),
+                        ("topic".to_string(), topic.topic.clone()),
+                        ("partition".to_string(), partition.partition.to_string()),
+                    ];
+                    (labels, partition)
+                })
+            })
+            .collect();
+        let partition_values = |value: fn(&Partition) -> u64| {
+            partitions
+                .iter()
+                .map(move |(labels, partition)| (labels.clone(), value(partition)))
+        };
+
+        self.partition_queued_messages.update(
+            &client,
+            partition_values(|partition| {
+                non_negative(partition.msgq_cnt + partition.xmit_msgq_cnt)
+            }),
+        );
+        self.partition_inflight_messages.update(
+            &client,
+            partition_values(|partition| non_negative(partition.msgs_inflight)),
+        );
+        self.partition_tx_messages
+            .update(&client, partition_values(|partition| partition.txmsgs));
+        self.partition_tx_bytes
+            .update(&client, partition_values(|partition| partition.txbytes));
+    }
 }
 
 #[derive(Default, Clone)]
 pub struct ContextWithStats {
     pub stats: Arc<Stats>,
+    // Set for the producers of the secondary cluster, they keep their own broker state
+    // and don't report the rdkafka statistics, which describe the primary cluster
+    pub secondary: bool,
+}
+
+impl ContextWithStats {
+    pub fn secondary(&self) -> Self {
+        ContextWithStats {
+            stats: self.stats.clone(),
+            secondary: true,
+        }
+    }
+
+    fn set_brokers_down(&self, brokers_down: bool) {
+        if self.secondary {
+            self.stats.kafka_secondary.set_brokers_down(brokers_down);
+        } else {
+            self.stats.health.set_brokers_down(brokers_down);
+        }
+    }
 }
 
 impl ClientContext for ContextWithStats {
     fn stats(&self, stats: Statistics) {
-        info!("{:?}", stats);
+        if !self.secondary {
+            self.stats.rdkafka.update(&stats);
+        }
+        if stats.brokers.values().any(|broker| broker.state == "UP") {
+            self.set_brokers_down(false);
+        }
+        debug!("{:?}", stats);
+    }
+
+    fn error(&self, error: KafkaError, reason: &str) {
+        if error.rdkafka_error_code() == Some(RDKafkaErrorCode::AllBrokersDown) {
+            self.set_brokers_down(true);
+        }
+        if self.secondary {
+            error!("librdkafka (secondary cluster): {}: {}", error, reason);
+        } else {
+            error!("librdkafka: {}: {}", error, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::{encoding::text::encode, registry::Registry};
+
+    use super::*;
+
+    fn acknowledged(slot_stats: &SlotStats) -> u64 {
+        slot_stats
+            .acknowledged
+            .get_or_create(&SlotStats::status_labels(KafkaSlotStatus::Confirmed))
+            .get()
+    }
+
+    #[test]
+    fn acknowledged_slot_waits_for_lower_slots() {
+        let slot_stats = SlotStats::default();
+        let status = KafkaSlotStatus::Confirmed;
+        for slot in [10, 11, 12] {
+            slot_stats.slot_sent(status, slot);
+        }
+
+        slot_stats.slot_acknowledged(status, 12);
+        assert_eq!(acknowledged(&slot_stats), 0);
+        slot_stats.slot_acknowledged(status, 10);
+        assert_eq!(acknowledged(&slot_stats), 10);
+        slot_stats.slot_acknowledged(status, 11);
+        assert_eq!(acknowledged(&slot_stats), 12);
+    }
+
+    #[test]
+    fn failed_slot_does_not_hold_back_acknowledged_slot() {
+        let slot_stats = SlotStats::default();
+        let status = KafkaSlotStatus::Confirmed;
+        for slot in [10, 11, 12] {
+            slot_stats.slot_sent(status, slot);
+        }
+
+        slot_stats.slot_acknowledged(status, 10);
+        slot_stats.slot_acknowledged(status, 12);
+        slot_stats.slot_failed(status, 11);
+        assert_eq!(acknowledged(&slot_stats), 12);
+
+        // A late lower slot does not move the gauge back
+        slot_stats.slot_sent(status, 9);
+        slot_stats.slot_acknowledged(status, 9);
+        assert_eq!(acknowledged(&slot_stats), 12);
+    }
+
+    fn labels(client: &str, broker: &str) -> Labels {
+        vec![
+            ("client".to_string(), client.to_string()),
+            ("broker".to_string(), broker.to_string()),
+        ]
+    }
+
+    #[test]
+    fn statistics_family_replaces_client_values() {
+        let family = StatisticsCounter::default();
+        let first = ("client".to_string(), "first".to_string());
+        let second = ("client".to_string(), "second".to_string());
+
+        family.update(
+            &first,
+            [(labels("first", "a"), 1), (labels("first", "b"), 2)],
+        );
+        family.update(&second, [(labels("second", "a"), 3)]);
+        family.update(&first, [(labels("first", "a"), 5)]);
+
+        assert_eq!(family.get(&labels("first", "a")), Some(5));
+        assert_eq!(family.get(&labels("first", "b")), None);
+        assert_eq!(family.get(&labels("second", "a")), Some(3));
+    }
+
+    #[test]
+    fn statistics_counters_are_exported_as_totals() {
+        let family = StatisticsCounter::default();
+        family.update(
+            &("client".to_string(), "first".to_string()),
+            [(labels("first", "a"), 7)],
+        );
+
+        let mut registry = <Registry>::default();
+        registry.register("tx_errors", "Errors", Box::new(family));
+        let mut buffer = Vec::new();
+        encode(&mut buffer, &registry).unwrap();
+
+        let text = String::from_utf8(buffer).unwrap();
+        assert!(text.contains("# TYPE tx_errors counter"));
+        assert!(text.contains("tx_errors_total{client=\"first\",broker=\"a\"} 7"));
+    }
+
+    #[test]
+    fn queue_len_is_read_from_the_channel() {
+        let queue = QueueStats::default();
+        let (tx, rx) = flume::unbounded();
+        queue.watch(&rx);
+
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        queue.refresh();
+        assert_eq!(queue.queue_len.get(), 3);
+
+        rx.recv().unwrap();
+        queue.refresh();
+        assert_eq!(queue.queue_len.get(), 2);
+        assert_eq!(queue.queue_len_max.get(), 3);
+    }
+
+    #[test]
+    fn slow_call_warnings_are_rate_limited() {
+        let callbacks = CallbackStats::default();
+        let at = |secs| callbacks.slow_call_at(Duration::from_secs(secs));
+
+        assert_eq!(at(0), Some(1));
+        assert_eq!(at(1), None);
+        assert_eq!(at(9), None);
+        assert_eq!(at(10), Some(3));
+        assert_eq!(at(11), None);
+        assert_eq!(at(25), Some(2));
     }
 }
@@ -0,0 +1,324 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use kafka_common::message_type::MessageType;
+use log::error;
+use rdkafka::{
+    error::{KafkaError, KafkaResult},
+    producer::{FutureProducer, Producer},
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    geyser_neon_config::GeyserPluginKafkaConfig,
+    kafka_producer::{KafkaCluster, KafkaProducer},
+    kafka_producer_stats::{ContextWithStats, Stats},
+    sink::{Sink, SinkError, SinkHealth, SinkRecord},
+};
+
+/// How the messages are mirrored to the secondary Kafka cluster
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum MirrorMode {
+    /// A message is sent once the primary cluster has it, it is mirrored in the background and
+    /// the failures of the secondary are only counted
+    #[default]
+    BestEffort,
+    /// A message is sent once both clusters have it
+    Required,
+}
+
+/// A producer per message type, so a busy stream does not fill the producer queue of the others
+struct KafkaProducers {
+    update_account: KafkaProducer,
+    update_slot: KafkaProducer,
+    notify_transaction: KafkaProducer,
+    notify_block: KafkaProducer,
+}
+
+impl KafkaProducers {
+    fn new(
+        config: &Arc<GeyserPluginKafkaConfig>,
+        cluster: KafkaCluster<'_>,
+        ctx_stats: ContextWithStats,
+    ) -> KafkaResult<Self> {
+        Ok(KafkaProducers {
+            update_account: KafkaProducer::new(config.clone(), cluster, ctx_stats.clone())?,
+            update_slot: KafkaProducer::new(config.clone(), cluster, ctx_stats.clone())?,
+            notify_transaction: KafkaProducer::new(config.clone(), cluster, ctx_stats.clone())?,
+            notify_block: KafkaProducer::new(config.clone(), cluster, ctx_stats)?,
+        })
+    }
+
+    fn get(&self, message_type: MessageType) -> &KafkaProducer {
+        match message_type {
+            MessageType::UpdateAccount => &self.update_account,
+            MessageType::UpdateSlot => &self.update_slot,
+            MessageType::NotifyTransaction => &self.notify_transaction,
+            MessageType::NotifyBlock => &self.notify_block,
+        }
+    }
+
+    fn future_producers(&self) -> [FutureProducer<ContextWithStats>; 4] {
+        [
+            self.update_account.future_producer.clone(),
+            self.update_slot.future_producer.clone(),
+            self.notify_transaction.future_producer.clone(),
+            self.notify_block.future_producer.clone(),
+        ]
+    }
+}
+
+async fn send(
+    producer: &KafkaProducer,
+    topic: &str,
+    record: &SinkRecord,
+) -> Result<(), KafkaError> {
+    let mut producer = producer.clone();
+
+    let partition = match &record.partition_key {
+        Some(partition_key) => Some(producer.partition(topic, partition_key).await?),
+        None => None,
+    };
+
+    let chunk_size = producer.message_chunk_size;
+    let result = if chunk_size > 0 && record.payload.len() > chunk_size {
+        producer
+            .send_chunked(topic, &record.payload, &record.key, partition, chunk_size)
+            .await
+    } else {
+        producer
+            .send(topic, &record.payload, &record.key, partition, None)
+            .await
+    };
+
+    result.map(|_| ()).map_err(|(e, _)| e)
+}
+
+/// Flushing blocks the thread until the queues are empty or the timeout expires
+async fn flush_producers(
+    producers: [FutureProducer<ContextWithStats>; 4],
+    timeout: Duration,
+) -> Result<(), SinkError> {
+    tokio::task::spawn_blocking(move || {
+        producers
+            .iter()
+            .try_for_each(|producer| producer.flush(timeout))
+    })
+    .await
+    .map_err(|e| SinkError::Other(e.to_string()))??;
+
+    Ok(())
+}
+
+/// Waits for the send to the primary cluster, and for the send to the secondary cluster too in
+/// the `Required` mode. In the `BestEffort` mode the secondary send goes on in the background
+async fn mirror<P, S>(mode: MirrorMode, primary: P, secondary: S) -> Result<(), KafkaError>
+where
+    P: Future<Output = Result<(), KafkaError>>,
+    S: Future<Output = Result<(), KafkaError>> + Send + 'static,
+{
+    match mode {
+        MirrorMode::BestEffort => {
+            tokio::spawn(secondary);
+            primary.await
+        }
+        MirrorMode::Required => {
+            let (primary_result, secondary_result) = tokio::join!(primary, secondary);
+            primary_result.and(secondary_result)
+        }
+    }
+}
+
+/// Publishes every message type to its own topic, and mirrors it to the secondary cluster if there is one
+pub struct KafkaSink {
+    config: Arc<GeyserPluginKafkaConfig>,
+    stats: Arc<Stats>,
+    primary: KafkaProducers,
+    secondary: Option<KafkaProducers>,
+}
+
+impl KafkaSink {
+    pub fn new(
+        config: Arc<GeyserPluginKafkaConfig>,
+        ctx_stats: ContextWithStats,
+    ) -> KafkaResult<Self> {
+        let secondary = match config.secondary_cluster() {
+            Some(cluster) => Some(KafkaProducers::new(
+                &config,
+                cluster,
+                ctx_stats.secondary(),
+            )?),
+            None => None,
+        };
+
+        Ok(KafkaSink {
+            stats: ctx_stats.stats.clone(),
+            primary: KafkaProducers::new(&config, config.primary_cluster(), ctx_stats)?,
+            secondary,
+            config,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let message_type = record.message_type();
+        let topic = match message_type {
+            MessageType::UpdateAccount => &self.config.update_account_topic,
+            MessageType::UpdateSlot => &self.config.update_slot_topic,
+            MessageType::NotifyTransaction => &self.config.notify_transaction_topic,
+            MessageType::NotifyBlock => &self.config.notify_block_topic,
+        };
+
+        let chunk_size = self.primary.get(message_type).message_chunk_size;
+        if chunk_size > 0 && record.payload.len() > chunk_size {
+            self.stats.kafka_chunked_messages.inc();
+        }
+
+        let primary = send(self.primary.get(message_type), topic, &record);
+        let result = match &self.secondary {
+            Some(secondary) => {
+                let producer = secondary.get(message_type).clone();
+                let topic = topic.clone();
+                let record = record.clone();
+                let stats = self.stats.clone();
+                let secondary = async move {
+                    let result = send(&producer, &topic, &record).await;
+                    stats.kafka_secondary.delivered(&topic, result.is_ok());
+                    match &result {
+                        Ok(()) => stats.kafka_secondary.set_brokers_down(false),
+                        Err(e) => error!(
+                            "Failed to mirror {message_type} message to the secondary cluster, error: {e}"
+                        ),
+                    }
+                    result
+                };
+                mirror(self.config.kafka_mirror_mode, primary, secondary).await
+            }
+            None => primary.await,
+        };
+
+        if result.is_ok() {
+            self.stats.health.set_brokers_down(false);
+        }
+
+        Ok(result?)
+    }
+
+    async fn flush(&self, timeout: Duration) -> Result<(), SinkError> {
+        let secondary = self
+            .secondary
+            .as_ref()
+            .map(|secondary| flush_producers(secondary.future_producers(), timeout));
+        let primary = flush_producers(self.primary.future_producers(), timeout);
+
+        match secondary {
+            Some(secondary) => {
+                let (primary_result, secondary_result) = tokio::join!(primary, secondary);
+                if let Err(e) = secondary_result {
+                    if self.config.kafka_mirror_mode == MirrorMode::Required {
+                        primary_result?;
+                        return Err(e);
+                    }
+                    error!("Failed to flush the producers of the secondary cluster, error: {e}");
+                }
+                primary_result
+            }
+            None => primary.await,
+        }
+    }
+
+    fn health(&self) -> SinkHealth {
+        if self.stats.health.brokers_down() {
+            SinkHealth::Unhealthy("Kafka producers are disconnected from all brokers".to_string())
+        } else if self.secondary.is_some()
+            && self.config.kafka_mirror_mode == MirrorMode::Required
+            && self.stats.kafka_secondary.brokers_down()
+        {
+            SinkHealth::Unhealthy(
+                "Kafka producers are disconnected from all brokers of the secondary cluster"
+                    .to_string(),
+            )
+        } else {
+            SinkHealth::Healthy
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    async fn delayed(
+        delay_ms: u64,
+        result: Result<(), KafkaError>,
+        sent: Arc<AtomicBool>,
+    ) -> Result<(), KafkaError> {
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        sent.store(true, Ordering::Relaxed);
+        result
+    }
+
+    #[test]
+    fn best_effort_mirroring_returns_on_the_primary() {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let primary_sent = Arc::new(AtomicBool::new(false));
+            let secondary_sent = Arc::new(AtomicBool::new(false));
+            mirror(
+                MirrorMode::BestEffort,
+                delayed(0, Ok(()), primary_sent.clone()),
+                delayed(100, Err(KafkaError::Canceled), secondary_sent.clone()),
+            )
+            .await
+            .unwrap();
+            assert!(primary_sent.load(Ordering::Relaxed));
+            assert!(!secondary_sent.load(Ordering::Relaxed));
+
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(secondary_sent.load(Ordering::Relaxed));
+
+            let result = mirror(
+                MirrorMode::BestEffort,
+                delayed(0, Err(KafkaError::Canceled), primary_sent),
+                delayed(0, Ok(()), secondary_sent),
+            )
+            .await;
+            assert!(matches!(result, Err(KafkaError::Canceled)));
+        });
+    }
+
+    #[test]
+    fn required_mirroring_waits_for_both_clusters() {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            let primary_sent = Arc::new(AtomicBool::new(false));
+            let secondary_sent = Arc::new(AtomicBool::new(false));
+            mirror(
+                MirrorMode::Required,
+                delayed(0, Ok(()), primary_sent.clone()),
+                delayed(100, Ok(()), secondary_sent.clone()),
+            )
+            .await
+            .unwrap();
+            assert!(secondary_sent.load(Ordering::Relaxed));
+
+            let result = mirror(
+                MirrorMode::Required,
+                delayed(0, Ok(()), primary_sent),
+                delayed(0, Err(KafkaError::Canceled), secondary_sent),
+            )
+            .await;
+            assert!(matches!(result, Err(KafkaError::Canceled)));
+        });
+    }
+}
@@ -1,7 +1,20 @@
 pub mod build_info;
+pub mod datapoints;
+pub mod fanout_sink;
+pub mod file_sink;
 pub mod geyser_neon_config;
 pub mod geyser_neon_kafka;
+pub mod grpc_sink;
+pub mod health;
 pub mod kafka_producer;
 pub mod kafka_producer_stats;
+pub mod kafka_sink;
+pub mod null_sink;
+pub mod parquet_sink;
+pub mod postgres_sink;
 pub mod prometheus;
 pub mod receivers;
+pub mod redis_sink;
+pub mod s3_uploader;
+pub mod sink;
+pub mod websocket_sink;
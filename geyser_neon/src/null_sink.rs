@@ -0,0 +1,158 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use kafka_common::message_type::MessageType;
+use log::info;
+
+use crate::{
+    geyser_neon_config::{GeyserPluginKafkaConfig, DEFAULT_NULL_SINK_REPORT_INTERVAL_MS},
+    sink::{Sink, SinkError, SinkHealth, SinkRecord},
+};
+
+#[derive(Default)]
+struct Throughput {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Throughput {
+    fn add(&self, bytes: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> (u64, u64) {
+        (
+            self.messages.swap(0, Ordering::Relaxed),
+            self.bytes.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+/// Discards the messages after they have been converted and serialized, and logs the
+/// throughput per stream. Used to benchmark the plugin without any backend
+pub struct NullSink {
+    report_interval: Duration,
+    started: Instant,
+    // Microseconds since `started` of the last report
+    last_report_us: AtomicU64,
+    update_account: Throughput,
+    update_slot: Throughput,
+    notify_transaction: Throughput,
+    notify_block: Throughput,
+}
+
+impl NullSink {
+    pub fn new(config: &GeyserPluginKafkaConfig) -> Self {
+        let report_interval_ms = config
+            .null_sink_report_interval_ms
+            .parse::<u64>()
+            .unwrap_or(DEFAULT_NULL_SINK_REPORT_INTERVAL_MS);
+
+        NullSink {
+            report_interval: Duration::from_millis(report_interval_ms),
+            started: Instant::now(),
+            last_report_us: AtomicU64::new(0),
+            update_account: Throughput::default(),
+            update_slot: Throughput::default(),
+            notify_transaction: Throughput::default(),
+            notify_block: Throughput::default(),
+        }
+    }
+
+    /// Returns the seconds since the last report when a report is due. Every publish checks,
+    /// so only the publish which moves the report time forward reports
+    fn report_due(&self, now: Duration) -> Option<f64> {
+        let now_us = now.as_micros() as u64;
+        let last_report_us = self.last_report_us.load(Ordering::Relaxed);
+        if self.report_interval.is_zero()
+            || now_us < last_report_us + self.report_interval.as_micros() as u64
+            || self
+                .last_report_us
+                .compare_exchange(last_report_us, now_us, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return None;
+        }
+        Some((now_us - last_report_us) as f64 / 1_000_000.0)
+    }
+
+    fn report(&self) {
+        let elapsed = match self.report_due(self.started.elapsed()) {
+            Some(elapsed) => elapsed,
+            None => return,
+        };
+
+        let streams = [
+            ("update_account", &self.update_account),
+            ("update_slot", &self.update_slot),
+            ("notify_transaction", &self.notify_transaction),
+            ("notify_block", &self.notify_block),
+        ];
+
+        for (stream, throughput) in streams {
+            let (messages, bytes) = throughput.take();
+            info!(
+                "Null sink {stream}: {:.1} messages/s, {:.1} bytes/s",
+                messages as f64 / elapsed,
+                bytes as f64 / elapsed
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for NullSink {
+    fn name(&self) -> &'static str {
+        "null"
+    }
+
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let throughput = match record.message_type() {
+            MessageType::UpdateAccount => &self.update_account,
+            MessageType::UpdateSlot => &self.update_slot,
+            MessageType::NotifyTransaction => &self.notify_transaction,
+            MessageType::NotifyBlock => &self.notify_block,
+        };
+        throughput.add(record.payload.len());
+        self.report();
+        Ok(())
+    }
+
+    async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn health(&self) -> SinkHealth {
+        SinkHealth::Healthy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::geyser_neon_config::tests::test_config;
+
+    #[test]
+    fn reports_once_per_interval() {
+        let sink = NullSink::new(&test_config(
+            json!({ "null_sink_report_interval_ms": "1000" }),
+        ));
+
+        assert_eq!(sink.report_due(Duration::from_millis(999)), None);
+        assert_eq!(sink.report_due(Duration::from_millis(1500)), Some(1.5));
+        assert_eq!(sink.report_due(Duration::from_millis(2000)), None);
+        assert_eq!(sink.report_due(Duration::from_millis(2500)), Some(1.0));
+
+        let sink = NullSink::new(&test_config(json!({ "null_sink_report_interval_ms": "0" })));
+        assert_eq!(sink.report_due(Duration::from_secs(10)), None);
+    }
+}
@@ -0,0 +1,768 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use arrow_array::{
+    builder::{ListBuilder, StringBuilder, UInt64Builder},
+    ArrayRef, BinaryArray, BooleanArray, Int64Array, RecordBatch, StringArray,
+    TimestampMicrosecondArray, UInt64Array,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use kafka_common::kafka_structs::{
+    KafkaReplicaAccountInfoVersions, KafkaReplicaBlockInfoVersions,
+    KafkaReplicaTransactionInfoVersions, NotifyBlockMetaData, NotifyTransaction, UpdateAccount,
+};
+use log::{error, info};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use tokio::runtime::Runtime;
+
+use crate::{
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, DEFAULT_PARQUET_SINK_ROW_GROUP_SIZE,
+        DEFAULT_PARQUET_SINK_SEGMENT_SLOTS,
+    },
+    kafka_producer_stats::Stats,
+    s3_uploader::S3Uploader,
+    sink::{
+        BlockingWriter, LastError, Sink, SinkError, SinkHealth, SinkMessage, SinkRecord,
+        WriterThread,
+    },
+};
+
+// Row groups a table buffers while its files fail to be written, the writes are refused past them
+const MAX_BUFFERED_ROW_GROUPS: usize = 16;
+
+fn other_error(e: impl ToString) -> SinkError {
+    SinkError::Other(e.to_string())
+}
+
+/// Columns of a table, buffered until they are written as a row group
+trait Rows: Default {
+    fn len(&self) -> usize;
+
+    fn to_batch(&self) -> Result<RecordBatch, SinkError>;
+}
+
+#[derive(Default)]
+struct AccountRows {
+    slot: Vec<u64>,
+    pubkey: Vec<Vec<u8>>,
+    owner: Vec<Vec<u8>>,
+    lamports: Vec<u64>,
+    executable: Vec<bool>,
+    rent_epoch: Vec<u64>,
+    data: Vec<Vec<u8>>,
+    write_version: Vec<u64>,
+    txn_signature: Vec<Option<Vec<u8>>>,
+    is_startup: Vec<bool>,
+    retrieved_time: Vec<i64>,
+}
+
+impl AccountRows {
+    fn push(&mut self, update_account: &UpdateAccount) {
+        let (lamports, executable, rent_epoch, txn_signature) = match &update_account.account {
+            KafkaReplicaAccountInfoVersions::V0_0_1(account) => (
+                account.lamports,
+                account.executable,
+                account.rent_epoch,
+                None,
+            ),
+            KafkaReplicaAccountInfoVersions::V0_0_2(account) => (
+                account.lamports,
+                account.executable,
+                account.rent_epoch,
+                account
+                    .txn_signature
+                    .as_ref()
+                    .map(|signature| signature.as_ref().to_vec()),
+            ),
+        };
+
+        self.slot.push(update_account.slot);
+        self.pubkey.push(update_account.account.pubkey().to_vec());
+        self.owner.push(update_account.account.owner().to_vec());
+        self.lamports.push(lamports);
+        self.executable.push(executable);
+        self.rent_epoch.push(rent_epoch);
+        self.data.push(update_account.account.data().to_vec());
+        self.write_version
+            .push(update_account.account.write_version());
+        self.txn_signature.push(txn_signature);
+        self.is_startup.push(update_account.is_startup);
+        self.retrieved_time
+            .push(update_account.retrieved_time.timestamp_micros());
+    }
+}
+
+impl Rows for AccountRows {
+    fn len(&self) -> usize {
+        self.slot.len()
+    }
+
+    fn to_batch(&self) -> Result<RecordBatch, SinkError> {
+        RecordBatch::try_from_iter_with_nullable(vec![
+            (
+                "slot",
+                Arc::new(UInt64Array::from(self.slot.clone())) as ArrayRef,
+                false,
+            ),
+            (
+                "pubkey",
+                Arc::new(BinaryArray::from_iter_values(&self.pubkey)),
+                false,
+            ),
+            (
+                "owner",
+                Arc::new(BinaryArray::from_iter_values(&self.owner)),
+                false,
+            ),
+            (
+                "lamports",
+                Arc::new(UInt64Array::from(self.lamports.clone())),
+                false,
+            ),
+            (
+                "executable",
+                Arc::new(BooleanArray::from(self.executable.clone())),
+                false,
+            ),
+            (
+                "rent_epoch",
+                Arc::new(UInt64Array::from(self.rent_epoch.clone())),
+                false,
+            ),
+            (
+                "data",
+                Arc::new(BinaryArray::from_iter_values(&self.data)),
+                false,
+            ),
+            (
+                "write_version",
+                Arc::new(UInt64Array::from(self.write_version.clone())),
+                false,
+            ),
+            (
+                "txn_signature",
+                Arc::new(BinaryArray::from_iter(
+                    self.txn_signature.iter().map(Option::as_deref),
+                )),
+                true,
+            ),
+            (
+                "is_startup",
+                Arc::new(BooleanArray::from(self.is_startup.clone())),
+                false,
+            ),
+            (
+                "retrieved_time",
+                Arc::new(TimestampMicrosecondArray::from(self.retrieved_time.clone())),
+                false,
+            ),
+        ])
+        .map_err(other_error)
+    }
+}
+
+#[derive(Default)]
+struct TransactionRows {
+    slot: Vec<u64>,
+    signature: Vec<Vec<u8>>,
+    is_vote: Vec<bool>,
+    tx_index: Vec<Option<u64>>,
+    error: Vec<Option<String>>,
+    fee: Vec<u64>,
+    pre_balances: Vec<Vec<u64>>,
+    post_balances: Vec<Vec<u64>>,
+    log_messages: Vec<Option<Vec<String>>>,
+    retrieved_time: Vec<i64>,
+}
+
+impl TransactionRows {
+    fn push(&mut self, notify_transaction: &NotifyTransaction) {
+        let (signature, is_vote, meta, index) = match &notify_transaction.transaction_info {
+            KafkaReplicaTransactionInfoVersions::V0_0_1(info) => (
+                &info.signature,
+                info.is_vote,
+                &info.transaction_status_meta,
+                None,
+            ),
+            KafkaReplicaTransactionInfoVersions::V0_0_2(info) => (
+                &info.signature,
+                info.is_vote,
+                &info.transaction_status_meta,
+                Some(info.index as u64),
+            ),
+        };
+
+        self.slot.push(notify_transaction.slot);
+        self.signature.push(signature.as_ref().to_vec());
+        self.is_vote.push(is_vote);
+        self.tx_index.push(index);
+        self.error
+            .push(meta.status.as_ref().err().map(|e| e.to_string()));
+        self.fee.push(meta.fee);
+        self.pre_balances.push(meta.pre_balances.clone());
+        self.post_balances.push(meta.post_balances.clone());
+        self.log_messages.push(meta.log_messages.clone());
+        self.retrieved_time
+            .push(notify_transaction.retrieved_time.timestamp_micros());
+    }
+}
+
+fn u64_lists(lists: &[Vec<u64>]) -> ArrayRef {
+    let mut builder = ListBuilder::new(UInt64Builder::new());
+    for list in lists {
+        builder.values().append_slice(list);
+        builder.append(true);
+    }
+    Arc::new(builder.finish())
+}
+
+fn string_lists(lists: &[Option<Vec<String>>]) -> ArrayRef {
+    let mut builder = ListBuilder::new(StringBuilder::new());
+    for list in lists {
+        match list {
+            Some(list) => {
+                for value in list {
+                    builder.values().append_value(value);
+                }
+                builder.append(true);
+            }
+            None => builder.append(false),
+        }
+    }
+    Arc::new(builder.finish())
+}
+
+impl Rows for TransactionRows {
+    fn len(&self) -> usize {
+        self.slot.len()
+    }
+
+    fn to_batch(&self) -> Result<RecordBatch, SinkError> {
+        RecordBatch::try_from_iter_with_nullable(vec![
+            (
+                "slot",
+                Arc::new(UInt64Array::from(self.slot.clone())) as ArrayRef,
+                false,
+            ),
+            (
+                "signature",
+                Arc::new(BinaryArray::from_iter_values(&self.signature)),
+                false,
+            ),
+            (
+                "is_vote",
+                Arc::new(BooleanArray::from(self.is_vote.clone())),
+                false,
+            ),
+            (
+                "tx_index",
+                Arc::new(UInt64Array::from(self.tx_index.clone())),
+                true,
+            ),
+            (
+                "error",
+                Arc::new(StringArray::from_iter(
+                    self.error.iter().map(Option::as_deref),
+                )),
+                true,
+            ),
+            ("fee", Arc::new(UInt64Array::from(self.fee.clone())), false),
+            ("pre_balances", u64_lists(&self.pre_balances), false),
+            ("post_balances", u64_lists(&self.post_balances), false),
+            ("log_messages", string_lists(&self.log_messages), true),
+            (
+                "retrieved_time",
+                Arc::new(TimestampMicrosecondArray::from(self.retrieved_time.clone())),
+                false,
+            ),
+        ])
+        .map_err(other_error)
+    }
+}
+
+#[derive(Default)]
+struct BlockRows {
+    slot: Vec<u64>,
+    blockhash: Vec<String>,
+    block_time: Vec<Option<i64>>,
+    block_height: Vec<Option<u64>>,
+    rewards: Vec<String>,
+    retrieved_time: Vec<i64>,
+}
+
+impl BlockRows {
+    fn push(&mut self, notify_block: &NotifyBlockMetaData) -> Result<(), SinkError> {
+        let KafkaReplicaBlockInfoVersions::V0_0_1(block_info) = &notify_block.block_info;
+
+        self.rewards
+            .push(serde_json::to_string(&block_info.rewards).map_err(other_error)?);
+        self.slot.push(block_info.slot);
+        self.blockhash.push(block_info.blockhash.clone());
+        self.block_time.push(block_info.block_time);
+        self.block_height.push(block_info.block_height);
+        self.retrieved_time
+            .push(notify_block.retrieved_time.timestamp_micros());
+        Ok(())
+    }
+}
+
+impl Rows for BlockRows {
+    fn len(&self) -> usize {
+        self.slot.len()
+    }
+
+    fn to_batch(&self) -> Result<RecordBatch, SinkError> {
+        RecordBatch::try_from_iter_with_nullable(vec![
+            (
+                "slot",
+                Arc::new(UInt64Array::from(self.slot.clone())) as ArrayRef,
+                false,
+            ),
+            (
+                "blockhash",
+                Arc::new(StringArray::from_iter_values(&self.blockhash)),
+                false,
+            ),
+            (
+                "block_time",
+                Arc::new(Int64Array::from(self.block_time.clone())),
+                true,
+            ),
+            (
+                "block_height",
+                Arc::new(UInt64Array::from(self.block_height.clone())),
+                true,
+            ),
+            (
+                "rewards",
+                Arc::new(StringArray::from_iter_values(&self.rewards)),
+                false,
+            ),
+            (
+                "retrieved_time",
+                Arc::new(TimestampMicrosecondArray::from(self.retrieved_time.clone())),
+                false,
+            ),
+        ])
+        .map_err(other_error)
+    }
+}
+
+/// A Parquet file being written. It is written under a `.tmp` name and renamed
+/// once its footer has been written, so readers only ever see complete files
+struct ParquetFile {
+    writer: ArrowWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    start_slot: u64,
+    created_ms: i64,
+}
+
+/// One of the tables, written to its own subdirectory with a file per slot range
+struct Table<R: Rows> {
+    name: &'static str,
+    directory: PathBuf,
+    properties: WriterProperties,
+    segment_slots: u64,
+    row_group_size: usize,
+    max_buffered_rows: usize,
+    start_slot: Option<u64>,
+    first_slot: u64,
+    end_slot: u64,
+    rows: R,
+    file: Option<ParquetFile>,
+    uploader: Option<Arc<S3Uploader>>,
+    last_error: Arc<LastError>,
+}
+
+impl<R: Rows> Table<R> {
+    fn new(
+        directory: &Path,
+        name: &'static str,
+        properties: WriterProperties,
+        segment_slots: u64,
+        row_group_size: usize,
+        uploader: Option<Arc<S3Uploader>>,
+    ) -> io::Result<Self> {
+        let directory = directory.join(name);
+        fs::create_dir_all(&directory)?;
+
+        Ok(Table {
+            name,
+            directory,
+            properties,
+            segment_slots,
+            row_group_size,
+            max_buffered_rows: row_group_size * MAX_BUFFERED_ROW_GROUPS,
+            start_slot: None,
+            first_slot: u64::MAX,
+            end_slot: 0,
+            rows: R::default(),
+            file: None,
+            uploader,
+            last_error: Arc::new(LastError::default()),
+        })
+    }
+
+    /// Buffers a row, starting a new file when the slot is past the range of the current one.
+    /// Rows of earlier slots arriving late are written to the current file.
+    ///
+    /// The row is accepted once it is buffered: a failed write is reported by the health of
+    /// the table, and retried with the next rows until `max_buffered_rows` are waiting
+    fn append(
+        &mut self,
+        slot: u64,
+        push: impl FnOnce(&mut R) -> Result<(), SinkError>,
+    ) -> Result<(), SinkError> {
+        if self.rows.len() >= self.max_buffered_rows {
+            return Err(SinkError::Other(format!(
+                "{} rows of the {} table are waiting to be written",
+                self.rows.len(),
+                self.name
+            )));
+        }
+
+        if matches!(self.start_slot, Some(start_slot) if slot >= start_slot + self.segment_slots) {
+            let result = self.finish();
+            self.report(&result);
+        }
+        if self.start_slot.is_none() {
+            // Aligned, so the files of different runs cover the same slot ranges
+            self.start_slot = Some(slot - slot % self.segment_slots);
+        }
+
+        push(&mut self.rows)?;
+        self.first_slot = self.first_slot.min(slot);
+        self.end_slot = self.end_slot.max(slot);
+        if self.rows.len() >= self.row_group_size {
+            let result = self.write_rows();
+            self.report(&result);
+        }
+        Ok(())
+    }
+
+    fn report(&self, result: &Result<(), SinkError>) {
+        if let Err(e) = result {
+            error!("Failed to write the {} table, error: {e}", self.name);
+        }
+        self.last_error.set(
+            &result
+                .as_ref()
+                .map_err(|e| format!("{} table: {e}", self.name)),
+        );
+    }
+
+    /// The rows stay buffered until they have been written. If the writer fails, it may have
+    /// written a part of the rows already, so the file is left behind with its `.tmp` name and
+    /// the rows are written to a new file
+    fn write_rows(&mut self) -> Result<(), SinkError> {
+        if self.rows.len() == 0 {
+            return Ok(());
+        }
+
+        let batch = self.rows.to_batch()?;
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                let start_slot = self.start_slot.unwrap_or_default();
+                let created_ms = Utc::now().timestamp_millis();
+                let name = format!("{start_slot:012}-{created_ms}");
+                let path = self.directory.join(format!("{name}.parquet"));
+                let tmp_path = self.directory.join(format!("{name}.parquet.tmp"));
+                let writer = ArrowWriter::try_new(
+                    File::create(&tmp_path)?,
+                    batch.schema(),
+                    Some(self.properties.clone()),
+                )
+                .map_err(other_error)?;
+                info!("Writing Parquet file {}", path.display());
+
+                self.file.insert(ParquetFile {
+                    writer,
+                    tmp_path,
+                    path,
+                    start_slot,
+                    created_ms,
+                })
+            }
+        };
+
+        if let Err(e) = file.writer.write(&batch) {
+            if let Some(file) = self.file.take() {
+                error!(
+                    "Failed to write to {}, leaving it incomplete, error: {e}",
+                    file.tmp_path.display()
+                );
+            }
+            return Err(other_error(e));
+        }
+
+        self.rows = R::default();
+        Ok(())
+    }
+
+    /// Writes the buffered rows and the footer of the current file, and hands the file
+    /// over to the uploader if there is one. The object is named after the slot range of
+    /// the file, which starts earlier than the aligned start when a late row went into it
+    fn finish(&mut self) -> Result<(), SinkError> {
+        self.write_rows()?;
+        self.start_slot = None;
+        let first_slot = std::mem::replace(&mut self.first_slot, u64::MAX);
+        let end_slot = std::mem::take(&mut self.end_slot);
+
+        if let Some(file) = self.file.take() {
+            let metadata = file.writer.close().map_err(other_error)?;
+            fs::rename(&file.tmp_path, &file.path)?;
+            info!(
+                "Finished Parquet file {} with {} rows",
+                file.path.display(),
+                metadata.num_rows
+            );
+
+            if let Some(uploader) = &self.uploader {
+                let key = format!(
+                    "{}/{:012}-{end_slot:012}-{}.parquet",
+                    self.name,
+                    file.start_slot.min(first_slot),
+                    file.created_ms
+                );
+                uploader.submit(&file.path, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SinkError> {
+        let result = self.finish();
+        self.report(&result);
+        result
+    }
+}
+
+struct Tables {
+    accounts: Table<AccountRows>,
+    transactions: Table<TransactionRows>,
+    blocks: Table<BlockRows>,
+}
+
+impl BlockingWriter for Tables {
+    fn write(&mut self, record: &SinkRecord) -> Result<(), SinkError> {
+        let slot = record.message.slot();
+
+        match &record.message {
+            SinkMessage::UpdateAccount(update_account) => self.accounts.append(slot, |rows| {
+                rows.push(update_account);
+                Ok(())
+            }),
+            SinkMessage::NotifyTransaction(notify_transaction) => {
+                self.transactions.append(slot, |rows| {
+                    rows.push(notify_transaction);
+                    Ok(())
+                })
+            }
+            SinkMessage::NotifyBlockMetaData(notify_block) => {
+                self.blocks.append(slot, |rows| rows.push(notify_block))
+            }
+            SinkMessage::UpdateSlotStatus(_) => Ok(()),
+        }
+    }
+
+    // A Parquet file can only be read once its footer is written, so the open files are finished
+    fn flush(&mut self) -> Result<(), SinkError> {
+        self.accounts.flush()?;
+        self.transactions.flush()?;
+        self.blocks.flush()
+    }
+}
+
+/// Writes account updates, transactions and block metadata into Parquet files, a table per
+/// subdirectory and a file per slot range. Slot statuses are not written
+pub struct ParquetSink {
+    writer: WriterThread,
+    table_errors: [Arc<LastError>; 3],
+}
+
+impl ParquetSink {
+    pub fn new(
+        config: &GeyserPluginKafkaConfig,
+        stats: Arc<Stats>,
+        runtime: &Runtime,
+    ) -> Result<Self, SinkError> {
+        if config.parquet_sink_directory.is_empty() {
+            return Err(SinkError::Other(
+                "parquet_sink_directory is not set".to_string(),
+            ));
+        }
+
+        let segment_slots = config
+            .parquet_sink_segment_slots
+            .parse::<u64>()
+            .unwrap_or(DEFAULT_PARQUET_SINK_SEGMENT_SLOTS)
+            .max(1);
+        let row_group_size = config
+            .parquet_sink_row_group_size
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_PARQUET_SINK_ROW_GROUP_SIZE)
+            .max(1);
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(row_group_size)
+            .build();
+
+        let directory = PathBuf::from(&config.parquet_sink_directory);
+        let uploader = S3Uploader::from_config(config, &directory, stats, runtime)?;
+        let tables = Tables {
+            accounts: Table::new(
+                &directory,
+                "accounts",
+                properties.clone(),
+                segment_slots,
+                row_group_size,
+                uploader.clone(),
+            )?,
+            transactions: Table::new(
+                &directory,
+                "transactions",
+                properties.clone(),
+                segment_slots,
+                row_group_size,
+                uploader.clone(),
+            )?,
+            blocks: Table::new(
+                &directory,
+                "blocks",
+                properties,
+                segment_slots,
+                row_group_size,
+                uploader,
+            )?,
+        };
+
+        let table_errors = [
+            tables.accounts.last_error.clone(),
+            tables.transactions.last_error.clone(),
+            tables.blocks.last_error.clone(),
+        ];
+
+        Ok(ParquetSink {
+            writer: WriterThread::spawn("parquet", tables)?,
+            table_errors,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for ParquetSink {
+    fn name(&self) -> &'static str {
+        "parquet"
+    }
+
+    // Resolves once the rows are buffered, the failures to write them are reported by the health
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        self.writer.write(record).await
+    }
+
+    async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+        self.writer.flush().await
+    }
+
+    fn health(&self) -> SinkHealth {
+        self.table_errors
+            .iter()
+            .map(|last_error| last_error.health())
+            .find(|health| *health != SinkHealth::Healthy)
+            .unwrap_or(SinkHealth::Healthy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use super::*;
+    use crate::sink::tests::account_record;
+
+    fn append_account(table: &mut Table<AccountRows>, slot: u64) -> Result<(), SinkError> {
+        let record = account_record(&[1; 32], slot, slot, b"data");
+        table.append(slot, |rows| {
+            if let SinkMessage::UpdateAccount(update_account) = &record.message {
+                rows.push(update_account);
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn rows_are_kept_until_they_are_written() {
+        let directory = env::temp_dir().join(format!(
+            "geyser_neon_parquet_{}",
+            Utc::now().timestamp_nanos()
+        ));
+        let properties = WriterProperties::builder().build();
+        let mut table: Table<AccountRows> =
+            Table::new(&directory, "accounts", properties, 1000, 2, None).unwrap();
+
+        // The file cannot be created without its directory
+        fs::remove_dir_all(directory.join("accounts")).unwrap();
+        append_account(&mut table, 1).unwrap();
+        append_account(&mut table, 2).unwrap();
+        assert_eq!(table.rows.len(), 2);
+        assert!(matches!(
+            table.last_error.health(),
+            SinkHealth::Unhealthy(e) if e.contains("accounts table")
+        ));
+
+        fs::create_dir_all(directory.join("accounts")).unwrap();
+        append_account(&mut table, 3).unwrap();
+        assert_eq!(table.rows.len(), 0);
+        assert_eq!(table.last_error.health(), SinkHealth::Healthy);
+        table.flush().unwrap();
+
+        let path = fs::read_dir(directory.join("accounts"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        assert_eq!(path.extension().unwrap(), "parquet");
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn appends_are_refused_past_the_buffered_rows() {
+        let directory = env::temp_dir().join(format!(
+            "geyser_neon_parquet_{}",
+            Utc::now().timestamp_nanos()
+        ));
+        let properties = WriterProperties::builder().build();
+        let mut table: Table<AccountRows> =
+            Table::new(&directory, "accounts", properties, 1000, 2, None).unwrap();
+        table.max_buffered_rows = 3;
+
+        fs::remove_dir_all(directory.join("accounts")).unwrap();
+        for slot in 1..=3 {
+            append_account(&mut table, slot).unwrap();
+        }
+        assert!(append_account(&mut table, 4).is_err());
+        assert_eq!(table.rows.len(), 3);
+
+        fs::create_dir_all(directory.join("accounts")).unwrap();
+        table.flush().unwrap();
+        append_account(&mut table, 4).unwrap();
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
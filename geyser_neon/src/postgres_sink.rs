@@ -0,0 +1,527 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use futures_util::future::try_join_all;
+use kafka_common::kafka_structs::{
+    KafkaReplicaAccountInfoVersions, KafkaReplicaTransactionInfoVersions,
+};
+use log::{error, info};
+use serde_json::Value;
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
+use tokio_postgres::{types::ToSql, Client, NoTls, Statement};
+
+use crate::{
+    geyser_neon_config::{GeyserPluginKafkaConfig, DEFAULT_POSTGRES_BATCH_SIZE},
+    sink::{LastError, Sink, SinkError, SinkHealth, SinkMessage, SinkRecord},
+};
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+const CREATE_TABLES: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    pubkey BYTEA PRIMARY KEY,
+    owner BYTEA NOT NULL,
+    lamports BIGINT NOT NULL,
+    executable BOOL NOT NULL,
+    rent_epoch BIGINT NOT NULL,
+    data BYTEA NOT NULL,
+    write_version BIGINT NOT NULL,
+    slot BIGINT NOT NULL,
+    txn_signature BYTEA,
+    updated_on TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    signature BYTEA PRIMARY KEY,
+    slot BIGINT NOT NULL,
+    is_vote BOOL NOT NULL,
+    tx_index BIGINT,
+    transaction JSONB NOT NULL,
+    updated_on TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS slot_statuses (
+    slot BIGINT NOT NULL,
+    parent BIGINT,
+    status VARCHAR(16) NOT NULL,
+    updated_on TIMESTAMP NOT NULL,
+    PRIMARY KEY (slot, status)
+);
+";
+
+// Write versions restart with the validator, so the slot is compared first
+const UPSERT_ACCOUNT: &str = "
+INSERT INTO accounts AS current
+    (pubkey, owner, lamports, executable, rent_epoch, data, write_version, slot, txn_signature, updated_on)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+ON CONFLICT (pubkey) DO UPDATE SET
+    owner = excluded.owner,
+    lamports = excluded.lamports,
+    executable = excluded.executable,
+    rent_epoch = excluded.rent_epoch,
+    data = excluded.data,
+    write_version = excluded.write_version,
+    slot = excluded.slot,
+    txn_signature = excluded.txn_signature,
+    updated_on = excluded.updated_on
+WHERE (current.slot, current.write_version) < (excluded.slot, excluded.write_version)
+";
+
+const INSERT_TRANSACTION: &str = "
+INSERT INTO transactions (signature, slot, is_vote, tx_index, transaction, updated_on)
+VALUES ($1, $2, $3, $4, $5::TEXT::JSONB, $6)
+ON CONFLICT (signature) DO NOTHING
+";
+
+const INSERT_SLOT_STATUS: &str = "
+INSERT INTO slot_statuses (slot, parent, status, updated_on)
+VALUES ($1, $2, $3, $4)
+ON CONFLICT (slot, status) DO NOTHING
+";
+
+struct AccountRow {
+    pubkey: Vec<u8>,
+    owner: Vec<u8>,
+    lamports: i64,
+    executable: bool,
+    rent_epoch: i64,
+    data: Vec<u8>,
+    write_version: i64,
+    txn_signature: Option<Vec<u8>>,
+    updated_on: NaiveDateTime,
+}
+
+struct TransactionRow {
+    signature: Vec<u8>,
+    is_vote: bool,
+    index: Option<i64>,
+    transaction: String,
+    updated_on: NaiveDateTime,
+}
+
+struct SlotStatusRow {
+    parent: Option<i64>,
+    status: String,
+    updated_on: NaiveDateTime,
+}
+
+/// JSONB can't hold the NUL character, so it is removed from the strings of the transaction
+fn transaction_json(payload: &[u8]) -> String {
+    fn strip_nul(value: &mut Value) {
+        match value {
+            Value::String(string) => string.retain(|c| c != '\0'),
+            Value::Array(values) => values.iter_mut().for_each(strip_nul),
+            Value::Object(values) => values.values_mut().for_each(strip_nul),
+            _ => (),
+        }
+    }
+
+    let json = String::from_utf8_lossy(payload);
+    if !json.contains("\\u0000") {
+        return json.into_owned();
+    }
+
+    match serde_json::from_str::<Value>(&json) {
+        Ok(mut value) => {
+            strip_nul(&mut value);
+            value.to_string()
+        }
+        Err(_) => json.into_owned(),
+    }
+}
+
+enum Row {
+    Account(AccountRow),
+    Transaction(TransactionRow),
+    SlotStatus(SlotStatusRow),
+}
+
+impl Row {
+    fn new(record: &SinkRecord) -> Option<Self> {
+        match &record.message {
+            SinkMessage::UpdateAccount(update_account) => {
+                let (lamports, executable, rent_epoch, txn_signature) =
+                    match &update_account.account {
+                        KafkaReplicaAccountInfoVersions::V0_0_1(account) => (
+                            account.lamports,
+                            account.executable,
+                            account.rent_epoch,
+                            None,
+                        ),
+                        KafkaReplicaAccountInfoVersions::V0_0_2(account) => (
+                            account.lamports,
+                            account.executable,
+                            account.rent_epoch,
+                            account
+                                .txn_signature
+                                .as_ref()
+                                .map(|signature| signature.as_ref().to_vec()),
+                        ),
+                    };
+
+                Some(Row::Account(AccountRow {
+                    pubkey: update_account.account.pubkey().to_vec(),
+                    owner: update_account.account.owner().to_vec(),
+                    lamports: lamports as i64,
+                    executable,
+                    rent_epoch: rent_epoch as i64,
+                    data: update_account.account.data().to_vec(),
+                    write_version: update_account.account.write_version() as i64,
+                    txn_signature,
+                    updated_on: update_account.retrieved_time,
+                }))
+            }
+            SinkMessage::NotifyTransaction(notify_transaction) => {
+                let (signature, is_vote, index) = match &notify_transaction.transaction_info {
+                    KafkaReplicaTransactionInfoVersions::V0_0_1(info) => {
+                        (&info.signature, info.is_vote, None)
+                    }
+                    KafkaReplicaTransactionInfoVersions::V0_0_2(info) => {
+                        (&info.signature, info.is_vote, Some(info.index as i64))
+                    }
+                };
+
+                Some(Row::Transaction(TransactionRow {
+                    signature: signature.as_ref().to_vec(),
+                    is_vote,
+                    index,
+                    transaction: transaction_json(&record.payload),
+                    updated_on: notify_transaction.retrieved_time,
+                }))
+            }
+            SinkMessage::UpdateSlotStatus(update_slot_status) => {
+                Some(Row::SlotStatus(SlotStatusRow {
+                    parent: update_slot_status.parent.map(|parent| parent as i64),
+                    status: update_slot_status.status.to_string(),
+                    updated_on: update_slot_status.retrieved_time,
+                }))
+            }
+            SinkMessage::NotifyBlockMetaData(_) => None,
+        }
+    }
+}
+
+/// A row waiting to be written with the rows of its slot taken in the same batch
+struct PostgresEntry {
+    slot: u64,
+    row: Row,
+    ack: oneshot::Sender<Result<(), String>>,
+}
+
+struct Statements {
+    upsert_account: Statement,
+    insert_transaction: Statement,
+    insert_slot_status: Statement,
+}
+
+struct Connection {
+    client: Client,
+    statements: Statements,
+}
+
+impl Connection {
+    /// The tables only need to be created once, the statements are prepared for every
+    /// connection because they belong to the connection they were prepared on
+    async fn connect(
+        connection_str: &str,
+        create_tables: bool,
+    ) -> Result<Self, tokio_postgres::Error> {
+        let (client, connection) = tokio_postgres::connect(connection_str, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("Postgres connection failed, error: {e}");
+            }
+        });
+
+        if create_tables {
+            client.batch_execute(CREATE_TABLES).await?;
+        }
+        let statements = Statements {
+            upsert_account: client.prepare(UPSERT_ACCOUNT).await?,
+            insert_transaction: client.prepare(INSERT_TRANSACTION).await?,
+            insert_slot_status: client.prepare(INSERT_SLOT_STATUS).await?,
+        };
+        info!("Connected to Postgres");
+
+        Ok(Connection { client, statements })
+    }
+
+    /// Writes the rows of a slot taken in a batch in a single database transaction, the rows of
+    /// the slot in later batches get transactions of their own. Only the latest update of every
+    /// account is written, the statements are pipelined
+    async fn write_slot(&mut self, slot: u64, rows: &[&Row]) -> Result<(), tokio_postgres::Error> {
+        let mut accounts: HashMap<&[u8], &AccountRow> = HashMap::new();
+        for row in rows {
+            if let Row::Account(account) = row {
+                let latest = accounts.entry(&account.pubkey).or_insert(account);
+                if latest.write_version < account.write_version {
+                    *latest = account;
+                }
+            }
+        }
+
+        let slot = slot as i64;
+        let statements = &self.statements;
+        let transaction = self.client.transaction().await?;
+
+        let mut writes = Vec::with_capacity(rows.len());
+        for account in accounts.values() {
+            let params: Vec<&(dyn ToSql + Sync)> = vec![
+                &account.pubkey,
+                &account.owner,
+                &account.lamports,
+                &account.executable,
+                &account.rent_epoch,
+                &account.data,
+                &account.write_version,
+                &slot,
+                &account.txn_signature,
+                &account.updated_on,
+            ];
+            writes.push(transaction.execute_raw(&statements.upsert_account, params));
+        }
+
+        for row in rows {
+            match row {
+                Row::Account(_) => (),
+                Row::Transaction(tx) => {
+                    let params: Vec<&(dyn ToSql + Sync)> = vec![
+                        &tx.signature,
+                        &slot,
+                        &tx.is_vote,
+                        &tx.index,
+                        &tx.transaction,
+                        &tx.updated_on,
+                    ];
+                    writes.push(transaction.execute_raw(&statements.insert_transaction, params));
+                }
+                Row::SlotStatus(slot_status) => {
+                    let params: Vec<&(dyn ToSql + Sync)> = vec![
+                        &slot,
+                        &slot_status.parent,
+                        &slot_status.status,
+                        &slot_status.updated_on,
+                    ];
+                    writes.push(transaction.execute_raw(&statements.insert_slot_status, params));
+                }
+            }
+        }
+
+        try_join_all(writes).await?;
+        transaction.commit().await
+    }
+}
+
+/// Takes the entries which are waiting, up to `batch_size` of them, and writes them slot by slot.
+/// While Postgres can't be reached, the batches fail right away and a new connection is
+/// attempted with an exponential backoff
+async fn write_entries(
+    connection_str: String,
+    mut rx: mpsc::Receiver<PostgresEntry>,
+    batch_size: usize,
+    last_error: Arc<LastError>,
+) {
+    let mut connection: Option<Connection> = None;
+    let mut tables_created = false;
+    let mut reconnect_delay = RECONNECT_MIN_DELAY;
+    let mut reconnect_at = Instant::now();
+
+    while let Some(entry) = rx.recv().await {
+        let mut batch = vec![entry];
+        while batch.len() < batch_size {
+            match rx.try_recv() {
+                Ok(entry) => batch.push(entry),
+                Err(_) => break,
+            }
+        }
+
+        let mut slots: BTreeMap<u64, Vec<PostgresEntry>> = BTreeMap::new();
+        for entry in batch {
+            slots.entry(entry.slot).or_default().push(entry);
+        }
+
+        if connection.is_none() && Instant::now() >= reconnect_at {
+            match Connection::connect(&connection_str, !tables_created).await {
+                Ok(connected) => {
+                    tables_created = true;
+                    reconnect_delay = RECONNECT_MIN_DELAY;
+                    connection = Some(connected);
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to connect to Postgres, retrying in {reconnect_delay:?}, error: {e}"
+                    );
+                    reconnect_at = Instant::now() + reconnect_delay;
+                    reconnect_delay = (reconnect_delay * 2).min(RECONNECT_MAX_DELAY);
+                }
+            }
+        }
+
+        for (slot, entries) in slots {
+            let result = match connection.as_mut() {
+                Some(current) => {
+                    let rows: Vec<&Row> = entries.iter().map(|entry| &entry.row).collect();
+                    current
+                        .write_slot(slot, &rows)
+                        .await
+                        .map_err(|e| e.to_string())
+                }
+                None => Err("Not connected to Postgres".to_string()),
+            };
+
+            // A new connection is made for the next batch if this one has been lost
+            if matches!(&connection, Some(current) if current.client.is_closed()) {
+                connection = None;
+            }
+
+            last_error.set(&result);
+
+            for entry in entries {
+                let _ = entry.ack.send(result.clone());
+            }
+        }
+    }
+}
+
+/// Keeps the latest state of the accounts in Postgres, and stores the transactions and
+/// slot statuses in their own tables. Block metadata is not stored
+pub struct PostgresSink {
+    tx: mpsc::Sender<PostgresEntry>,
+    last_error: Arc<LastError>,
+}
+
+impl PostgresSink {
+    pub fn new(config: &GeyserPluginKafkaConfig, runtime: &Runtime) -> Result<Self, SinkError> {
+        let batch_size = config
+            .postgres_batch_size
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_POSTGRES_BATCH_SIZE)
+            .max(1);
+
+        let (tx, rx) = mpsc::channel(batch_size * 2);
+        let last_error = Arc::new(LastError::default());
+        runtime.spawn(write_entries(
+            config.postgres_connection_str.clone(),
+            rx,
+            batch_size,
+            last_error.clone(),
+        ));
+
+        Ok(PostgresSink { tx, last_error })
+    }
+}
+
+#[async_trait]
+impl Sink for PostgresSink {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let row = match Row::new(&record) {
+            Some(row) => row,
+            None => return Ok(()),
+        };
+
+        let (ack, ack_rx) = oneshot::channel();
+        self.tx
+            .send(PostgresEntry {
+                slot: record.message.slot(),
+                row,
+                ack,
+            })
+            .await
+            .map_err(|_| SinkError::Other("Postgres writer has stopped".to_string()))?;
+
+        ack_rx
+            .await
+            .map_err(|_| SinkError::Other("Postgres writer has stopped".to_string()))?
+            .map_err(SinkError::Other)
+    }
+
+    // Every publish waits for the transaction of its slot, so nothing is left to flush
+    async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn health(&self) -> SinkHealth {
+        self.last_error.health()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{geyser_neon_config::tests::test_config, sink::tests::account_record};
+
+    #[test]
+    fn nul_characters_are_removed_from_the_transaction() {
+        let payload = json!({
+            "log_messages": ["Program log: a\u{0}b", "Program log: \\u0000"],
+            "nested": { "memo": "\u{0}" },
+        });
+        let transaction: Value =
+            serde_json::from_str(&transaction_json(payload.to_string().as_bytes())).unwrap();
+
+        assert_eq!(
+            transaction,
+            json!({
+                "log_messages": ["Program log: ab", "Program log: \\u0000"],
+                "nested": { "memo": "" },
+            })
+        );
+
+        let payload = br#"{"memo":"\\u0000 is kept"}"#;
+        assert_eq!(transaction_json(payload), r#"{"memo":"\\u0000 is kept"}"#);
+    }
+
+    // Needs a Postgres server which accepts the default connection string,
+    // e.g. `docker run -p 5432:5432 -e POSTGRES_HOST_AUTH_METHOD=trust postgres`
+    #[test]
+    #[ignore]
+    fn older_account_updates_do_not_overwrite_newer_ones() {
+        let config = test_config(json!({ "postgres_batch_size": "1" }));
+        let runtime = Runtime::new().unwrap();
+        let sink = PostgresSink::new(&config, &runtime).unwrap();
+        let pubkey = Utc::now().timestamp_nanos().to_le_bytes();
+
+        runtime.block_on(async {
+            for (slot, write_version, data) in
+                [(10, 5, b"newer"), (9, 7, b"older"), (10, 4, b"older")]
+            {
+                sink.publish(account_record(&pubkey, slot, write_version, data))
+                    .await
+                    .unwrap();
+            }
+
+            let (client, connection) =
+                tokio_postgres::connect(&config.postgres_connection_str, NoTls)
+                    .await
+                    .unwrap();
+            tokio::spawn(connection);
+            let row = client
+                .query_one(
+                    "DELETE FROM accounts WHERE pubkey = $1 RETURNING data, slot, write_version",
+                    &[&pubkey.as_slice()],
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(row.get::<_, Vec<u8>>(0), b"newer");
+            assert_eq!(row.get::<_, i64>(1), 10);
+            assert_eq!(row.get::<_, i64>(2), 5);
+        });
+        assert_eq!(sink.health(), SinkHealth::Healthy);
+    }
+}
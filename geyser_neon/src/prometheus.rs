@@ -9,15 +9,36 @@ use std::{
 
 use hyper::{
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Request, Response, Server, StatusCode,
 };
-use prometheus_client::{encoding::text::encode, registry::Registry};
+use prometheus_client::{encoding::text::encode, metrics::info::Info, registry::Registry};
 use tokio::signal::unix::{signal, SignalKind};
 
-use crate::{geyser_neon_config::GeyserPluginKafkaConfig, kafka_producer_stats::Stats};
+use crate::{
+    build_info::{get_version_info, VersionInfo},
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, DEFAULT_INTERNAL_QUEUE_CAPACITY,
+        DEFAULT_READINESS_QUEUE_THRESHOLD_PERCENT,
+    },
+    health::readiness_problems,
+    kafka_producer_stats::{LatencyStats, QueueStats, RdKafkaStats, Stats},
+    sink::Sink,
+};
 
-pub async fn start_prometheus(stats: Arc<Stats>, config: Arc<GeyserPluginKafkaConfig>, port: u16) {
+pub async fn start_prometheus(
+    stats: Arc<Stats>,
+    sink: Arc<dyn Sink>,
+    config: Arc<GeyserPluginKafkaConfig>,
+    port: u16,
+) {
     let mut registry = <Registry>::default();
+    let version_info = Arc::new(get_version_info());
+
+    registry.register(
+        "geyser_neon_build",
+        "Version of the plugin and the toolchain it was built with",
+        Box::new(Info::new(version_info.labels())),
+    );
 
     registry.register(
         "kafka_bytes_sent",
@@ -25,12 +46,42 @@ pub async fn start_prometheus(stats: Arc<Stats>, config: Arc<GeyserPluginKafkaCo
         Box::new(stats.kafka_bytes_tx.clone()),
     );
 
+    registry.register(
+        "kafka_messages_chunked",
+        "How many messages have been split into chunks",
+        Box::new(stats.kafka_chunked_messages.clone()),
+    );
+
+    registry.register(
+        "kafka_account_data_keyframes",
+        "How many account updates have been sent in full for delta decoding",
+        Box::new(stats.kafka_account_data_keyframes.clone()),
+    );
+
+    registry.register(
+        "kafka_account_data_deltas",
+        "How many account updates have been sent as a diff against the previous update",
+        Box::new(stats.kafka_account_data_deltas.clone()),
+    );
+
     registry.register(
         "kafka_errors_serialize",
         "How many messages have not been serialized",
         Box::new(stats.kafka_error_serialize.clone()),
     );
 
+    registry.register(
+        "kafka_errors_compress",
+        "How many account updates have not been compressed",
+        Box::new(stats.kafka_error_compress.clone()),
+    );
+
+    registry.register(
+        "kafka_errors_delivery",
+        "How many messages have not been delivered, by message type and reason",
+        Box::new(stats.kafka_error_delivery.clone()),
+    );
+
     let registry_with_label = registry.sub_registry_with_label((
         Cow::Borrowed("topic"),
         Cow::from(config.update_account_topic.clone()),
@@ -119,11 +170,347 @@ pub async fn start_prometheus(stats: Arc<Stats>, config: Arc<GeyserPluginKafkaCo
         Box::new(stats.kafka_error_notify_block.clone()),
     );
 
+    if config.secondary_cluster().is_some() {
+        let registry_with_label = registry
+            .sub_registry_with_label((Cow::Borrowed("cluster"), Cow::Borrowed("secondary")));
+
+        registry_with_label.register(
+            "kafka_messages_sent",
+            "How many messages have been mirrored to the secondary cluster",
+            Box::new(stats.kafka_secondary.sent.clone()),
+        );
+
+        registry_with_label.register(
+            "kafka_messages_unsent",
+            "How many messages have not been mirrored to the secondary cluster",
+            Box::new(stats.kafka_secondary.unsent.clone()),
+        );
+    }
+
+    register_queue_stats(&mut registry, "update_account", &stats.update_account_queue);
+    register_queue_stats(&mut registry, "update_slot", &stats.update_slot_queue);
+    register_queue_stats(
+        &mut registry,
+        "notify_transaction",
+        &stats.notify_transaction_queue,
+    );
+    register_queue_stats(&mut registry, "notify_block", &stats.notify_block_queue);
+
+    register_latency_stats(
+        &mut registry,
+        "update_account",
+        &stats.update_account_latency,
+    );
+    register_latency_stats(&mut registry, "update_slot", &stats.update_slot_latency);
+    register_latency_stats(
+        &mut registry,
+        "notify_transaction",
+        &stats.notify_transaction_latency,
+    );
+    register_latency_stats(&mut registry, "notify_block", &stats.notify_block_latency);
+
+    registry.register(
+        "slot_to_publish_seconds",
+        "Time from the block time to the delivery of the NotifyBlock message",
+        Box::new(stats.notify_block_latency.slot_to_publish.clone()),
+    );
+
+    for (callback, histogram) in [
+        ("update_account", &stats.callbacks.update_account),
+        ("update_slot_status", &stats.callbacks.update_slot_status),
+        ("notify_transaction", &stats.callbacks.notify_transaction),
+        (
+            "notify_block_metadata",
+            &stats.callbacks.notify_block_metadata,
+        ),
+    ] {
+        registry
+            .sub_registry_with_label((Cow::Borrowed("callback"), Cow::Borrowed(callback)))
+            .register(
+                "geyser_callback_seconds",
+                "Time spent inside the geyser callback on the validator thread",
+                Box::new(histogram.clone()),
+            );
+    }
+
+    registry.register(
+        "account_updates_by_owner",
+        "How many UpdateAccount messages have been sent, by owner program",
+        Box::new(stats.owners.messages.clone()),
+    );
+
+    registry.register(
+        "account_update_bytes_by_owner",
+        "How many bytes of UpdateAccount messages have been sent, by owner program",
+        Box::new(stats.owners.bytes.clone()),
+    );
+
+    registry.register(
+        "sink_messages_published",
+        "How many messages each sink has published",
+        Box::new(stats.sinks.published.clone()),
+    );
+
+    registry.register(
+        "sink_messages_failed",
+        "How many messages each sink has failed to publish",
+        Box::new(stats.sinks.failed.clone()),
+    );
+
+    registry.register(
+        "sink_messages_dropped",
+        "How many messages have been dropped because the queue of the sink was full",
+        Box::new(stats.sinks.dropped.clone()),
+    );
+
+    registry.register(
+        "sink_queue_messages",
+        "How many messages are waiting in the queue of each sink",
+        Box::new(stats.sinks.queue_len.clone()),
+    );
+
+    registry.register(
+        "sink_in_flight_messages",
+        "How many messages each sink is publishing",
+        Box::new(stats.sinks.in_flight.clone()),
+    );
+
+    registry.register(
+        "slot_seen",
+        "Latest slot the validator has notified the plugin about",
+        Box::new(stats.slots.seen.clone()),
+    );
+
+    registry.register(
+        "slot_acknowledged",
+        "Latest slot whose status has been acknowledged by Kafka",
+        Box::new(stats.slots.acknowledged.clone()),
+    );
+
+    register_rdkafka_stats(&mut registry, &stats.rdkafka);
+
+    registry.register(
+        "grpc_subscribers",
+        "How many clients are subscribed to the gRPC server",
+        Box::new(stats.grpc.subscribers.clone()),
+    );
+
+    registry.register(
+        "grpc_subscribers_lagged",
+        "How many gRPC subscribers have been disconnected because their buffer was full",
+        Box::new(stats.grpc.subscribers_lagged.clone()),
+    );
+
+    registry.register(
+        "websocket_connections",
+        "How many clients are connected to the WebSocket server",
+        Box::new(stats.websocket.connections.clone()),
+    );
+
+    registry.register(
+        "websocket_subscriptions_lagged",
+        "How many WebSocket subscriptions have been dropped because the connection buffer was full",
+        Box::new(stats.websocket.subscriptions_lagged.clone()),
+    );
+
+    registry.register(
+        "s3_uploads_pending",
+        "How many finished segment files are waiting to be uploaded to S3",
+        Box::new(stats.s3_uploads.pending.clone()),
+    );
+
+    registry.register(
+        "s3_uploads_pending_bytes",
+        "Size of the segment files waiting to be uploaded to S3",
+        Box::new(stats.s3_uploads.pending_bytes.clone()),
+    );
+
+    registry.register(
+        "s3_uploads_completed",
+        "How many segment files have been uploaded to S3",
+        Box::new(stats.s3_uploads.completed.clone()),
+    );
+
+    registry.register(
+        "s3_upload_failed_attempts",
+        "How many attempts to upload a segment file to S3 have failed",
+        Box::new(stats.s3_uploads.failed_attempts.clone()),
+    );
+
+    registry.register(
+        "s3_uploads_failed",
+        "How many segment files are in the failed directories because their upload has failed for good",
+        Box::new(stats.s3_uploads.failed.clone()),
+    );
+
+    registry.register(
+        "s3_bytes_uploaded",
+        "How many bytes of segment files have been uploaded to S3",
+        Box::new(stats.s3_uploads.bytes.clone()),
+    );
+
+    let internal_queue_capacity = config
+        .internal_queue_capacity
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_INTERNAL_QUEUE_CAPACITY);
+    let readiness_queue_threshold_percent = config
+        .readiness_queue_threshold_percent
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_READINESS_QUEUE_THRESHOLD_PERCENT);
+    let max_queue_len = (internal_queue_capacity * readiness_queue_threshold_percent / 100) as u64;
+
     let metrics_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
-    start_metrics_server(metrics_addr, registry).await
+    start_metrics_server(
+        metrics_addr,
+        registry,
+        stats,
+        sink,
+        version_info,
+        max_queue_len,
+    )
+    .await
 }
 
-async fn start_metrics_server(metrics_addr: SocketAddr, registry: Registry) {
+fn register_queue_stats(registry: &mut Registry, stream: &'static str, queue: &QueueStats) {
+    let registry_with_label =
+        registry.sub_registry_with_label((Cow::Borrowed("stream"), Cow::Borrowed(stream)));
+
+    registry_with_label.register(
+        "internal_queue_messages",
+        "How many messages are waiting in the internal queue",
+        Box::new(queue.queue_len.clone()),
+    );
+
+    registry_with_label.register(
+        "internal_queue_messages_max",
+        "The largest number of messages seen waiting in the internal queue",
+        Box::new(queue.queue_len_max.clone()),
+    );
+
+    registry_with_label.register(
+        "send_tasks_in_flight",
+        "How many messages are being serialized and sent to Kafka",
+        Box::new(queue.in_flight.clone()),
+    );
+
+    registry_with_label.register(
+        "send_tasks_in_flight_max",
+        "The largest number of messages seen being serialized and sent to Kafka at once",
+        Box::new(queue.in_flight_max.clone()),
+    );
+}
+
+fn register_latency_stats(registry: &mut Registry, stream: &'static str, latency: &LatencyStats) {
+    let registry_with_label =
+        registry.sub_registry_with_label((Cow::Borrowed("stream"), Cow::Borrowed(stream)));
+
+    registry_with_label.register(
+        "callback_to_dequeue_seconds",
+        "Time from the geyser callback to the message being taken from the internal queue",
+        Box::new(latency.callback_to_dequeue.clone()),
+    );
+
+    registry_with_label.register(
+        "dequeue_to_serialized_seconds",
+        "Time from the message being taken from the internal queue to its serialization",
+        Box::new(latency.dequeue_to_serialized.clone()),
+    );
+
+    registry_with_label.register(
+        "serialized_to_ack_seconds",
+        "Time from the serialization of the message to its delivery acknowledgement",
+        Box::new(latency.serialized_to_ack.clone()),
+    );
+}
+
+fn register_rdkafka_stats(registry: &mut Registry, rdkafka: &RdKafkaStats) {
+    registry.register(
+        "rdkafka_producer_queue_messages",
+        "How many messages are waiting in the librdkafka producer queue",
+        Box::new(rdkafka.queue_messages.clone()),
+    );
+
+    registry.register(
+        "rdkafka_producer_queue_bytes",
+        "Total size of the messages waiting in the librdkafka producer queue",
+        Box::new(rdkafka.queue_bytes.clone()),
+    );
+
+    registry.register(
+        "rdkafka_broker_up",
+        "Whether the connection to the broker is up",
+        Box::new(rdkafka.broker_up.clone()),
+    );
+
+    registry.register(
+        "rdkafka_broker_rtt_avg_us",
+        "Average broker round-trip time in microseconds",
+        Box::new(rdkafka.broker_rtt_avg.clone()),
+    );
+
+    registry.register(
+        "rdkafka_broker_rtt_p99_us",
+        "99th percentile of the broker round-trip time in microseconds",
+        Box::new(rdkafka.broker_rtt_p99.clone()),
+    );
+
+    registry.register(
+        "rdkafka_broker_outbuf_requests",
+        "How many requests are waiting to be sent to the broker",
+        Box::new(rdkafka.broker_outbuf_requests.clone()),
+    );
+
+    registry.register(
+        "rdkafka_broker_waitresp_requests",
+        "How many requests are in flight to the broker, waiting for a response",
+        Box::new(rdkafka.broker_waitresp_requests.clone()),
+    );
+
+    registry.register(
+        "rdkafka_broker_tx_errors",
+        "How many transmission errors have happened with the broker",
+        Box::new(rdkafka.broker_tx_errors.clone()),
+    );
+
+    registry.register(
+        "rdkafka_broker_request_timeouts",
+        "How many requests to the broker have timed out",
+        Box::new(rdkafka.broker_request_timeouts.clone()),
+    );
+
+    registry.register(
+        "rdkafka_partition_queued_messages",
+        "How many messages are queued for the partition",
+        Box::new(rdkafka.partition_queued_messages.clone()),
+    );
+
+    registry.register(
+        "rdkafka_partition_inflight_messages",
+        "How many messages to the partition are in flight",
+        Box::new(rdkafka.partition_inflight_messages.clone()),
+    );
+
+    registry.register(
+        "rdkafka_partition_tx_messages",
+        "How many messages have been sent to the partition",
+        Box::new(rdkafka.partition_tx_messages.clone()),
+    );
+
+    registry.register(
+        "rdkafka_partition_tx_bytes",
+        "How many bytes have been sent to the partition",
+        Box::new(rdkafka.partition_tx_bytes.clone()),
+    );
+}
+
+async fn start_metrics_server(
+    metrics_addr: SocketAddr,
+    registry: Registry,
+    stats: Arc<Stats>,
+    sink: Arc<dyn Sink>,
+    version_info: Arc<VersionInfo>,
+    max_queue_len: u64,
+) {
     let mut shutdown_stream = signal(SignalKind::terminate()).unwrap();
 
     println!("Starting metrics server on {metrics_addr}");
@@ -132,8 +519,11 @@ async fn start_metrics_server(metrics_addr: SocketAddr, registry: Registry) {
     Server::bind(&metrics_addr)
         .serve(make_service_fn(move |_conn| {
             let registry = registry.clone();
+            let stats = stats.clone();
+            let sink = sink.clone();
+            let version_info = version_info.clone();
             async move {
-                let handler = make_handler(registry);
+                let handler = make_handler(registry, stats, sink, version_info, max_queue_len);
                 Ok::<_, io::Error>(service_fn(handler))
             }
         }))
@@ -146,11 +536,50 @@ async fn start_metrics_server(metrics_addr: SocketAddr, registry: Registry) {
 
 fn make_handler(
     registry: Arc<Registry>,
+    stats: Arc<Stats>,
+    sink: Arc<dyn Sink>,
+    version_info: Arc<VersionInfo>,
+    max_queue_len: u64,
 ) -> impl Fn(Request<Body>) -> Pin<Box<dyn Future<Output = io::Result<Response<Body>>> + Send>> {
-    // This closure accepts a request and responds with the OpenMetrics encoding of our metrics.
-    move |_req: Request<Body>| {
+    // This closure accepts a request and responds with the OpenMetrics encoding of our metrics,
+    // or with the health of the plugin for the probe paths.
+    // The root path keeps serving metrics for the existing scrape configurations.
+    move |req: Request<Body>| {
         let reg = registry.clone();
+        let stats = stats.clone();
+        let sink = sink.clone();
+        let version_info = version_info.clone();
         Box::pin(async move {
+            match req.uri().path() {
+                "/" | "/metrics" => (),
+                "/healthz" => return Ok(text_response(StatusCode::OK, "ok".to_string())),
+                "/readyz" => {
+                    let problems = readiness_problems(&stats, sink.as_ref(), max_queue_len);
+                    return Ok(if problems.is_empty() {
+                        text_response(StatusCode::OK, "ready".to_string())
+                    } else {
+                        text_response(StatusCode::SERVICE_UNAVAILABLE, problems.join("\n"))
+                    });
+                }
+                "/version" => {
+                    return serde_json::to_vec(&*version_info)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                        .map(|body| {
+                            Response::builder()
+                                .header(hyper::header::CONTENT_TYPE, "application/json")
+                                .body(Body::from(body))
+                                .unwrap()
+                        })
+                }
+                _ => {
+                    return Ok(text_response(
+                        StatusCode::NOT_FOUND,
+                        "not found".to_string(),
+                    ))
+                }
+            }
+
+            stats.refresh_queues();
             let mut buf = Vec::new();
             encode(&mut buf, &reg.clone())
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
@@ -167,3 +596,152 @@ fn make_handler(
         })
     }
 }
+
+fn text_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::{
+        geyser_neon_config::tests::test_config,
+        null_sink::NullSink,
+        sink::{SinkError, SinkHealth, SinkRecord},
+    };
+
+    struct UnhealthySink;
+
+    #[async_trait]
+    impl Sink for UnhealthySink {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        async fn publish(&self, _record: Arc<SinkRecord>) -> Result<(), SinkError> {
+            Ok(())
+        }
+
+        async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+            Ok(())
+        }
+
+        fn health(&self) -> SinkHealth {
+            SinkHealth::Unhealthy("brokers are down".to_string())
+        }
+    }
+
+    fn version_info() -> VersionInfo {
+        VersionInfo {
+            name: "geyser_neon".to_string(),
+            version: "1.0.0".to_string(),
+            git_commit: "abcdef".to_string(),
+            git_branch: "main".to_string(),
+            rustc_version: "1.65.0".to_string(),
+            opt_level: "3".to_string(),
+            build_timestamp: "2022-12-01T00:00:00+00:00".to_string(),
+        }
+    }
+
+    // Status, content type and body of the response to a GET of `path`
+    fn get(stats: &Arc<Stats>, sink: Arc<dyn Sink>, path: &str) -> (StatusCode, String, String) {
+        let handler = make_handler(
+            Arc::new(Registry::default()),
+            stats.clone(),
+            sink,
+            Arc::new(version_info()),
+            100,
+        );
+        let request = Request::get(path).body(Body::empty()).unwrap();
+
+        Runtime::new().unwrap().block_on(async {
+            let response = handler(request).await.unwrap();
+            let content_type = response.headers()[hyper::header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .to_string();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (
+                status,
+                content_type,
+                String::from_utf8(body.to_vec()).unwrap(),
+            )
+        })
+    }
+
+    fn null_sink() -> Arc<dyn Sink> {
+        Arc::new(NullSink::new(&test_config(json!({}))))
+    }
+
+    #[test]
+    fn healthz_answers_and_unknown_paths_are_not_found() {
+        let stats = Arc::new(Stats::default());
+
+        let (status, _, body) = get(&stats, null_sink(), "/healthz");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "ok");
+
+        let (status, _, body) = get(&stats, null_sink(), "/unknown");
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "not found");
+
+        let (status, content_type, _) = get(&stats, null_sink(), "/metrics");
+        assert_eq!(status, StatusCode::OK);
+        assert!(content_type.starts_with("application/openmetrics-text"));
+    }
+
+    #[test]
+    fn readyz_lists_the_readiness_problems() {
+        let stats = Arc::new(Stats::default());
+        stats.update_account_queue.in_flight.set(101);
+
+        let (status, _, body) = get(&stats, Arc::new(UnhealthySink), "/readyz");
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body.lines().collect::<Vec<_>>(),
+            vec![
+                "startup has not ended",
+                "test sink is unhealthy: brokers are down",
+                "update_account has 101 messages in flight, more than 100",
+            ]
+        );
+
+        stats.health.set_startup_finished();
+        stats.update_account_queue.in_flight.set(100);
+        let (status, _, body) = get(&stats, null_sink(), "/readyz");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "ready");
+    }
+
+    #[test]
+    fn version_serves_the_build_details() {
+        let stats = Arc::new(Stats::default());
+
+        let (status, content_type, body) = get(&stats, null_sink(), "/version");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/json");
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({
+                "name": "geyser_neon",
+                "version": "1.0.0",
+                "git_commit": "abcdef",
+                "git_branch": "main",
+                "rustc_version": "1.65.0",
+                "opt_level": "3",
+                "build_timestamp": "2022-12-01T00:00:00+00:00",
+            })
+        );
+    }
+}
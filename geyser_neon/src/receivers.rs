@@ -1,69 +1,137 @@
 use flume::Receiver;
+use kafka_common::delta::{DataDelta, DeltaEncoder};
 use kafka_common::kafka_structs::{
-    NotifyBlockMetaData, NotifyTransaction, UpdateAccount, UpdateSlotStatus,
+    KafkaReplicaBlockInfoVersions, NotifyBlockMetaData, NotifyTransaction, UpdateAccount,
+    UpdateSlotStatus,
 };
 use kafka_common::message_type::{GetMessageType, MessageType};
 use log::*;
 use serde::Serialize;
-use std::sync::atomic::Ordering::{self, Relaxed};
-use std::sync::{atomic::AtomicBool, Arc};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::runtime::Runtime;
+use tokio::sync::oneshot::{self, error::TryRecvError};
+use tokio::task::JoinSet;
 
-use crate::geyser_neon_config::GeyserPluginKafkaConfig;
-use crate::kafka_producer::KafkaProducer;
-use crate::kafka_producer_stats::{ContextWithStats, Stats};
+use crate::geyser_neon_config::{
+    GeyserPluginKafkaConfig, DEFAULT_ACCOUNT_DATA_COMPRESSION_LEVEL,
+    DEFAULT_ACCOUNT_DATA_COMPRESSION_THRESHOLD, DEFAULT_ACCOUNT_DATA_DELTA_MAX_ACCOUNTS,
+    DEFAULT_ACCOUNT_DATA_DELTA_MAX_BYTES, DEFAULT_ACCOUNT_DATA_DELTA_MIN_SIZE,
+    DEFAULT_OWNER_METRICS_MAX_PROGRAMS,
+};
+use crate::kafka_producer_stats::Stats;
+use crate::sink::{Sink, SinkMessage, SinkRecord};
 
-async fn serialize_and_send<T: Serialize + GetMessageType>(
-    config: Arc<GeyserPluginKafkaConfig>,
-    mut producer: KafkaProducer,
+/// Returns the size of the message if it has been delivered
+async fn serialize_and_send<T: Serialize + GetMessageType + Into<SinkMessage>>(
+    sink: Arc<dyn Sink>,
     message: T,
     hash: String,
+    partition_key: Option<String>,
     stats: Arc<Stats>,
-) {
+    dequeued_at: Instant,
+) -> Option<usize> {
     let message_type = message.get_type();
-    let (topic, counter_send_success, counter_send_failed) = match message_type {
+    let (counter_send_success, counter_send_failed, latency) = match message_type {
         MessageType::UpdateAccount => (
-            &config.update_account_topic,
             &stats.kafka_update_account,
             &stats.kafka_error_update_account,
+            &stats.update_account_latency,
         ),
         MessageType::UpdateSlot => (
-            &config.update_slot_topic,
             &stats.kafka_update_slot,
             &stats.kafka_error_update_slot,
+            &stats.update_slot_latency,
         ),
         MessageType::NotifyTransaction => (
-            &config.notify_transaction_topic,
             &stats.kafka_notify_transaction,
             &stats.kafka_error_notify_transaction,
+            &stats.notify_transaction_latency,
         ),
         MessageType::NotifyBlock => (
-            &config.notify_block_topic,
             &stats.kafka_notify_block,
             &stats.kafka_error_notify_block,
+            &stats.notify_block_latency,
         ),
     };
 
-    match serde_json::to_string(&message) {
-        Ok(message) => {
-            if let Err(e) = producer.send(topic, &message, &hash, None).await {
+    match serde_json::to_vec(&message) {
+        Ok(payload) => {
+            let serialized_at = Instant::now();
+            latency
+                .dequeue_to_serialized
+                .observe((serialized_at - dequeued_at).as_secs_f64());
+
+            let size = payload.len();
+            let record = Arc::new(SinkRecord {
+                key: hash,
+                partition_key,
+                payload,
+                message: message.into(),
+            });
+
+            if let Err(e) = sink.publish(record).await {
                 counter_send_failed.inc();
+                stats.delivery_failed(&message_type, &e);
                 error!(
-                    "Producer cannot send {message_type} message with size {}, error: {}",
-                    message.len(),
-                    e.0
+                    "Sink {} cannot send {message_type} message with size {size}, error: {e}",
+                    sink.name()
                 );
-                return;
+                return None;
             }
+            latency
+                .serialized_to_ack
+                .observe(serialized_at.elapsed().as_secs_f64());
             counter_send_success.inc();
             stats
                 .kafka_bytes_tx
                 .inner()
-                .fetch_add(message.len() as u64, Ordering::Relaxed);
+                .fetch_add(size as u64, Ordering::Relaxed);
+            Some(size)
         }
         Err(e) => {
             stats.kafka_error_serialize.inc();
             error!("Failed to serialize {message_type} message, error {e}");
+            None
+        }
+    }
+}
+
+const MIN_CHAINED_ACCOUNTS_PRUNE: usize = 1024;
+
+/// Chains the publishes of every delta encoded account, so a diff is never published
+/// before the update it is based on, even if that one is slower, e.g. because it is chunked
+#[derive(Default)]
+struct AccountPublishes {
+    // Completion of the last publish of every account
+    last: HashMap<Vec<u8>, oneshot::Receiver<()>>,
+    prune_at: usize,
+}
+
+impl AccountPublishes {
+    /// Returns the completion of the previous publish of the account, if it is still running,
+    /// and the sender which completes the next one when dropped
+    fn chain(&mut self, pubkey: &[u8]) -> (Option<oneshot::Receiver<()>>, oneshot::Sender<()>) {
+        if self.last.len() >= self.prune_at {
+            self.last
+                .retain(|_, done| matches!(done.try_recv(), Err(TryRecvError::Empty)));
+            self.prune_at = (self.last.len() * 2).max(MIN_CHAINED_ACCOUNTS_PRUNE);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        (self.last.insert(pubkey.to_vec(), rx), tx)
+    }
+}
+
+/// Waits for the next message of the internal queue, reaping the finished send tasks meanwhile.
+/// Returns `None` once the senders have been dropped and the queue is drained
+async fn next_message<T>(rx: &Receiver<T>, tasks: &mut JoinSet<()>) -> Option<T> {
+    loop {
+        tokio::select! {
+            message = rx.recv_async() => return message.ok(),
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => (),
         }
     }
 }
@@ -72,118 +140,323 @@ pub async fn update_account_loop(
     runtime: Arc<Runtime>,
     config: Arc<GeyserPluginKafkaConfig>,
     rx: Receiver<UpdateAccount>,
-    ctx_stats: ContextWithStats,
-    should_stop: Arc<AtomicBool>,
+    sink: Arc<dyn Sink>,
+    stats: Arc<Stats>,
 ) {
-    let producer_result = KafkaProducer::new(config.clone(), ctx_stats);
-    if let Ok(producer) = producer_result {
-        info!("Created KafkaProducer for update_account_loop!");
-        while !should_stop.load(Relaxed) {
-            if let Ok(update_account) = rx.recv_async().await {
-                let producer = producer.clone();
-                let stats = producer.get_stats();
-                let config = config.clone();
-                let hash = update_account.get_hash();
-
-                runtime.spawn(async move {
-                    serialize_and_send(config, producer, update_account, hash, stats).await;
-                });
+    let compression = config.account_data_compression;
+    let compression_threshold = config
+        .account_data_compression_threshold
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_ACCOUNT_DATA_COMPRESSION_THRESHOLD);
+    let compression_level = config
+        .account_data_compression_level
+        .parse::<i32>()
+        .unwrap_or(DEFAULT_ACCOUNT_DATA_COMPRESSION_LEVEL);
+
+    let keyframe_interval = config
+        .account_data_delta_keyframe_interval
+        .parse::<u32>()
+        .unwrap_or(0);
+    let delta_encoder = (keyframe_interval > 0).then(|| {
+        Arc::new(Mutex::new(DeltaEncoder::new(
+            keyframe_interval,
+            config
+                .account_data_delta_min_size
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_ACCOUNT_DATA_DELTA_MIN_SIZE),
+            config
+                .account_data_delta_max_accounts
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_ACCOUNT_DATA_DELTA_MAX_ACCOUNTS),
+            config
+                .account_data_delta_max_bytes
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_ACCOUNT_DATA_DELTA_MAX_BYTES),
+        )))
+    });
+    let mut account_publishes = AccountPublishes::default();
+
+    let owner_metrics_max_programs = config
+        .owner_metrics_max_programs
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_OWNER_METRICS_MAX_PROGRAMS);
+
+    if let Some(compression) = compression {
+        info!("Account data of {compression_threshold} bytes or more will be compressed with {compression}");
+    }
+    if delta_encoder.is_some() {
+        info!(
+            "Account data will be delta encoded with a keyframe every {keyframe_interval} updates"
+        );
+    }
+    stats.update_account_queue.watch(&rx);
+    let mut tasks = JoinSet::new();
+    while let Some(mut update_account) = next_message(&rx, &mut tasks).await {
+        let dequeued_at = Instant::now();
+        let sink = sink.clone();
+        let stats = stats.clone();
+        stats
+            .update_account_latency
+            .dequeued(update_account.retrieved_time);
+
+        // Diffs are computed in the order the updates arrive and the updates of an account
+        // are published one after another in the same order, to the same partition
+        let hash = update_account.get_hash();
+        let mut delta = None;
+        if let Some(delta_encoder) = &delta_encoder {
+            delta_encoder
+                .lock()
+                .expect("Delta encoder mutex is poisoned")
+                .encode(&mut update_account);
+            match update_account.data_delta {
+                Some(DataDelta::Keyframe) => {
+                    stats.kafka_account_data_keyframes.inc();
+                }
+                Some(DataDelta::Delta { .. }) => {
+                    stats.kafka_account_data_deltas.inc();
+                }
+                None => (),
             }
+            let pubkey = update_account.account.pubkey().to_vec();
+            let (previous, done) = account_publishes.chain(&pubkey);
+            delta = Some((delta_encoder.clone(), pubkey, previous, done));
         }
-    } else {
-        panic!(
-            "Failed to create Kafka producer for update_account_loop: {:?}",
-            producer_result.err()
+        let partition_key = delta
+            .as_ref()
+            .map(|(_, pubkey, _, _)| bs58::encode(pubkey).into_string());
+
+        let owner_labels = stats
+            .owners
+            .labels(update_account.account.owner(), owner_metrics_max_programs);
+
+        stats.update_account_queue.set_queue_len(rx.len());
+        stats.update_account_queue.task_started();
+
+        tasks.spawn_on(
+            async move {
+                if let Some(compression) = compression {
+                    if let Err(e) = update_account.compress_data(
+                        compression,
+                        compression_level,
+                        compression_threshold,
+                    ) {
+                        stats.kafka_error_compress.inc();
+                        error!(
+                            "Failed to compress UpdateAccount data with {compression}, error: {e}"
+                        );
+                    }
+                }
+                if let Some((_, _, Some(previous), _)) = delta.as_mut() {
+                    // Completes with an error if the previous task has been dropped, which is fine
+                    let _ = previous.await;
+                }
+                let delivered = serialize_and_send(
+                    sink,
+                    update_account,
+                    hash,
+                    partition_key,
+                    stats.clone(),
+                    dequeued_at,
+                )
+                .await;
+                match (delivered, delta) {
+                    (Some(size), _) => stats.owners.delivered(&owner_labels, size),
+                    // The next update of the account is sent as a keyframe, since the consumers
+                    // cannot apply a diff against data they have not received
+                    (None, Some((delta_encoder, pubkey, _, _))) => delta_encoder
+                        .lock()
+                        .expect("Delta encoder mutex is poisoned")
+                        .remove(&pubkey),
+                    (None, None) => (),
+                }
+                stats.update_account_queue.task_finished();
+            },
+            runtime.handle(),
         );
     }
+    // Unloading waits for the loops, so every queued message is sent before the sink is flushed
+    while tasks.join_next().await.is_some() {}
 }
 
 pub async fn update_slot_status_loop(
     runtime: Arc<Runtime>,
-    config: Arc<GeyserPluginKafkaConfig>,
     rx: Receiver<UpdateSlotStatus>,
-    ctx_stats: ContextWithStats,
-    should_stop: Arc<AtomicBool>,
+    sink: Arc<dyn Sink>,
+    stats: Arc<Stats>,
 ) {
-    let producer_result = KafkaProducer::new(config.clone(), ctx_stats);
-    if let Ok(producer) = producer_result {
-        info!("Created KafkaProducer for update_slot_status_loop!");
-        while !should_stop.load(Relaxed) {
-            if let Ok(update_slot_status) = rx.recv_async().await {
-                let producer = producer.clone();
-                let stats = producer.get_stats();
-                let config = config.clone();
-                let hash = update_slot_status.get_hash();
-
-                runtime.spawn(async move {
-                    serialize_and_send(config, producer, update_slot_status, hash, stats).await;
-                });
-            }
-        }
-    } else {
-        panic!(
-            "Failed to create Kafka producer for update_slot_status_loop: {:?}",
-            producer_result.err()
+    stats.update_slot_queue.watch(&rx);
+    let mut tasks = JoinSet::new();
+    while let Some(update_slot_status) = next_message(&rx, &mut tasks).await {
+        let dequeued_at = Instant::now();
+        let sink = sink.clone();
+        let stats = stats.clone();
+        stats
+            .update_slot_latency
+            .dequeued(update_slot_status.retrieved_time);
+        let hash = update_slot_status.get_hash();
+
+        stats
+            .slots
+            .slot_sent(update_slot_status.status, update_slot_status.slot);
+        stats.update_slot_queue.set_queue_len(rx.len());
+        stats.update_slot_queue.task_started();
+
+        tasks.spawn_on(
+            async move {
+                let status = update_slot_status.status;
+                let slot = update_slot_status.slot;
+                let delivered = serialize_and_send(
+                    sink,
+                    update_slot_status,
+                    hash,
+                    None,
+                    stats.clone(),
+                    dequeued_at,
+                )
+                .await;
+                match delivered {
+                    Some(_) => stats.slots.slot_acknowledged(status, slot),
+                    None => stats.slots.slot_failed(status, slot),
+                }
+                stats.update_slot_queue.task_finished();
+            },
+            runtime.handle(),
         );
     }
+    // Unloading waits for the loops, so every queued message is sent before the sink is flushed
+    while tasks.join_next().await.is_some() {}
 }
 
 pub async fn notify_transaction_loop(
     runtime: Arc<Runtime>,
-    config: Arc<GeyserPluginKafkaConfig>,
     rx: Receiver<NotifyTransaction>,
-    ctx_stats: ContextWithStats,
-    should_stop: Arc<AtomicBool>,
+    sink: Arc<dyn Sink>,
+    stats: Arc<Stats>,
 ) {
-    let producer_result = KafkaProducer::new(config.clone(), ctx_stats);
-    if let Ok(producer) = producer_result {
-        info!("Created KafkaProducer for notify_transaction_loop!");
-        while !should_stop.load(Relaxed) {
-            if let Ok(notify_transaction) = rx.recv_async().await {
-                let producer = producer.clone();
-                let stats = producer.get_stats();
-                let config = config.clone();
-                let hash = notify_transaction.get_hash();
-
-                runtime.spawn(async move {
-                    serialize_and_send(config, producer, notify_transaction, hash, stats).await;
-                });
-            }
-        }
-    } else {
-        panic!(
-            "Failed to create Kafka producer for notify_transaction_loop: {:?}",
-            producer_result.err()
+    stats.notify_transaction_queue.watch(&rx);
+    let mut tasks = JoinSet::new();
+    while let Some(notify_transaction) = next_message(&rx, &mut tasks).await {
+        let dequeued_at = Instant::now();
+        let sink = sink.clone();
+        let stats = stats.clone();
+        stats
+            .notify_transaction_latency
+            .dequeued(notify_transaction.retrieved_time);
+        let hash = notify_transaction.get_hash();
+
+        stats.notify_transaction_queue.set_queue_len(rx.len());
+        stats.notify_transaction_queue.task_started();
+
+        tasks.spawn_on(
+            async move {
+                serialize_and_send(
+                    sink,
+                    notify_transaction,
+                    hash,
+                    None,
+                    stats.clone(),
+                    dequeued_at,
+                )
+                .await;
+                stats.notify_transaction_queue.task_finished();
+            },
+            runtime.handle(),
         );
     }
+    // Unloading waits for the loops, so every queued message is sent before the sink is flushed
+    while tasks.join_next().await.is_some() {}
 }
 
 pub async fn notify_block_loop(
     runtime: Arc<Runtime>,
-    config: Arc<GeyserPluginKafkaConfig>,
     rx: Receiver<NotifyBlockMetaData>,
-    ctx_stats: ContextWithStats,
-    should_stop: Arc<AtomicBool>,
+    sink: Arc<dyn Sink>,
+    stats: Arc<Stats>,
 ) {
-    let producer_result = KafkaProducer::new(config.clone(), ctx_stats);
-    if let Ok(producer) = producer_result {
-        info!("Created KafkaProducer for notify_block_loop!");
-        while !should_stop.load(Relaxed) {
-            if let Ok(notify_block) = rx.recv_async().await {
-                let producer = producer.clone();
-                let stats = producer.get_stats();
-                let config = config.clone();
-                let hash = notify_block.get_hash().to_string();
-
-                runtime.spawn(async move {
-                    serialize_and_send(config, producer, notify_block, hash, stats).await;
-                });
+    stats.notify_block_queue.watch(&rx);
+    let mut tasks = JoinSet::new();
+    while let Some(notify_block) = next_message(&rx, &mut tasks).await {
+        let dequeued_at = Instant::now();
+        let sink = sink.clone();
+        let stats = stats.clone();
+        stats
+            .notify_block_latency
+            .dequeued(notify_block.retrieved_time);
+        let hash = notify_block.get_hash().to_string();
+
+        stats.notify_block_queue.set_queue_len(rx.len());
+        stats.notify_block_queue.task_started();
+
+        tasks.spawn_on(
+            async move {
+                let block_time = match &notify_block.block_info {
+                    KafkaReplicaBlockInfoVersions::V0_0_1(block_info) => block_info.block_time,
+                };
+                let delivered =
+                    serialize_and_send(sink, notify_block, hash, None, stats.clone(), dequeued_at)
+                        .await;
+                if let (Some(_), Some(block_time)) = (delivered, block_time) {
+                    stats.notify_block_latency.published(block_time);
+                }
+                stats.notify_block_queue.task_finished();
+            },
+            runtime.handle(),
+        );
+    }
+    // Unloading waits for the loops, so every queued message is sent before the sink is flushed
+    while tasks.join_next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_publishes_of_an_account() {
+        let mut account_publishes = AccountPublishes::default();
+
+        let (previous, first) = account_publishes.chain(&[1]);
+        assert!(previous.is_none());
+        let (other, _other_done) = account_publishes.chain(&[2]);
+        assert!(other.is_none());
+
+        let (previous, _second) = account_publishes.chain(&[1]);
+        let mut previous = previous.expect("First publish is still running");
+        assert!(matches!(previous.try_recv(), Err(TryRecvError::Empty)));
+        drop(first);
+        assert!(matches!(previous.try_recv(), Err(TryRecvError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn drains_queue_after_senders_are_dropped() {
+        let (tx, rx) = flume::bounded(4);
+        let mut tasks = JoinSet::new();
+        tasks.spawn(async {});
+        for i in 0..3 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+
+        let mut received = Vec::new();
+        while let Some(message) = next_message(&rx, &mut tasks).await {
+            received.push(message);
+        }
+        assert_eq!(received, [0, 1, 2]);
+    }
+
+    #[test]
+    fn prunes_finished_publishes() {
+        let mut account_publishes = AccountPublishes::default();
+        let mut running = Vec::new();
+
+        for i in 0..MIN_CHAINED_ACCOUNTS_PRUNE as u32 {
+            let (_, done) = account_publishes.chain(&i.to_le_bytes());
+            if i % 2 == 0 {
+                running.push(done);
             }
         }
-    } else {
-        panic!(
-            "Failed to create Kafka producer for notify_transaction_loop: {:?}",
-            producer_result.err()
-        );
+        account_publishes.chain(b"next");
+
+        assert_eq!(account_publishes.last.len(), running.len() + 1);
     }
 }
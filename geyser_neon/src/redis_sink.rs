@@ -0,0 +1,239 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use kafka_common::message_type::MessageType;
+use log::{error, info};
+use redis::{aio::ConnectionManager, Client};
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, DEFAULT_REDIS_PIPELINE_SIZE, DEFAULT_REDIS_STREAM_MAXLEN,
+    },
+    sink::{LastError, Sink, SinkError, SinkHealth, SinkRecord},
+};
+
+/// A message waiting to be added to its stream
+struct RedisEntry {
+    stream: String,
+    key: String,
+    message_type: MessageType,
+    payload: Vec<u8>,
+    ack: oneshot::Sender<Result<(), String>>,
+}
+
+/// Adds the entries with one pipeline per batch. The batch is made of the entries which are
+/// already waiting when the previous pipeline completes, up to `pipeline_size` of them
+async fn write_entries(
+    client: Client,
+    mut rx: mpsc::Receiver<RedisEntry>,
+    stream_maxlen: usize,
+    pipeline_size: usize,
+    last_error: Arc<LastError>,
+) {
+    let mut connection: Option<ConnectionManager> = None;
+
+    while let Some(entry) = rx.recv().await {
+        let mut batch = vec![entry];
+        while batch.len() < pipeline_size {
+            match rx.try_recv() {
+                Ok(entry) => batch.push(entry),
+                Err(_) => break,
+            }
+        }
+
+        let mut pipe = redis::pipe();
+        for entry in &batch {
+            let command = pipe.cmd("XADD").arg(&entry.stream);
+            if stream_maxlen > 0 {
+                command.arg("MAXLEN").arg("~").arg(stream_maxlen);
+            }
+            command
+                .arg("*")
+                .arg("key")
+                .arg(&entry.key)
+                .arg("type")
+                .arg(entry.message_type.to_string())
+                .arg("payload")
+                .arg(&entry.payload)
+                .ignore();
+        }
+
+        // The connection manager reconnects by itself once it has been created
+        if connection.is_none() {
+            match ConnectionManager::new(client.clone()).await {
+                Ok(manager) => {
+                    info!("Connected to Redis");
+                    connection = Some(manager);
+                }
+                Err(e) => error!("Failed to connect to Redis, error: {e}"),
+            }
+        }
+
+        let result = match connection.as_mut() {
+            Some(connection) => pipe
+                .query_async::<_, ()>(connection)
+                .await
+                .map_err(|e| e.to_string()),
+            None => Err("Not connected to Redis".to_string()),
+        };
+
+        last_error.set(&result);
+
+        for entry in batch {
+            let _ = entry.ack.send(result.clone());
+        }
+    }
+}
+
+/// Adds every message to the Redis stream named after the topic of its message type
+pub struct RedisSink {
+    update_account_stream: String,
+    update_slot_stream: String,
+    notify_transaction_stream: String,
+    notify_block_stream: String,
+    tx: mpsc::Sender<RedisEntry>,
+    last_error: Arc<LastError>,
+}
+
+impl RedisSink {
+    pub fn new(config: &GeyserPluginKafkaConfig, runtime: &Runtime) -> Result<Self, SinkError> {
+        let client = Client::open(config.redis_url.as_str())
+            .map_err(|e| SinkError::Other(format!("Wrong Redis URL, error: {e}")))?;
+
+        let stream_maxlen = config
+            .redis_stream_maxlen
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_REDIS_STREAM_MAXLEN);
+
+        let pipeline_size = config
+            .redis_pipeline_size
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_REDIS_PIPELINE_SIZE)
+            .max(1);
+
+        let (tx, rx) = mpsc::channel(pipeline_size * 2);
+        let last_error = Arc::new(LastError::default());
+        runtime.spawn(write_entries(
+            client,
+            rx,
+            stream_maxlen,
+            pipeline_size,
+            last_error.clone(),
+        ));
+
+        Ok(RedisSink {
+            update_account_stream: config.update_account_topic.clone(),
+            update_slot_stream: config.update_slot_topic.clone(),
+            notify_transaction_stream: config.notify_transaction_topic.clone(),
+            notify_block_stream: config.notify_block_topic.clone(),
+            tx,
+            last_error,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for RedisSink {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let message_type = record.message_type();
+        let stream = match message_type {
+            MessageType::UpdateAccount => &self.update_account_stream,
+            MessageType::UpdateSlot => &self.update_slot_stream,
+            MessageType::NotifyTransaction => &self.notify_transaction_stream,
+            MessageType::NotifyBlock => &self.notify_block_stream,
+        };
+
+        let (ack, ack_rx) = oneshot::channel();
+        self.tx
+            .send(RedisEntry {
+                stream: stream.clone(),
+                key: record.key.clone(),
+                message_type,
+                payload: record.payload.clone(),
+                ack,
+            })
+            .await
+            .map_err(|_| SinkError::Other("Redis writer has stopped".to_string()))?;
+
+        ack_rx
+            .await
+            .map_err(|_| SinkError::Other("Redis writer has stopped".to_string()))?
+            .map_err(SinkError::Other)
+    }
+
+    // Every publish waits for its pipeline, so nothing is left to flush
+    async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn health(&self) -> SinkHealth {
+        self.last_error.health()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::Utc;
+    use futures_util::future::join_all;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{geyser_neon_config::tests::test_config, sink::tests::slot_record};
+
+    // Needs a Redis server on 127.0.0.1:6379, e.g. `docker run -p 6379:6379 redis`
+    #[test]
+    #[ignore]
+    fn adds_records_to_the_stream() {
+        let stream = format!("geyser_neon_test_{}", Utc::now().timestamp_millis());
+        let config = test_config(json!({
+            "update_slot_topic": stream,
+            "redis_pipeline_size": "2",
+        }));
+        let runtime = Runtime::new().unwrap();
+        let sink = RedisSink::new(&config, &runtime).unwrap();
+
+        let records: Vec<_> = (0..5).map(slot_record).collect();
+        runtime.block_on(async {
+            for result in join_all(records.iter().map(|record| sink.publish(record.clone()))).await
+            {
+                result.unwrap();
+            }
+        });
+        assert_eq!(sink.health(), SinkHealth::Healthy);
+
+        let mut connection = Client::open(config.redis_url.as_str())
+            .unwrap()
+            .get_connection()
+            .unwrap();
+        let entries: Vec<(String, HashMap<String, Vec<u8>>)> = redis::cmd("XRANGE")
+            .arg(&stream)
+            .arg("-")
+            .arg("+")
+            .query(&mut connection)
+            .unwrap();
+        redis::cmd("DEL")
+            .arg(&stream)
+            .query::<()>(&mut connection)
+            .unwrap();
+
+        assert_eq!(entries.len(), records.len());
+        for (_, fields) in entries {
+            let record = records
+                .iter()
+                .find(|record| fields["key"] == record.key.as_bytes())
+                .expect("Unknown key");
+            assert_eq!(fields["type"], b"UpdateSlot");
+            assert_eq!(fields["payload"], record.payload);
+        }
+    }
+}
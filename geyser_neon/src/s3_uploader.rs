@@ -0,0 +1,659 @@
+use std::{
+    error::Error,
+    fmt::{self, Debug, Display},
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
+
+use aws_sdk_s3::{
+    model::{CompletedMultipartUpload, CompletedPart},
+    types::{ByteStream, DisplayErrorContext},
+    Client, Credentials, Region,
+};
+use aws_smithy_http::result::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
+use log::{error, info, warn};
+use prometheus_client::metrics::{counter::Counter, gauge::Gauge};
+use tokio::{io::AsyncReadExt, runtime::Runtime, sync::mpsc};
+
+use crate::{
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, DEFAULT_S3_MAX_UPLOAD_ATTEMPTS, DEFAULT_S3_MULTIPART_PART_SIZE,
+    },
+    kafka_producer_stats::Stats,
+};
+
+// S3 rejects multipart uploads with smaller parts, except for the last one
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+// errno of a rename across file systems on Linux
+const EXDEV: i32 = 18;
+// Suffix of a file which is being copied to the staging directory
+const PARTIAL_SUFFIX: &str = ".partial";
+// Error codes of S3 which no retry is going to fix
+const PERMANENT_ERROR_CODES: &[&str] = &[
+    "AccessDenied",
+    "AccountProblem",
+    "AllAccessDisabled",
+    "EntityTooLarge",
+    "InvalidAccessKeyId",
+    "InvalidArgument",
+    "InvalidBucketName",
+    "InvalidObjectState",
+    "InvalidRequest",
+    "KeyTooLongError",
+    "NoSuchBucket",
+    "SignatureDoesNotMatch",
+];
+
+struct UploadSettings {
+    bucket: String,
+    key_prefix: String,
+    region: String,
+    endpoint: String,
+    access_key_id: String,
+    secret_access_key: String,
+    part_size: usize,
+    max_attempts: u32,
+}
+
+/// Uploads of the finished segment files to S3
+#[derive(Default)]
+pub struct UploadStats {
+    pub pending: Gauge<u64, AtomicU64>,
+    pub pending_bytes: Gauge<u64, AtomicU64>,
+    pub completed: Counter<u64, AtomicU64>,
+    pub failed_attempts: Counter<u64, AtomicU64>,
+    pub failed: Gauge<u64, AtomicU64>,
+    pub bytes: Counter<u64, AtomicU64>,
+}
+
+impl UploadStats {
+    pub fn queued(&self, size: u64) {
+        self.pending.inc();
+        self.pending_bytes.inc_by(size);
+    }
+
+    pub fn uploaded(&self, size: u64) {
+        self.pending.dec();
+        self.pending_bytes.dec_by(size);
+        self.completed.inc();
+        self.bytes.inc_by(size);
+    }
+
+    pub fn abandoned(&self, size: u64) {
+        self.pending.dec();
+        self.pending_bytes.dec_by(size);
+    }
+
+    pub fn failed(&self, size: u64) {
+        self.abandoned(size);
+        self.failed.inc();
+    }
+}
+
+/// A file waiting in the staging directory, `key` is its path relative to the staging directory
+struct PendingUpload {
+    key: String,
+    size: u64,
+}
+
+enum UploadError {
+    Retryable(String),
+    Permanent(String),
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::Retryable(e) | UploadError::Permanent(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        UploadError::Retryable(e.to_string())
+    }
+}
+
+/// A request which could not be built or which S3 has rejected with one of
+/// `PERMANENT_ERROR_CODES` fails the same way when it is retried
+fn sdk_error<E, R>(e: SdkError<E, R>) -> UploadError
+where
+    E: ProvideErrorKind + Error + 'static,
+    R: Debug,
+{
+    let permanent = match &e {
+        SdkError::ConstructionFailure(_) => true,
+        SdkError::ServiceError(context) => matches!(
+            context.err().code(),
+            Some(code) if PERMANENT_ERROR_CODES.contains(&code)
+        ),
+        _ => false,
+    };
+    let message = DisplayErrorContext(e).to_string();
+    if permanent {
+        UploadError::Permanent(message)
+    } else {
+        UploadError::Retryable(message)
+    }
+}
+
+async fn create_client(settings: &UploadSettings) -> Client {
+    let mut loader = aws_config::from_env();
+    if !settings.region.is_empty() {
+        loader = loader.region(Region::new(settings.region.clone()));
+    }
+    if !settings.access_key_id.is_empty() {
+        loader = loader.credentials_provider(Credentials::new(
+            &settings.access_key_id,
+            &settings.secret_access_key,
+            None,
+            None,
+            "geyser_neon",
+        ));
+    }
+    let sdk_config = loader.load().await;
+
+    let mut builder = aws_sdk_s3::config::Builder::from(&sdk_config);
+    if !settings.endpoint.is_empty() {
+        // S3-compatible services are usually not set up for virtual-hosted-style buckets
+        builder = builder
+            .endpoint_url(&settings.endpoint)
+            .force_path_style(true);
+    }
+    Client::from_conf(builder.build())
+}
+
+async fn upload_parts(
+    client: &Client,
+    settings: &UploadSettings,
+    path: &Path,
+    object_key: &str,
+    upload_id: &str,
+) -> Result<Vec<CompletedPart>, UploadError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut parts = Vec::new();
+
+    for part_number in 1.. {
+        let mut buffer = Vec::with_capacity(settings.part_size);
+        (&mut file)
+            .take(settings.part_size as u64)
+            .read_to_end(&mut buffer)
+            .await?;
+        if buffer.is_empty() {
+            break;
+        }
+
+        let part = client
+            .upload_part()
+            .bucket(&settings.bucket)
+            .key(object_key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(buffer))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+
+        parts.push(
+            CompletedPart::builder()
+                .set_e_tag(part.e_tag().map(str::to_string))
+                .part_number(part_number)
+                .build(),
+        );
+    }
+
+    Ok(parts)
+}
+
+/// Uploads the file with a single request if it fits in a part, in parts otherwise
+async fn upload_file(
+    client: &Client,
+    settings: &UploadSettings,
+    path: &Path,
+    key: &str,
+    size: u64,
+) -> Result<(), UploadError> {
+    let object_key = format!("{}{key}", settings.key_prefix);
+
+    if size <= settings.part_size as u64 {
+        let body = ByteStream::from_path(path)
+            .await
+            .map_err(|e| UploadError::Retryable(e.to_string()))?;
+        client
+            .put_object()
+            .bucket(&settings.bucket)
+            .key(&object_key)
+            .body(body)
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        return Ok(());
+    }
+
+    let upload = client
+        .create_multipart_upload()
+        .bucket(&settings.bucket)
+        .key(&object_key)
+        .send()
+        .await
+        .map_err(sdk_error)?;
+    let upload_id = upload
+        .upload_id()
+        .ok_or_else(|| UploadError::Retryable("Multipart upload has no id".to_string()))?;
+
+    let parts = match upload_parts(client, settings, path, &object_key, upload_id).await {
+        Ok(parts) => parts,
+        Err(e) => {
+            // The parts uploaded so far would be billed until the upload is aborted
+            if let Err(abort_error) = client
+                .abort_multipart_upload()
+                .bucket(&settings.bucket)
+                .key(&object_key)
+                .upload_id(upload_id)
+                .send()
+                .await
+            {
+                warn!(
+                    "Failed to abort the multipart upload of {object_key}, error: {}",
+                    DisplayErrorContext(abort_error)
+                );
+            }
+            return Err(e);
+        }
+    };
+
+    client
+        .complete_multipart_upload()
+        .bucket(&settings.bucket)
+        .key(&object_key)
+        .upload_id(upload_id)
+        .multipart_upload(
+            CompletedMultipartUpload::builder()
+                .set_parts(Some(parts))
+                .build(),
+        )
+        .send()
+        .await
+        .map_err(sdk_error)?;
+
+    Ok(())
+}
+
+/// Moves a file which could not be uploaded from the staging to the failed directory,
+/// keeping its key, so it is not picked up again on the next start
+fn move_to_failed(staging_directory: &Path, failed_directory: &Path, key: &str) -> io::Result<()> {
+    let failed_path = failed_directory.join(key);
+    if let Some(parent) = failed_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(staging_directory.join(key), failed_path)
+}
+
+/// Uploads the files one after another in the order they were finished. A failed upload
+/// is retried with an exponential backoff, the file is moved to the failed directory
+/// when the error is permanent or `max_attempts` have failed
+async fn upload_files(
+    settings: UploadSettings,
+    staging_directory: PathBuf,
+    failed_directory: PathBuf,
+    mut rx: mpsc::UnboundedReceiver<PendingUpload>,
+    stats: Arc<Stats>,
+) {
+    let client = create_client(&settings).await;
+
+    while let Some(upload) = rx.recv().await {
+        let path = staging_directory.join(&upload.key);
+        let mut delay = RETRY_MIN_DELAY;
+        let mut attempts = 0;
+
+        loop {
+            if !path.exists() {
+                error!("{} has been removed before it was uploaded", path.display());
+                stats.s3_uploads.abandoned(upload.size);
+                break;
+            }
+
+            match upload_file(&client, &settings, &path, &upload.key, upload.size).await {
+                Ok(()) => {
+                    info!(
+                        "Uploaded {} to s3://{}/{}{}",
+                        path.display(),
+                        settings.bucket,
+                        settings.key_prefix,
+                        upload.key
+                    );
+                    if let Err(e) = fs::remove_file(&path) {
+                        error!("Failed to remove {}, error: {e}", path.display());
+                    }
+                    stats.s3_uploads.uploaded(upload.size);
+                    break;
+                }
+                Err(e) => {
+                    stats.s3_uploads.failed_attempts.inc();
+                    attempts += 1;
+                    if matches!(e, UploadError::Permanent(_)) || attempts >= settings.max_attempts {
+                        error!(
+                            "Giving up on the upload of {} to S3 after {attempts} attempts, moving it to {}, error: {e}",
+                            path.display(),
+                            failed_directory.display()
+                        );
+                        if let Err(e) =
+                            move_to_failed(&staging_directory, &failed_directory, &upload.key)
+                        {
+                            error!("Failed to move {}, error: {e}", path.display());
+                        }
+                        stats.s3_uploads.failed(upload.size);
+                        break;
+                    }
+                    warn!(
+                        "Failed to upload {} to S3, retrying in {delay:?}, error: {e}",
+                        path.display()
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(RETRY_MAX_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// Lists the files left in a directory by a previous run, relative to the directory.
+/// The copies interrupted by the previous run are removed, their sources are still in place
+fn staged_keys(directory: &Path, prefix: &str, keys: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let key = format!("{prefix}{name}");
+        if entry.file_type()?.is_dir() {
+            staged_keys(&entry.path(), &format!("{key}/"), keys)?;
+        } else if name.ends_with(PARTIAL_SUFFIX) {
+            fs::remove_file(entry.path())?;
+        } else {
+            keys.push(key);
+        }
+    }
+    Ok(())
+}
+
+/// Names the staging subdirectory of a sink after its directory, so the sinks sharing
+/// `s3_staging_directory` do not resume the uploads of each other
+fn staging_subdirectory(sink_directory: &Path) -> String {
+    let components: Vec<_> = sink_directory
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    components.join("_")
+}
+
+/// Moves a file, copying it when the destination is on another file system
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(EXDEV) => {
+            // The copy only gets its name once it is complete, so it is never uploaded partially
+            let mut partial_name = to.as_os_str().to_os_string();
+            partial_name.push(PARTIAL_SUFFIX);
+            let partial_path = PathBuf::from(partial_name);
+            fs::copy(from, &partial_path)?;
+            fs::rename(&partial_path, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Uploads the finished files of the File and Parquet sinks to an S3-compatible bucket.
+/// A finished file is moved to the staging directory and stays there until it has been
+/// uploaded, so the uploads which are pending on unload are resumed on the next start
+pub struct S3Uploader {
+    staging_directory: PathBuf,
+    tx: mpsc::UnboundedSender<PendingUpload>,
+    stats: Arc<Stats>,
+}
+
+impl S3Uploader {
+    /// Returns `None` if no bucket is configured. `sink_directory` is the directory the
+    /// files are written to, the staging directory defaults to a subdirectory of it.
+    /// A configured staging directory gets a subdirectory per sink directory
+    pub fn from_config(
+        config: &GeyserPluginKafkaConfig,
+        sink_directory: &Path,
+        stats: Arc<Stats>,
+        runtime: &Runtime,
+    ) -> io::Result<Option<Arc<Self>>> {
+        if config.s3_bucket.is_empty() {
+            return Ok(None);
+        }
+
+        let staging_directory = if config.s3_staging_directory.is_empty() {
+            sink_directory.join("s3-staging")
+        } else {
+            PathBuf::from(&config.s3_staging_directory).join(staging_subdirectory(sink_directory))
+        };
+        fs::create_dir_all(&staging_directory)?;
+
+        let mut failed_directory_name = staging_directory
+            .file_name()
+            .unwrap_or_default()
+            .to_os_string();
+        failed_directory_name.push("-failed");
+        let failed_directory = staging_directory.with_file_name(failed_directory_name);
+        if failed_directory.exists() {
+            let mut failed_keys = Vec::new();
+            staged_keys(&failed_directory, "", &mut failed_keys)?;
+            // The sinks sharing the stats count their failed files together
+            stats.s3_uploads.failed.inc_by(failed_keys.len() as u64);
+        }
+
+        let settings = UploadSettings {
+            bucket: config.s3_bucket.clone(),
+            key_prefix: config.s3_key_prefix.clone(),
+            region: config.s3_region.clone(),
+            endpoint: config.s3_endpoint.clone(),
+            access_key_id: config.s3_access_key_id.clone(),
+            secret_access_key: config.s3_secret_access_key.clone(),
+            part_size: config
+                .s3_multipart_part_size
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_S3_MULTIPART_PART_SIZE)
+                .max(MIN_PART_SIZE),
+            max_attempts: config
+                .s3_max_upload_attempts
+                .parse::<u32>()
+                .unwrap_or(DEFAULT_S3_MAX_UPLOAD_ATTEMPTS)
+                .max(1),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        runtime.spawn(upload_files(
+            settings,
+            staging_directory.clone(),
+            failed_directory,
+            rx,
+            stats.clone(),
+        ));
+
+        let uploader = S3Uploader {
+            staging_directory,
+            tx,
+            stats,
+        };
+
+        let mut keys = Vec::new();
+        staged_keys(&uploader.staging_directory, "", &mut keys)?;
+        // The keys start with the slot range, so the older files are uploaded first
+        keys.sort();
+        if !keys.is_empty() {
+            info!("Resuming the upload of {} staged files", keys.len());
+        }
+        for key in keys {
+            uploader.queue(key)?;
+        }
+
+        Ok(Some(Arc::new(uploader)))
+    }
+
+    /// Moves a finished file to the staging directory and queues it for upload under `key`
+    pub fn submit(&self, path: &Path, key: &str) -> io::Result<()> {
+        let staged_path = self.staging_directory.join(key);
+        if let Some(parent) = staged_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        move_file(path, &staged_path)?;
+        self.queue(key.to_string())
+    }
+
+    fn queue(&self, key: String) -> io::Result<()> {
+        let size = fs::metadata(self.staging_directory.join(&key))?.len();
+        self.stats.s3_uploads.queued(size);
+        self.tx
+            .send(PendingUpload { key, size })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "S3 uploader has stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::geyser_neon_config::tests::test_config;
+
+    #[test]
+    fn failed_files_keep_their_key() {
+        let directory =
+            env::temp_dir().join(format!("geyser_neon_s3_{}", Utc::now().timestamp_nanos()));
+        let staging_directory = directory.join("s3-staging");
+        let failed_directory = directory.join("s3-staging-failed");
+        fs::create_dir_all(staging_directory.join("accounts")).unwrap();
+        fs::write(staging_directory.join("accounts/1-2.parquet"), b"data").unwrap();
+
+        move_to_failed(
+            &staging_directory,
+            &failed_directory,
+            "accounts/1-2.parquet",
+        )
+        .unwrap();
+
+        assert!(!staging_directory.join("accounts/1-2.parquet").exists());
+        let mut keys = Vec::new();
+        staged_keys(&failed_directory, "", &mut keys).unwrap();
+        assert_eq!(keys, vec!["accounts/1-2.parquet".to_string()]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn staging_subdirectory_is_named_after_the_sink_directory() {
+        assert_eq!(
+            staging_subdirectory(Path::new("/var/lib/geyser/parquet")),
+            "var_lib_geyser_parquet"
+        );
+        assert_eq!(staging_subdirectory(Path::new("./files")), "files");
+    }
+
+    #[test]
+    fn failed_files_of_the_sinks_are_counted_together() {
+        let config = test_config(json!({
+            "s3_bucket": "geyser-neon-test",
+            "s3_endpoint": "http://127.0.0.1:9",
+        }));
+        let runtime = Runtime::new().unwrap();
+        let stats = Arc::new(Stats::default());
+        let directory =
+            env::temp_dir().join(format!("geyser_neon_s3_{}", Utc::now().timestamp_nanos()));
+
+        for (sink, failed_files) in [("file", 1), ("parquet", 2)] {
+            let failed_directory = directory.join(sink).join("s3-staging-failed");
+            fs::create_dir_all(&failed_directory).unwrap();
+            for file in 0..failed_files {
+                fs::write(failed_directory.join(format!("{file}.bin")), b"data").unwrap();
+            }
+            S3Uploader::from_config(&config, &directory.join(sink), stats.clone(), &runtime)
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(stats.s3_uploads.failed.get(), 3);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Needs a MinIO server with the default credentials,
+    // e.g. `docker run -p 9000:9000 minio/minio server /data`
+    #[test]
+    #[ignore]
+    fn files_are_uploaded_to_the_bucket() {
+        let config = test_config(json!({
+            "s3_bucket": "geyser-neon-test",
+            "s3_key_prefix": "test/",
+            "s3_region": "us-east-1",
+            "s3_endpoint": "http://127.0.0.1:9000",
+            "s3_access_key_id": "minioadmin",
+            "s3_secret_access_key": "minioadmin",
+        }));
+        let runtime = Runtime::new().unwrap();
+        let settings = UploadSettings {
+            bucket: config.s3_bucket.clone(),
+            key_prefix: config.s3_key_prefix.clone(),
+            region: config.s3_region.clone(),
+            endpoint: config.s3_endpoint.clone(),
+            access_key_id: config.s3_access_key_id.clone(),
+            secret_access_key: config.s3_secret_access_key.clone(),
+            part_size: MIN_PART_SIZE,
+            max_attempts: 1,
+        };
+        let client = runtime.block_on(async {
+            let client = create_client(&settings).await;
+            // Fails if the bucket exists already
+            let _ = client.create_bucket().bucket(&settings.bucket).send().await;
+            client
+        });
+
+        let directory =
+            env::temp_dir().join(format!("geyser_neon_s3_{}", Utc::now().timestamp_nanos()));
+        fs::create_dir_all(&directory).unwrap();
+        let stats = Arc::new(Stats::default());
+        let uploader = S3Uploader::from_config(&config, &directory, stats.clone(), &runtime)
+            .unwrap()
+            .unwrap();
+
+        let key = format!("{}.ndjson", Utc::now().timestamp_nanos());
+        let path = directory.join(&key);
+        fs::write(&path, b"{\"slot\":1}\n").unwrap();
+        uploader.submit(&path, &key).unwrap();
+
+        for _ in 0..100 {
+            if stats.s3_uploads.completed.get() == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(stats.s3_uploads.completed.get(), 1);
+        assert!(!directory.join("s3-staging").join(&key).exists());
+
+        let body = runtime.block_on(async {
+            let object = client
+                .get_object()
+                .bucket(&settings.bucket)
+                .key(format!("{}{key}", settings.key_prefix))
+                .send()
+                .await
+                .unwrap();
+            object.body.collect().await.unwrap().into_bytes()
+        });
+        assert_eq!(&body[..], b"{\"slot\":1}\n");
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use kafka_common::{
+    kafka_structs::{
+        KafkaReplicaBlockInfoVersions, KafkaReplicaTransactionInfoVersions, NotifyBlockMetaData,
+        NotifyTransaction, UpdateAccount, UpdateSlotStatus,
+    },
+    message_type::{GetMessageType, MessageType},
+};
+use rdkafka::error::KafkaError;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    runtime::Runtime,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    fanout_sink::FanOutSink, file_sink::FileSink, geyser_neon_config::GeyserPluginKafkaConfig,
+    grpc_sink::GrpcSink, kafka_producer_stats::ContextWithStats, kafka_sink::KafkaSink,
+    null_sink::NullSink, parquet_sink::ParquetSink, postgres_sink::PostgresSink,
+    redis_sink::RedisSink, websocket_sink::WebSocketSink,
+};
+
+/// Backend the messages are published to
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SinkKind {
+    #[default]
+    Kafka,
+    File,
+    Null,
+    Grpc,
+    WebSocket,
+    Redis,
+    Postgres,
+    Parquet,
+}
+
+/// Any of the messages received from the validator
+#[derive(Debug)]
+pub enum SinkMessage {
+    UpdateAccount(UpdateAccount),
+    UpdateSlotStatus(UpdateSlotStatus),
+    NotifyTransaction(NotifyTransaction),
+    NotifyBlockMetaData(NotifyBlockMetaData),
+}
+
+impl From<UpdateAccount> for SinkMessage {
+    fn from(update_account: UpdateAccount) -> Self {
+        SinkMessage::UpdateAccount(update_account)
+    }
+}
+
+impl From<UpdateSlotStatus> for SinkMessage {
+    fn from(update_slot_status: UpdateSlotStatus) -> Self {
+        SinkMessage::UpdateSlotStatus(update_slot_status)
+    }
+}
+
+impl From<NotifyTransaction> for SinkMessage {
+    fn from(notify_transaction: NotifyTransaction) -> Self {
+        SinkMessage::NotifyTransaction(notify_transaction)
+    }
+}
+
+impl From<NotifyBlockMetaData> for SinkMessage {
+    fn from(notify_block: NotifyBlockMetaData) -> Self {
+        SinkMessage::NotifyBlockMetaData(notify_block)
+    }
+}
+
+impl GetMessageType for SinkMessage {
+    fn get_type(&self) -> MessageType {
+        match self {
+            SinkMessage::UpdateAccount(message) => message.get_type(),
+            SinkMessage::UpdateSlotStatus(message) => message.get_type(),
+            SinkMessage::NotifyTransaction(message) => message.get_type(),
+            SinkMessage::NotifyBlockMetaData(message) => message.get_type(),
+        }
+    }
+}
+
+impl SinkMessage {
+    pub fn slot(&self) -> u64 {
+        match self {
+            SinkMessage::UpdateAccount(message) => message.slot,
+            SinkMessage::UpdateSlotStatus(message) => message.slot,
+            SinkMessage::NotifyTransaction(message) => message.slot,
+            SinkMessage::NotifyBlockMetaData(message) => match &message.block_info {
+                KafkaReplicaBlockInfoVersions::V0_0_1(block_info) => block_info.slot,
+            },
+        }
+    }
+
+    pub fn is_vote_transaction(&self) -> bool {
+        match self {
+            SinkMessage::NotifyTransaction(message) => match &message.transaction_info {
+                KafkaReplicaTransactionInfoVersions::V0_0_1(info) => info.is_vote,
+                KafkaReplicaTransactionInfoVersions::V0_0_2(info) => info.is_vote,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// A message together with its serialized form, so sinks which only need
+/// the bytes don't have to serialize it again
+#[derive(Debug)]
+pub struct SinkRecord {
+    /// Hash of the message, unique unless the validator sends the same message twice
+    pub key: String,
+    /// Records with the same partition key are kept in order by the sinks which partition them
+    pub partition_key: Option<String>,
+    /// JSON encoded message, the format consumed by `kafka_common`
+    pub payload: Vec<u8>,
+    pub message: SinkMessage,
+}
+
+impl SinkRecord {
+    pub fn message_type(&self) -> MessageType {
+        self.message.get_type()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] KafkaError),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Other(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SinkHealth {
+    Healthy,
+    Unhealthy(String),
+}
+
+#[async_trait]
+pub trait Sink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Resolves once the record has been accepted by the backend
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError>;
+
+    /// Waits for the records which have been published but not yet delivered
+    async fn flush(&self, timeout: Duration) -> Result<(), SinkError>;
+
+    fn health(&self) -> SinkHealth;
+}
+
+/// Error of the last write of a sink, the sink is unhealthy until a write succeeds again
+#[derive(Debug, Default)]
+pub struct LastError(Mutex<Option<String>>);
+
+impl LastError {
+    pub fn set<T, E: ToString>(&self, result: &Result<T, E>) {
+        *self.0.lock().expect("Error lock is poisoned") =
+            result.as_ref().err().map(ToString::to_string);
+    }
+
+    pub fn health(&self) -> SinkHealth {
+        match self.0.lock().expect("Error lock is poisoned").as_ref() {
+            Some(e) => SinkHealth::Unhealthy(format!("last write failed: {e}")),
+            None => SinkHealth::Healthy,
+        }
+    }
+}
+
+// Capacity of the queue in front of a writer thread
+const WRITER_QUEUE_SIZE: usize = 1024;
+
+/// Blocking file work of a sink, run by a `WriterThread`
+pub trait BlockingWriter: Send + 'static {
+    fn write(&mut self, record: &SinkRecord) -> Result<(), SinkError>;
+
+    fn flush(&mut self) -> Result<(), SinkError>;
+}
+
+enum WriterCommand {
+    Write {
+        record: Arc<SinkRecord>,
+        ack: oneshot::Sender<Result<(), SinkError>>,
+    },
+    Flush {
+        ack: oneshot::Sender<Result<(), SinkError>>,
+    },
+}
+
+/// Runs a `BlockingWriter` on a thread of its own, so the blocking work doesn't hold up the
+/// runtime workers. The thread stops once the handle is dropped and the queue is drained
+pub struct WriterThread {
+    name: &'static str,
+    tx: mpsc::Sender<WriterCommand>,
+}
+
+impl WriterThread {
+    pub fn spawn(name: &'static str, mut writer: impl BlockingWriter) -> io::Result<Self> {
+        let (tx, mut rx) = mpsc::channel(WRITER_QUEUE_SIZE);
+        thread::Builder::new()
+            .name(format!("{name}-sink-writer"))
+            .spawn(move || {
+                while let Some(command) = rx.blocking_recv() {
+                    match command {
+                        WriterCommand::Write { record, ack } => {
+                            let _ = ack.send(writer.write(&record));
+                        }
+                        WriterCommand::Flush { ack } => {
+                            let _ = ack.send(writer.flush());
+                        }
+                    }
+                }
+            })?;
+
+        Ok(WriterThread { name, tx })
+    }
+
+    pub async fn write(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let (ack, ack_rx) = oneshot::channel();
+        self.request(WriterCommand::Write { record, ack }, ack_rx)
+            .await
+    }
+
+    pub async fn flush(&self) -> Result<(), SinkError> {
+        let (ack, ack_rx) = oneshot::channel();
+        self.request(WriterCommand::Flush { ack }, ack_rx).await
+    }
+
+    async fn request(
+        &self,
+        command: WriterCommand,
+        ack_rx: oneshot::Receiver<Result<(), SinkError>>,
+    ) -> Result<(), SinkError> {
+        let stopped = || SinkError::Other(format!("The {} writer has stopped", self.name));
+        self.tx.send(command).await.map_err(|_| stopped())?;
+        ack_rx.await.map_err(|_| stopped())?
+    }
+}
+
+/// Creates the sink selected in the configuration, or the fan-out sink when several sinks are configured
+pub fn create_sink(
+    config: Arc<GeyserPluginKafkaConfig>,
+    ctx_stats: ContextWithStats,
+    runtime: &Runtime,
+) -> Result<Arc<dyn Sink>, SinkError> {
+    if config.sinks.is_empty() {
+        create_sink_of_kind(config.sink, config, ctx_stats, runtime)
+    } else {
+        Ok(Arc::new(FanOutSink::new(&config, ctx_stats, runtime)?))
+    }
+}
+
+/// Only Kafka consumers restore the account data compressed or delta encoded by the plugin,
+/// the other sinks store or forward the messages as they are
+fn check_account_data_encoding(
+    kind: SinkKind,
+    config: &GeyserPluginKafkaConfig,
+) -> Result<(), SinkError> {
+    let delta_keyframe_interval = config
+        .account_data_delta_keyframe_interval
+        .parse::<usize>()
+        .unwrap_or(0);
+
+    if matches!(kind, SinkKind::Kafka | SinkKind::Null)
+        || (config.account_data_compression.is_none() && delta_keyframe_interval == 0)
+    {
+        return Ok(());
+    }
+
+    Err(SinkError::Other(format!(
+        "The {kind:?} sink sends account data as is, account_data_compression and \
+         account_data_delta_keyframe_interval must not be set"
+    )))
+}
+
+pub fn create_sink_of_kind(
+    kind: SinkKind,
+    config: Arc<GeyserPluginKafkaConfig>,
+    ctx_stats: ContextWithStats,
+    runtime: &Runtime,
+) -> Result<Arc<dyn Sink>, SinkError> {
+    check_account_data_encoding(kind, &config)?;
+
+    let sink: Arc<dyn Sink> = match kind {
+        SinkKind::Kafka => Arc::new(KafkaSink::new(config, ctx_stats)?),
+        SinkKind::File => Arc::new(FileSink::new(&config, ctx_stats.stats, runtime)?),
+        SinkKind::Null => Arc::new(NullSink::new(&config)),
+        SinkKind::Grpc => Arc::new(GrpcSink::new(&config, ctx_stats.stats, runtime)?),
+        SinkKind::WebSocket => Arc::new(WebSocketSink::new(&config, ctx_stats.stats, runtime)?),
+        SinkKind::Redis => Arc::new(RedisSink::new(&config, runtime)?),
+        SinkKind::Postgres => Arc::new(PostgresSink::new(&config, runtime)?),
+        SinkKind::Parquet => Arc::new(ParquetSink::new(&config, ctx_stats.stats, runtime)?),
+    };
+    Ok(sink)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chrono::NaiveDateTime;
+    use kafka_common::kafka_structs::{
+        KafkaReplicaAccountInfoV2, KafkaReplicaAccountInfoVersions, KafkaSlotStatus,
+    };
+
+    use super::*;
+
+    pub(crate) fn slot_record(slot: u64) -> Arc<SinkRecord> {
+        let update_slot_status = UpdateSlotStatus {
+            slot,
+            parent: slot.checked_sub(1),
+            status: KafkaSlotStatus::Processed,
+            retrieved_time: NaiveDateTime::default(),
+        };
+
+        Arc::new(SinkRecord {
+            key: update_slot_status.get_hash(),
+            partition_key: None,
+            payload: serde_json::to_vec(&update_slot_status).unwrap(),
+            message: update_slot_status.into(),
+        })
+    }
+
+    pub(crate) fn account_record(
+        pubkey: &[u8],
+        slot: u64,
+        write_version: u64,
+        data: &[u8],
+    ) -> Arc<SinkRecord> {
+        let update_account = UpdateAccount {
+            account: KafkaReplicaAccountInfoVersions::V0_0_2(KafkaReplicaAccountInfoV2 {
+                pubkey: pubkey.to_vec(),
+                lamports: 1,
+                owner: vec![0; 32],
+                executable: false,
+                rent_epoch: 0,
+                data: data.to_vec(),
+                write_version,
+                txn_signature: None,
+            }),
+            slot,
+            is_startup: false,
+            retrieved_time: NaiveDateTime::default(),
+            data_compression: None,
+            data_delta: None,
+        };
+
+        Arc::new(SinkRecord {
+            key: update_account.get_hash(),
+            partition_key: None,
+            payload: serde_json::to_vec(&update_account).unwrap(),
+            message: update_account.into(),
+        })
+    }
+}
@@ -0,0 +1,648 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use kafka_common::kafka_structs::KafkaReplicaTransactionInfoVersions;
+use log::{error, info, warn};
+use prometheus_client::metrics::{counter::Counter, gauge::Gauge};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    sync::{mpsc, Notify, Semaphore},
+    time::{sleep, timeout},
+};
+use tokio_tungstenite::{
+    accept_async,
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+};
+
+use crate::{
+    geyser_neon_config::{
+        GeyserPluginKafkaConfig, DEFAULT_WEBSOCKET_CONNECTION_BUFFER,
+        DEFAULT_WEBSOCKET_HANDSHAKE_TIMEOUT_MS, DEFAULT_WEBSOCKET_MAX_CONNECTIONS,
+        DEFAULT_WEBSOCKET_MAX_SUBSCRIPTIONS_PER_CONNECTION,
+    },
+    kafka_producer_stats::Stats,
+    sink::{Sink, SinkError, SinkHealth, SinkMessage, SinkRecord},
+};
+
+const JSON_RPC_PARSE_ERROR: i64 = -32700;
+const JSON_RPC_METHOD_NOT_FOUND: i64 = -32601;
+const JSON_RPC_INVALID_PARAMS: i64 = -32602;
+const JSON_RPC_SERVER_ERROR: i64 = -32000;
+
+// Failing accepts, e.g. when the process is out of file descriptors, are retried with a backoff
+const ACCEPT_ERROR_MIN_DELAY: Duration = Duration::from_millis(10);
+const ACCEPT_ERROR_MAX_DELAY: Duration = Duration::from_secs(1);
+
+/// Connections and subscriptions of the WebSocket server
+#[derive(Default)]
+pub struct WebSocketStats {
+    pub connections: Gauge<u64, AtomicU64>,
+    pub subscriptions_lagged: Counter<u64, AtomicU64>,
+}
+
+#[derive(Deserialize)]
+struct JsonRpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SubscriptionMethod {
+    Account,
+    Program,
+    Slot,
+    Signature,
+}
+
+impl SubscriptionMethod {
+    fn parse(method: &str) -> Option<(Self, bool)> {
+        match method {
+            "accountSubscribe" => Some((SubscriptionMethod::Account, true)),
+            "accountUnsubscribe" => Some((SubscriptionMethod::Account, false)),
+            "programSubscribe" => Some((SubscriptionMethod::Program, true)),
+            "programUnsubscribe" => Some((SubscriptionMethod::Program, false)),
+            "slotSubscribe" => Some((SubscriptionMethod::Slot, true)),
+            "slotUnsubscribe" => Some((SubscriptionMethod::Slot, false)),
+            "signatureSubscribe" => Some((SubscriptionMethod::Signature, true)),
+            "signatureUnsubscribe" => Some((SubscriptionMethod::Signature, false)),
+            _ => None,
+        }
+    }
+
+    fn notification(&self) -> &'static str {
+        match self {
+            SubscriptionMethod::Account => "accountNotification",
+            SubscriptionMethod::Program => "programNotification",
+            SubscriptionMethod::Slot => "slotNotification",
+            SubscriptionMethod::Signature => "signatureNotification",
+        }
+    }
+}
+
+struct Subscription {
+    method: SubscriptionMethod,
+    // Decoded pubkey or signature, empty for slot subscriptions
+    key: Vec<u8>,
+    connection_id: u64,
+    tx: mpsc::Sender<String>,
+    lagged: Arc<Notify>,
+}
+
+impl Subscription {
+    fn matches(&self, message: &SinkMessage) -> bool {
+        match (self.method, message) {
+            (SubscriptionMethod::Account, SinkMessage::UpdateAccount(update_account)) => {
+                update_account.account.pubkey() == self.key
+            }
+            (SubscriptionMethod::Program, SinkMessage::UpdateAccount(update_account)) => {
+                update_account.account.owner() == self.key
+            }
+            (SubscriptionMethod::Slot, SinkMessage::UpdateSlotStatus(_)) => true,
+            (SubscriptionMethod::Signature, SinkMessage::NotifyTransaction(notify_transaction)) => {
+                let signature = match &notify_transaction.transaction_info {
+                    KafkaReplicaTransactionInfoVersions::V0_0_1(info) => &info.signature,
+                    KafkaReplicaTransactionInfoVersions::V0_0_2(info) => &info.signature,
+                };
+                signature.as_ref() == self.key
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Subscriptions of all the connections, shared by the sink and the connection tasks
+#[derive(Default)]
+struct Subscriptions {
+    next_id: AtomicU64,
+    subscriptions: Mutex<HashMap<u64, Subscription>>,
+    server_error: Mutex<Option<String>>,
+}
+
+impl Subscriptions {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Subscription>> {
+        self.subscriptions
+            .lock()
+            .expect("Subscriptions lock is poisoned")
+    }
+
+    fn remove_connection(&self, connection_id: u64) {
+        self.lock()
+            .retain(|_, subscription| subscription.connection_id != connection_id);
+    }
+}
+
+fn json_rpc_error(id: Value, code: i64, message: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+    .to_string()
+}
+
+fn json_rpc_result(id: Value, result: Value) -> String {
+    json!({ "jsonrpc": "2.0", "result": result, "id": id }).to_string()
+}
+
+/// Limits of every connection, from the configuration
+#[derive(Clone, Copy)]
+struct ConnectionLimits {
+    buffer: usize,
+    max_subscriptions: usize,
+    handshake_timeout: Duration,
+}
+
+/// State of a single WebSocket connection
+struct Connection {
+    id: u64,
+    subscriptions: Arc<Subscriptions>,
+    max_subscriptions: usize,
+    tx: mpsc::Sender<String>,
+    lagged: Arc<Notify>,
+}
+
+impl Connection {
+    fn handle_request(&self, text: &str) -> String {
+        let request: JsonRpcRequest = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(e) => return json_rpc_error(Value::Null, JSON_RPC_PARSE_ERROR, &e.to_string()),
+        };
+
+        let (method, subscribe) = match SubscriptionMethod::parse(&request.method) {
+            Some(method) => method,
+            None => {
+                return json_rpc_error(request.id, JSON_RPC_METHOD_NOT_FOUND, "Method not found")
+            }
+        };
+
+        if subscribe {
+            self.subscribe(request.id, method, &request.params)
+        } else {
+            self.unsubscribe(request.id, method, &request.params)
+        }
+    }
+
+    fn subscribe(&self, id: Value, method: SubscriptionMethod, params: &[Value]) -> String {
+        let key = if method == SubscriptionMethod::Slot {
+            Vec::new()
+        } else {
+            let decoded = params
+                .first()
+                .and_then(Value::as_str)
+                .map(|key| bs58::decode(key).into_vec());
+            match decoded {
+                Some(Ok(key)) => key,
+                _ => {
+                    return json_rpc_error(
+                        id,
+                        JSON_RPC_INVALID_PARAMS,
+                        "Expected a base58 encoded key as the first parameter",
+                    )
+                }
+            }
+        };
+
+        let mut subscriptions = self.subscriptions.lock();
+        let count = subscriptions
+            .values()
+            .filter(|subscription| subscription.connection_id == self.id)
+            .count();
+        if count >= self.max_subscriptions {
+            return json_rpc_error(
+                id,
+                JSON_RPC_SERVER_ERROR,
+                "Too many subscriptions on this connection",
+            );
+        }
+
+        let subscription_id = self.subscriptions.next_id.fetch_add(1, Ordering::Relaxed);
+        subscriptions.insert(
+            subscription_id,
+            Subscription {
+                method,
+                key,
+                connection_id: self.id,
+                tx: self.tx.clone(),
+                lagged: self.lagged.clone(),
+            },
+        );
+
+        json_rpc_result(id, json!(subscription_id))
+    }
+
+    fn unsubscribe(&self, id: Value, method: SubscriptionMethod, params: &[Value]) -> String {
+        let subscription_id = match params.first().and_then(Value::as_u64) {
+            Some(subscription_id) => subscription_id,
+            None => {
+                return json_rpc_error(
+                    id,
+                    JSON_RPC_INVALID_PARAMS,
+                    "Expected the subscription id as the first parameter",
+                )
+            }
+        };
+
+        let mut subscriptions = self.subscriptions.lock();
+        let removed = match subscriptions.get(&subscription_id) {
+            Some(subscription)
+                if subscription.connection_id == self.id && subscription.method == method =>
+            {
+                subscriptions.remove(&subscription_id);
+                true
+            }
+            _ => false,
+        };
+
+        json_rpc_result(id, json!(removed))
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    subscriptions: Arc<Subscriptions>,
+    connection_id: u64,
+    limits: ConnectionLimits,
+    stats: Arc<Stats>,
+) {
+    let websocket = match timeout(limits.handshake_timeout, accept_async(stream)).await {
+        Ok(Ok(websocket)) => websocket,
+        Ok(Err(e)) => {
+            warn!("WebSocket handshake with {peer} failed, error: {e}");
+            return;
+        }
+        Err(_) => {
+            warn!("WebSocket handshake with {peer} timed out");
+            return;
+        }
+    };
+    info!("WebSocket connection {connection_id} from {peer}");
+    stats.websocket.connections.inc();
+
+    let (mut write, mut read) = websocket.split();
+    let (tx, mut rx) = mpsc::channel(limits.buffer);
+    let connection = Connection {
+        id: connection_id,
+        subscriptions: subscriptions.clone(),
+        max_subscriptions: limits.max_subscriptions,
+        tx,
+        lagged: Arc::new(Notify::new()),
+    };
+
+    loop {
+        let result = tokio::select! {
+            message = read.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    write.send(Message::Text(connection.handle_request(&text))).await
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => Ok(()),
+            },
+            Some(notification) = rx.recv() => write.send(Message::Text(notification)).await,
+            _ = connection.lagged.notified() => {
+                warn!("WebSocket connection {connection_id} is lagging behind, closing it");
+                let _ = write
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Again,
+                        reason: "Client is too slow, the buffer is full".into(),
+                    })))
+                    .await;
+                break;
+            }
+        };
+
+        if let Err(e) = result {
+            warn!("WebSocket connection {connection_id} failed, error: {e}");
+            break;
+        }
+    }
+
+    subscriptions.remove_connection(connection_id);
+    stats.websocket.connections.dec();
+    info!("WebSocket connection {connection_id} closed");
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    subscriptions: Arc<Subscriptions>,
+    max_connections: usize,
+    limits: ConnectionLimits,
+    stats: Arc<Stats>,
+) {
+    let connection_permits = Arc::new(Semaphore::new(max_connections));
+    let mut next_connection_id = 0;
+    let mut error_delay = ACCEPT_ERROR_MIN_DELAY;
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                error_delay = ACCEPT_ERROR_MIN_DELAY;
+
+                // The permit is held until the connection is closed
+                let permit = match connection_permits.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        warn!(
+                            "Refusing the WebSocket connection from {peer}, \
+                             {max_connections} connections are open"
+                        );
+                        continue;
+                    }
+                };
+
+                let subscriptions = subscriptions.clone();
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    handle_connection(
+                        stream,
+                        peer,
+                        subscriptions,
+                        next_connection_id,
+                        limits,
+                        stats,
+                    )
+                    .await;
+                    drop(permit);
+                });
+                next_connection_id += 1;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to accept a WebSocket connection, retrying in {error_delay:?}, error: {e}"
+                );
+                sleep(error_delay).await;
+                error_delay = (error_delay * 2).min(ACCEPT_ERROR_MAX_DELAY);
+            }
+        }
+    }
+}
+
+/// Pushes the messages to WebSocket clients which subscribe with Solana pubsub style methods
+pub struct WebSocketSink {
+    subscriptions: Arc<Subscriptions>,
+    stats: Arc<Stats>,
+}
+
+impl WebSocketSink {
+    pub fn new(
+        config: &GeyserPluginKafkaConfig,
+        stats: Arc<Stats>,
+        runtime: &Runtime,
+    ) -> Result<Self, SinkError> {
+        let listen_address: SocketAddr = config.websocket_listen_address.parse().map_err(|e| {
+            SinkError::Other(format!(
+                "Wrong WebSocket listen address {}, error: {e}",
+                config.websocket_listen_address
+            ))
+        })?;
+
+        let max_connections = config
+            .websocket_max_connections
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_WEBSOCKET_MAX_CONNECTIONS)
+            .max(1);
+
+        let limits = ConnectionLimits {
+            buffer: config
+                .websocket_connection_buffer
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_WEBSOCKET_CONNECTION_BUFFER)
+                .max(1),
+            max_subscriptions: config
+                .websocket_max_subscriptions_per_connection
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_WEBSOCKET_MAX_SUBSCRIPTIONS_PER_CONNECTION),
+            handshake_timeout: Duration::from_millis(
+                config
+                    .websocket_handshake_timeout_ms
+                    .parse::<u64>()
+                    .unwrap_or(DEFAULT_WEBSOCKET_HANDSHAKE_TIMEOUT_MS),
+            ),
+        };
+
+        let subscriptions = Arc::new(Subscriptions::default());
+        let server_subscriptions = subscriptions.clone();
+        let server_stats = stats.clone();
+        runtime.spawn(async move {
+            info!("Starting WebSocket server on {listen_address}");
+            match TcpListener::bind(listen_address).await {
+                Ok(listener) => {
+                    accept_connections(
+                        listener,
+                        server_subscriptions,
+                        max_connections,
+                        limits,
+                        server_stats,
+                    )
+                    .await
+                }
+                Err(e) => {
+                    error!("WebSocket server cannot listen on {listen_address}, error: {e}");
+                    *server_subscriptions
+                        .server_error
+                        .lock()
+                        .expect("Server error lock is poisoned") = Some(e.to_string());
+                }
+            }
+        });
+
+        Ok(WebSocketSink {
+            subscriptions,
+            stats,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for WebSocketSink {
+    fn name(&self) -> &'static str {
+        "websocket"
+    }
+
+    async fn publish(&self, record: Arc<SinkRecord>) -> Result<(), SinkError> {
+        let mut subscriptions = self.subscriptions.lock();
+        if subscriptions.is_empty() {
+            return Ok(());
+        }
+
+        // The payload is already JSON, so the notification is assembled around it
+        let payload = String::from_utf8_lossy(&record.payload);
+        subscriptions.retain(|subscription_id, subscription| {
+            if !subscription.matches(&record.message) {
+                return true;
+            }
+
+            let notification = format!(
+                "{{\"jsonrpc\":\"2.0\",\"method\":\"{}\",\"params\":{{\"result\":{payload},\"subscription\":{subscription_id}}}}}",
+                subscription.method.notification()
+            );
+
+            match subscription.tx.try_send(notification) {
+                // Like in Solana pubsub, a signature subscription ends with its notification
+                Ok(()) => subscription.method != SubscriptionMethod::Signature,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    subscription.lagged.notify_one();
+                    self.stats.websocket.subscriptions_lagged.inc();
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn flush(&self, _timeout: Duration) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    fn health(&self) -> SinkHealth {
+        match self
+            .subscriptions
+            .server_error
+            .lock()
+            .expect("Server error lock is poisoned")
+            .as_ref()
+        {
+            Some(e) => SinkHealth::Unhealthy(format!("WebSocket server failed: {e}")),
+            None => SinkHealth::Healthy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::tests::{account_record, slot_record};
+
+    fn connection(id: u64, subscriptions: &Arc<Subscriptions>) -> Connection {
+        let (tx, _) = mpsc::channel(1);
+        Connection {
+            id,
+            subscriptions: subscriptions.clone(),
+            max_subscriptions: 2,
+            tx,
+            lagged: Arc::new(Notify::new()),
+        }
+    }
+
+    fn request(connection: &Connection, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        serde_json::from_str(&connection.handle_request(&request.to_string())).unwrap()
+    }
+
+    fn subscription(method: SubscriptionMethod, key: &[u8]) -> Subscription {
+        let (tx, _) = mpsc::channel(1);
+        Subscription {
+            method,
+            key: key.to_vec(),
+            connection_id: 0,
+            tx,
+            lagged: Arc::new(Notify::new()),
+        }
+    }
+
+    #[test]
+    fn wrong_requests_get_json_rpc_errors() {
+        let subscriptions = Arc::new(Subscriptions::default());
+        let connection = connection(0, &subscriptions);
+
+        let response: Value = serde_json::from_str(&connection.handle_request("not json")).unwrap();
+        assert_eq!(response["error"]["code"], JSON_RPC_PARSE_ERROR);
+        assert_eq!(response["id"], Value::Null);
+
+        let response = request(&connection, "getSlot", json!([]));
+        assert_eq!(response["error"]["code"], JSON_RPC_METHOD_NOT_FOUND);
+        assert_eq!(response["id"], 1);
+
+        let response = request(&connection, "accountSubscribe", json!(["0OIl"]));
+        assert_eq!(response["error"]["code"], JSON_RPC_INVALID_PARAMS);
+
+        let response = request(&connection, "accountUnsubscribe", json!(["1"]));
+        assert_eq!(response["error"]["code"], JSON_RPC_INVALID_PARAMS);
+        assert!(subscriptions.lock().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_are_registered_and_removed_by_their_connection() {
+        let subscriptions = Arc::new(Subscriptions::default());
+        let other_connection = connection(1, &subscriptions);
+        let connection = connection(0, &subscriptions);
+
+        let pubkey = bs58::encode([1; 32]).into_string();
+        let response = request(&connection, "accountSubscribe", json!([pubkey]));
+        let subscription_id = response["result"].as_u64().unwrap();
+        {
+            let subscriptions = subscriptions.lock();
+            let subscription = &subscriptions[&subscription_id];
+            assert!(subscription.method == SubscriptionMethod::Account);
+            assert_eq!(subscription.key, vec![1; 32]);
+            assert_eq!(subscription.connection_id, 0);
+        }
+
+        // Only the connection of the subscription can remove it, with the matching method
+        let response = request(
+            &other_connection,
+            "accountUnsubscribe",
+            json!([subscription_id]),
+        );
+        assert_eq!(response["result"], false);
+        let response = request(&connection, "slotUnsubscribe", json!([subscription_id]));
+        assert_eq!(response["result"], false);
+        let response = request(&connection, "accountUnsubscribe", json!([subscription_id]));
+        assert_eq!(response["result"], true);
+        assert!(subscriptions.lock().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_are_limited_per_connection() {
+        let subscriptions = Arc::new(Subscriptions::default());
+        let other_connection = connection(1, &subscriptions);
+        let connection = connection(0, &subscriptions);
+
+        for _ in 0..2 {
+            let response = request(&connection, "slotSubscribe", json!([]));
+            assert!(response["result"].is_u64());
+        }
+        let response = request(&connection, "slotSubscribe", json!([]));
+        assert_eq!(response["error"]["code"], JSON_RPC_SERVER_ERROR);
+
+        let response = request(&other_connection, "slotSubscribe", json!([]));
+        assert!(response["result"].is_u64());
+        assert_eq!(subscriptions.lock().len(), 3);
+    }
+
+    #[test]
+    fn subscriptions_match_their_messages() {
+        let record = account_record(&[1; 32], 10, 1, b"data");
+
+        assert!(subscription(SubscriptionMethod::Account, &[1; 32]).matches(&record.message));
+        assert!(!subscription(SubscriptionMethod::Account, &[2; 32]).matches(&record.message));
+        // The owner of the test accounts is the zero pubkey
+        assert!(subscription(SubscriptionMethod::Program, &[0; 32]).matches(&record.message));
+        assert!(!subscription(SubscriptionMethod::Program, &[1; 32]).matches(&record.message));
+        assert!(!subscription(SubscriptionMethod::Slot, &[]).matches(&record.message));
+        assert!(!subscription(SubscriptionMethod::Signature, &[1; 32]).matches(&record.message));
+
+        let record = slot_record(10);
+        assert!(subscription(SubscriptionMethod::Slot, &[]).matches(&record.message));
+        assert!(!subscription(SubscriptionMethod::Account, &[]).matches(&record.message));
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    collections::HashMap,
+    error, fmt, mem,
+    time::{Duration, Instant},
+};
+
+use crate::compression::MAX_DECOMPRESSED_SIZE;
+
+/// Header with the identifier shared by all the chunks of a message
+pub const CHUNK_ID_HEADER: &str = "chunk_id";
+/// Header with the zero-based number of the chunk
+pub const CHUNK_INDEX_HEADER: &str = "chunk_index";
+/// Header with the total number of chunks of a message
+pub const CHUNK_COUNT_HEADER: &str = "chunk_count";
+
+/// Producers do not split messages into chunks smaller than this
+pub const MIN_CHUNK_SIZE: usize = 1024;
+/// A message carries at most `MAX_DECOMPRESSED_SIZE` bytes of account data,
+/// which take up to 4 bytes per byte in JSON
+pub const MAX_MESSAGE_SIZE: usize = 8 * MAX_DECOMPRESSED_SIZE;
+/// Chunk sets with more chunks are rejected before anything is allocated for them
+pub const MAX_CHUNK_COUNT: u32 = (MAX_MESSAGE_SIZE / MIN_CHUNK_SIZE) as u32;
+/// Default limit of the bytes held by the incomplete chunk sets of a `Reassembler`
+pub const DEFAULT_MAX_PENDING_BYTES: usize = 4 * MAX_MESSAGE_SIZE;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub id: String,
+    pub index: u32,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The header is missing or its value is not valid
+    InvalidHeader(&'static str),
+    /// The chunk index is not less than the number of chunks
+    IndexOutOfRange { id: String, index: u32, count: u32 },
+    /// Chunks with the same id disagree on the number of chunks
+    CountMismatch { id: String, expected: u32, got: u32 },
+    /// The message has more than `MAX_CHUNK_COUNT` chunks
+    TooManyChunks { id: String, count: u32 },
+    /// The chunks of the message add up to more than `MAX_MESSAGE_SIZE` bytes
+    MessageTooLarge { id: String },
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkError::InvalidHeader(name) => write!(f, "Invalid or missing header {name}"),
+            ChunkError::IndexOutOfRange { id, index, count } => {
+                write!(f, "Chunk {index} of {id} is out of range, count {count}")
+            }
+            ChunkError::CountMismatch { id, expected, got } => {
+                write!(f, "Chunk count of {id} changed from {expected} to {got}")
+            }
+            ChunkError::TooManyChunks { id, count } => {
+                write!(
+                    f,
+                    "{id} has {count} chunks, at most {MAX_CHUNK_COUNT} are allowed"
+                )
+            }
+            ChunkError::MessageTooLarge { id } => {
+                write!(f, "Chunks of {id} are larger than {MAX_MESSAGE_SIZE} bytes")
+            }
+        }
+    }
+}
+
+impl error::Error for ChunkError {}
+
+fn parse_header<T: std::str::FromStr>(name: &'static str, value: &[u8]) -> Result<T, ChunkError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or(ChunkError::InvalidHeader(name))
+}
+
+impl ChunkHeader {
+    /// Extracts the chunk headers from the Kafka message headers.
+    /// Returns `Ok(None)` if the message is not a chunk.
+    pub fn from_headers<'a, I>(headers: I) -> Result<Option<Self>, ChunkError>
+    where
+        I: IntoIterator<Item = (&'a str, &'a [u8])>,
+    {
+        let mut id = None;
+        let mut index = None;
+        let mut count = None;
+
+        for (name, value) in headers {
+            match name {
+                CHUNK_ID_HEADER => id = Some(parse_header(CHUNK_ID_HEADER, value)?),
+                CHUNK_INDEX_HEADER => index = Some(parse_header(CHUNK_INDEX_HEADER, value)?),
+                CHUNK_COUNT_HEADER => count = Some(parse_header(CHUNK_COUNT_HEADER, value)?),
+                _ => (),
+            }
+        }
+
+        match (id, index, count) {
+            (None, None, None) => Ok(None),
+            (Some(id), Some(index), Some(count)) => Ok(Some(ChunkHeader { id, index, count })),
+            (None, _, _) => Err(ChunkError::InvalidHeader(CHUNK_ID_HEADER)),
+            (_, None, _) => Err(ChunkError::InvalidHeader(CHUNK_INDEX_HEADER)),
+            (_, _, None) => Err(ChunkError::InvalidHeader(CHUNK_COUNT_HEADER)),
+        }
+    }
+
+    /// Header values in the order they should be attached to a Kafka record
+    pub fn to_headers(&self) -> [(&'static str, String); 3] {
+        [
+            (CHUNK_ID_HEADER, self.id.clone()),
+            (CHUNK_INDEX_HEADER, self.index.to_string()),
+            (CHUNK_COUNT_HEADER, self.count.to_string()),
+        ]
+    }
+}
+
+/// Splits the payload into chunks of at most `chunk_size` bytes
+pub fn split(payload: &[u8], chunk_size: usize) -> Vec<&[u8]> {
+    payload.chunks(chunk_size.max(1)).collect()
+}
+
+/// Chunk ids are only unique within a partition, so the chunks of a message are
+/// identified by the partition they were read from and the chunk id
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkKey {
+    pub topic: String,
+    pub partition: i32,
+    pub id: String,
+}
+
+/// A message whose chunks did not all arrive before the timeout,
+/// or which was dropped to stay within the pending bytes limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncompleteMessage {
+    pub topic: String,
+    pub partition: i32,
+    pub id: String,
+    pub received: u32,
+    pub count: u32,
+}
+
+struct PendingMessage {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    // Bytes of the chunks and of the chunk list
+    size: usize,
+    first_seen: Instant,
+}
+
+impl PendingMessage {
+    fn incomplete(&self, key: &ChunkKey) -> IncompleteMessage {
+        IncompleteMessage {
+            topic: key.topic.clone(),
+            partition: key.partition,
+            id: key.id.clone(),
+            received: self.received,
+            count: self.chunks.len() as u32,
+        }
+    }
+}
+
+/// Rebuilds chunked messages on the consumer side.
+/// Chunks may arrive in any order, duplicated chunks are ignored.
+pub struct Reassembler {
+    pending: HashMap<ChunkKey, PendingMessage>,
+    timeout: Duration,
+    pending_bytes: usize,
+    max_pending_bytes: usize,
+    // Chunk sets dropped to stay within `max_pending_bytes`, reported by the next `expire`
+    evicted: Vec<IncompleteMessage>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self::with_max_pending_bytes(timeout, DEFAULT_MAX_PENDING_BYTES)
+    }
+
+    /// Once the incomplete chunk sets hold more than `max_pending_bytes`,
+    /// the oldest ones are dropped
+    pub fn with_max_pending_bytes(timeout: Duration, max_pending_bytes: usize) -> Self {
+        Reassembler {
+            pending: HashMap::new(),
+            timeout,
+            pending_bytes: 0,
+            max_pending_bytes,
+            evicted: Vec::new(),
+        }
+    }
+
+    /// Adds a chunk read from the partition, returns the whole message once its last chunk has arrived
+    pub fn push(
+        &mut self,
+        topic: &str,
+        partition: i32,
+        header: ChunkHeader,
+        chunk: &[u8],
+    ) -> Result<Option<Vec<u8>>, ChunkError> {
+        self.push_at(topic, partition, header, chunk, Instant::now())
+    }
+
+    pub fn push_at(
+        &mut self,
+        topic: &str,
+        partition: i32,
+        header: ChunkHeader,
+        chunk: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, ChunkError> {
+        if header.index >= header.count {
+            return Err(ChunkError::IndexOutOfRange {
+                id: header.id,
+                index: header.index,
+                count: header.count,
+            });
+        }
+        if header.count > MAX_CHUNK_COUNT {
+            return Err(ChunkError::TooManyChunks {
+                id: header.id,
+                count: header.count,
+            });
+        }
+
+        let key = ChunkKey {
+            topic: topic.to_string(),
+            partition,
+            id: header.id.clone(),
+        };
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| {
+            let chunks = vec![None; header.count as usize];
+            let size = chunks.len() * mem::size_of::<Option<Vec<u8>>>();
+            self.pending_bytes += size;
+            PendingMessage {
+                chunks,
+                received: 0,
+                size,
+                first_seen: now,
+            }
+        });
+
+        if pending.chunks.len() != header.count as usize {
+            return Err(ChunkError::CountMismatch {
+                expected: pending.chunks.len() as u32,
+                got: header.count,
+                id: header.id,
+            });
+        }
+
+        let slot = &mut pending.chunks[header.index as usize];
+        if slot.is_none() {
+            if pending.size + chunk.len() > MAX_MESSAGE_SIZE {
+                self.remove(&key);
+                return Err(ChunkError::MessageTooLarge { id: header.id });
+            }
+            *slot = Some(chunk.to_vec());
+            pending.received += 1;
+            pending.size += chunk.len();
+            self.pending_bytes += chunk.len();
+        }
+
+        if pending.received < header.count {
+            self.evict(&key);
+            return Ok(None);
+        }
+
+        let pending = self.remove(&key).expect("Pending message must exist");
+
+        let message = pending.chunks.into_iter().flatten().flatten().collect();
+        Ok(Some(message))
+    }
+
+    fn remove(&mut self, key: &ChunkKey) -> Option<PendingMessage> {
+        let pending = self.pending.remove(key)?;
+        self.pending_bytes -= pending.size;
+        Some(pending)
+    }
+
+    /// Drops the oldest chunk sets, except the one of `current`, until the pending bytes are within the limit
+    fn evict(&mut self, current: &ChunkKey) {
+        while self.pending_bytes > self.max_pending_bytes {
+            let oldest = self
+                .pending
+                .iter()
+                .filter(|(key, _)| *key != current)
+                .min_by_key(|(_, pending)| pending.first_seen)
+                .map(|(key, _)| key.clone());
+            let oldest = match oldest {
+                Some(oldest) => oldest,
+                None => return,
+            };
+            let pending = self.remove(&oldest).expect("Pending message must exist");
+            self.evicted.push(pending.incomplete(&oldest));
+        }
+    }
+
+    /// Drops the messages which have been incomplete for longer than the timeout, and returns
+    /// them together with the messages dropped since the last call to stay within the pending bytes limit.
+    /// Should be called periodically, not only when a chunk arrives, so the messages
+    /// whose remaining chunks never come are still reported
+    pub fn expire(&mut self) -> Vec<IncompleteMessage> {
+        self.expire_at(Instant::now())
+    }
+
+    pub fn expire_at(&mut self, now: Instant) -> Vec<IncompleteMessage> {
+        let timeout = self.timeout;
+        let mut expired = mem::take(&mut self.evicted);
+        let mut expired_bytes = 0;
+
+        self.pending.retain(|key, pending| {
+            if now.saturating_duration_since(pending.first_seen) < timeout {
+                return true;
+            }
+            expired.push(pending.incomplete(key));
+            expired_bytes += pending.size;
+            false
+        });
+        self.pending_bytes -= expired_bytes;
+
+        expired
+    }
+
+    /// Number of messages waiting for more chunks
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bytes held by the messages waiting for more chunks
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOPIC: &str = "update_account";
+
+    fn header(id: &str, index: u32, count: u32) -> ChunkHeader {
+        ChunkHeader {
+            id: id.to_string(),
+            index,
+            count,
+        }
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let message: Vec<u8> = (0..100).collect();
+        let chunks = split(&message, 30);
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+
+        for index in [3, 0, 2] {
+            let result = reassembler
+                .push(TOPIC, 0, header("a", index, 4), chunks[index as usize])
+                .unwrap();
+            assert_eq!(result, None);
+        }
+        let result = reassembler
+            .push(TOPIC, 0, header("a", 1, 4), chunks[1])
+            .unwrap();
+
+        assert_eq!(result, Some(message));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn ignores_duplicated_chunks() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+
+        assert_eq!(
+            reassembler
+                .push(TOPIC, 0, header("a", 0, 2), b"ab")
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            reassembler
+                .push(TOPIC, 0, header("a", 0, 2), b"xx")
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            reassembler
+                .push(TOPIC, 0, header("a", 1, 2), b"cd")
+                .unwrap(),
+            Some(b"abcd".to_vec())
+        );
+    }
+
+    #[test]
+    fn keeps_partitions_apart() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+
+        reassembler
+            .push(TOPIC, 0, header("a", 0, 2), b"p0")
+            .unwrap();
+        reassembler
+            .push(TOPIC, 1, header("a", 0, 2), b"p1")
+            .unwrap();
+        assert_eq!(reassembler.pending(), 2);
+
+        assert_eq!(
+            reassembler.push(TOPIC, 1, header("a", 1, 2), b"!").unwrap(),
+            Some(b"p1!".to_vec())
+        );
+        assert_eq!(reassembler.pending(), 1);
+    }
+
+    #[test]
+    fn rejects_inconsistent_chunks() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+
+        assert!(matches!(
+            reassembler.push(TOPIC, 0, header("a", 2, 2), b"ab"),
+            Err(ChunkError::IndexOutOfRange { .. })
+        ));
+
+        reassembler
+            .push(TOPIC, 0, header("a", 0, 2), b"ab")
+            .unwrap();
+        assert!(matches!(
+            reassembler.push(TOPIC, 0, header("a", 1, 3), b"cd"),
+            Err(ChunkError::CountMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let start = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+
+        reassembler
+            .push_at(TOPIC, 0, header("a", 0, 3), b"ab", start)
+            .unwrap();
+        reassembler
+            .push_at(
+                TOPIC,
+                0,
+                header("b", 0, 2),
+                b"cd",
+                start + Duration::from_secs(5),
+            )
+            .unwrap();
+
+        assert!(reassembler
+            .expire_at(start + Duration::from_secs(9))
+            .is_empty());
+        assert_eq!(
+            reassembler.expire_at(start + Duration::from_secs(10)),
+            vec![IncompleteMessage {
+                topic: TOPIC.to_string(),
+                partition: 0,
+                id: "a".to_string(),
+                received: 1,
+                count: 3,
+            }]
+        );
+        assert_eq!(reassembler.pending(), 1);
+    }
+
+    #[test]
+    fn rejects_oversized_chunk_sets() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(10));
+
+        assert!(matches!(
+            reassembler.push(TOPIC, 0, header("a", 0, MAX_CHUNK_COUNT + 1), b"ab"),
+            Err(ChunkError::TooManyChunks { .. })
+        ));
+        assert_eq!(reassembler.pending(), 0);
+
+        let chunk = vec![0; MAX_MESSAGE_SIZE / 2];
+        reassembler
+            .push(TOPIC, 0, header("b", 0, 3), &chunk)
+            .unwrap();
+        assert!(matches!(
+            reassembler.push(TOPIC, 0, header("b", 1, 3), &chunk),
+            Err(ChunkError::MessageTooLarge { .. })
+        ));
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.pending_bytes(), 0);
+    }
+
+    #[test]
+    fn evicts_the_oldest_chunk_sets() {
+        let start = Instant::now();
+        let list_size = 2 * mem::size_of::<Option<Vec<u8>>>();
+        let mut reassembler =
+            Reassembler::with_max_pending_bytes(Duration::from_secs(10), 2 * (list_size + 100));
+
+        for (i, id) in ["a", "b", "c"].into_iter().enumerate() {
+            reassembler
+                .push_at(
+                    TOPIC,
+                    0,
+                    header(id, 0, 2),
+                    &[0; 100],
+                    start + Duration::from_secs(i as u64),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(reassembler.pending(), 2);
+        assert_eq!(reassembler.pending_bytes(), 2 * (list_size + 100));
+        let evicted = reassembler.expire_at(start);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id, "a");
+        assert_eq!(
+            reassembler
+                .push(TOPIC, 0, header("c", 1, 2), b"!")
+                .unwrap()
+                .map(|m| m.len()),
+            Some(101)
+        );
+        assert_eq!(reassembler.pending_bytes(), list_size + 100);
+    }
+
+    #[test]
+    fn parses_chunk_headers() {
+        let chunk_header = header("a", 1, 2);
+        let headers = chunk_header.to_headers();
+        let parsed = ChunkHeader::from_headers(
+            headers
+                .iter()
+                .map(|(name, value)| (*name, value.as_bytes())),
+        )
+        .unwrap();
+        assert_eq!(parsed, Some(chunk_header));
+
+        assert_eq!(ChunkHeader::from_headers([]).unwrap(), None);
+        assert_eq!(
+            ChunkHeader::from_headers([(CHUNK_ID_HEADER, &b"a"[..])]),
+            Err(ChunkError::InvalidHeader(CHUNK_INDEX_HEADER))
+        );
+    }
+}
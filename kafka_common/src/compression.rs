@@ -0,0 +1,159 @@
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+use lz4::block::CompressionMode;
+use serde::{Deserialize, Serialize};
+
+use crate::kafka_structs::UpdateAccount;
+
+/// Account data is at most 10 MiB, anything which decompresses to more is rejected
+/// instead of being allocated
+pub const MAX_DECOMPRESSED_SIZE: usize = 10 * 1024 * 1024;
+
+/// Codec used for the application-level compression of account data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataCompression {
+    /// Zstandard, the level is taken into account
+    Zstd,
+    /// LZ4 block format with the uncompressed size prepended
+    Lz4,
+}
+
+impl fmt::Display for DataCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataCompression::Zstd => write!(f, "Zstd"),
+            DataCompression::Lz4 => write!(f, "Lz4"),
+        }
+    }
+}
+
+pub fn compress(compression: DataCompression, level: i32, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression {
+        DataCompression::Zstd => zstd::encode_all(data, level),
+        DataCompression::Lz4 => lz4::block::compress(data, Some(CompressionMode::DEFAULT), true),
+    }
+}
+
+fn too_large(size: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Decompressed data is larger than {MAX_DECOMPRESSED_SIZE} bytes: {size}"),
+    )
+}
+
+pub fn decompress(compression: DataCompression, data: &[u8]) -> io::Result<Vec<u8>> {
+    match compression {
+        DataCompression::Zstd => {
+            let mut decompressed = Vec::new();
+            zstd::Decoder::new(data)?
+                .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+                .read_to_end(&mut decompressed)?;
+            if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+                return Err(too_large(decompressed.len()));
+            }
+            Ok(decompressed)
+        }
+        DataCompression::Lz4 => {
+            // The block starts with the uncompressed size as a little endian i32
+            let size = data
+                .get(..4)
+                .map(|prefix| i32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]))
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "LZ4 block is too short")
+                })?;
+            if size < 0 || size as usize > MAX_DECOMPRESSED_SIZE {
+                return Err(too_large(size as usize));
+            }
+            lz4::block::decompress(data, None)
+        }
+    }
+}
+
+impl UpdateAccount {
+    /// Compresses the account data in place if it is at least `threshold` bytes long.
+    /// Returns true if the data has been compressed.
+    pub fn compress_data(
+        &mut self,
+        compression: DataCompression,
+        level: i32,
+        threshold: usize,
+    ) -> io::Result<bool> {
+        if self.data_compression.is_some() {
+            return Ok(false);
+        }
+
+        let data = self.account.data_mut();
+        if data.len() < threshold {
+            return Ok(false);
+        }
+
+        *data = compress(compression, level, data)?;
+        self.data_compression = Some(compression);
+        Ok(true)
+    }
+
+    /// Restores the original account data if it has been compressed.
+    /// Called automatically when an `UpdateAccount` is deserialized.
+    pub fn decompress_data(&mut self) -> io::Result<()> {
+        if let Some(compression) = self.data_compression {
+            let data = self.account.data_mut();
+            *data = decompress(compression, data)?;
+            self.data_compression = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account_data() -> Vec<u8> {
+        (0..64 * 1024).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        let data = account_data();
+        let compressed = compress(DataCompression::Zstd, 3, &data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(
+            decompress(DataCompression::Zstd, &compressed).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        let data = account_data();
+        let compressed = compress(DataCompression::Lz4, 0, &data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(DataCompression::Lz4, &compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_round_trip() {
+        for compression in [DataCompression::Zstd, DataCompression::Lz4] {
+            let compressed = compress(compression, 0, &[]).unwrap();
+            assert!(decompress(compression, &compressed).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_data() {
+        let data = vec![0; MAX_DECOMPRESSED_SIZE + 1];
+        for compression in [DataCompression::Zstd, DataCompression::Lz4] {
+            let compressed = compress(compression, 0, &data).unwrap();
+            let error = decompress(compression, &compressed).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_lz4_block() {
+        assert!(decompress(DataCompression::Lz4, &[1, 0]).is_err());
+    }
+}
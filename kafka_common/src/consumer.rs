@@ -0,0 +1,611 @@
+use std::{borrow::Cow, time::Duration};
+
+use futures::{stream, Stream, StreamExt};
+use rdkafka::{
+    consumer::{ConsumerContext, StreamConsumer},
+    error::{KafkaError, KafkaResult},
+    message::{BorrowedMessage, Headers},
+    Message, Timestamp,
+};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    chunking::{ChunkError, ChunkHeader, Reassembler},
+    delta::{DeltaDecoder, DeltaError},
+    kafka_structs::{NotifyBlockMetaData, NotifyTransaction, UpdateAccount, UpdateSlotStatus},
+};
+
+const MIN_EXPIRY_PERIOD: Duration = Duration::from_millis(100);
+
+/// A decoded message together with its position in Kafka
+#[derive(Debug)]
+pub struct ConsumedMessage<T> {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    /// Milliseconds since the Unix epoch, if the broker or the producer provided it
+    pub timestamp: Option<i64>,
+    pub message: T,
+}
+
+#[derive(Error, Debug)]
+pub enum ConsumerError {
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] KafkaError),
+    #[error("Message at {topic}/{partition}@{offset} has no payload")]
+    EmptyPayload {
+        topic: String,
+        partition: i32,
+        offset: i64,
+    },
+    #[error("Message at {topic}/{partition}@{offset} is from an unknown topic")]
+    UnknownTopic {
+        topic: String,
+        partition: i32,
+        offset: i64,
+    },
+    #[error("Invalid chunk at {topic}/{partition}@{offset}: {source}")]
+    Chunk {
+        topic: String,
+        partition: i32,
+        offset: i64,
+        source: ChunkError,
+    },
+    #[error("Only {received} of {count} chunks of message {id} from {topic}/{partition} arrived before the timeout")]
+    IncompleteMessage {
+        topic: String,
+        partition: i32,
+        id: String,
+        received: u32,
+        count: u32,
+    },
+    #[error("Failed to restore account data at {topic}/{partition}@{offset}: {source}")]
+    Delta {
+        topic: String,
+        partition: i32,
+        offset: i64,
+        source: DeltaError,
+    },
+    #[error("Failed to decode message at {topic}/{partition}@{offset}: {source}")]
+    Decode {
+        topic: String,
+        partition: i32,
+        offset: i64,
+        source: serde_json::Error,
+    },
+}
+
+/// Any of the messages published by the plugin
+#[derive(Debug)]
+pub enum GeyserEvent {
+    UpdateAccount(UpdateAccount),
+    UpdateSlotStatus(UpdateSlotStatus),
+    NotifyTransaction(NotifyTransaction),
+    NotifyBlockMetaData(NotifyBlockMetaData),
+}
+
+/// Topic names used to tell apart the messages of a consumer subscribed to several topics.
+/// Should match the topics from the plugin configuration.
+#[derive(Clone, Debug)]
+pub struct Topics {
+    pub update_account_topic: String,
+    pub update_slot_topic: String,
+    pub notify_transaction_topic: String,
+    pub notify_block_topic: String,
+}
+
+/// A message type that can be read from the plugin topics
+pub trait ConsumerEvent: DeserializeOwned {
+    /// Restores the parts of the message which depend on the previous messages
+    fn restore(&mut self, _delta_decoder: &mut DeltaDecoder) -> Result<(), DeltaError> {
+        Ok(())
+    }
+}
+
+impl ConsumerEvent for UpdateAccount {
+    fn restore(&mut self, delta_decoder: &mut DeltaDecoder) -> Result<(), DeltaError> {
+        delta_decoder.decode(self)
+    }
+}
+
+impl ConsumerEvent for UpdateSlotStatus {}
+
+impl ConsumerEvent for NotifyTransaction {}
+
+impl ConsumerEvent for NotifyBlockMetaData {}
+
+/// State carried between the messages of a stream
+struct StreamState {
+    reassembler: Reassembler,
+    delta_decoder: DeltaDecoder,
+}
+
+impl StreamState {
+    fn new(chunk_timeout: Duration) -> Self {
+        StreamState {
+            reassembler: Reassembler::new(chunk_timeout),
+            delta_decoder: DeltaDecoder::new(),
+        }
+    }
+}
+
+fn decode_payload<T: ConsumerEvent, M: Message>(
+    message: &M,
+    payload: &[u8],
+    delta_decoder: &mut DeltaDecoder,
+) -> Result<ConsumedMessage<T>, ConsumerError> {
+    let topic = message.topic().to_string();
+    let partition = message.partition();
+    let offset = message.offset();
+
+    if payload.is_empty() {
+        return Err(ConsumerError::EmptyPayload {
+            topic,
+            partition,
+            offset,
+        });
+    }
+
+    // Both envelope versions are untagged and compressed account data is restored
+    // by the `UpdateAccount` deserializer, so plain serde_json is enough here
+    let mut decoded: T = match serde_json::from_slice(payload) {
+        Ok(decoded) => decoded,
+        Err(source) => {
+            return Err(ConsumerError::Decode {
+                topic,
+                partition,
+                offset,
+                source,
+            })
+        }
+    };
+
+    match decoded.restore(delta_decoder) {
+        Ok(()) => Ok(ConsumedMessage {
+            key: message.key().map(|k| k.to_vec()),
+            timestamp: match message.timestamp() {
+                Timestamp::NotAvailable => None,
+                Timestamp::CreateTime(t) | Timestamp::LogAppendTime(t) => Some(t),
+            },
+            topic,
+            partition,
+            offset,
+            message: decoded,
+        }),
+        Err(source) => Err(ConsumerError::Delta {
+            topic,
+            partition,
+            offset,
+            source,
+        }),
+    }
+}
+
+fn decode_event<M: Message>(
+    message: &M,
+    payload: &[u8],
+    delta_decoder: &mut DeltaDecoder,
+    topics: &Topics,
+) -> Result<ConsumedMessage<GeyserEvent>, ConsumerError> {
+    let topic = message.topic();
+
+    if topic == topics.update_account_topic {
+        decode_payload(message, payload, delta_decoder).map(|m| m.map(GeyserEvent::UpdateAccount))
+    } else if topic == topics.update_slot_topic {
+        decode_payload(message, payload, delta_decoder)
+            .map(|m| m.map(GeyserEvent::UpdateSlotStatus))
+    } else if topic == topics.notify_transaction_topic {
+        decode_payload(message, payload, delta_decoder)
+            .map(|m| m.map(GeyserEvent::NotifyTransaction))
+    } else if topic == topics.notify_block_topic {
+        decode_payload(message, payload, delta_decoder)
+            .map(|m| m.map(GeyserEvent::NotifyBlockMetaData))
+    } else {
+        Err(ConsumerError::UnknownTopic {
+            topic: topic.to_string(),
+            partition: message.partition(),
+            offset: message.offset(),
+        })
+    }
+}
+
+/// Returns the payload of a regular message, or the whole message once the last of its chunks arrives
+fn reassemble<'a, M: Message>(
+    message: &'a M,
+    reassembler: &mut Reassembler,
+) -> Result<Option<Cow<'a, [u8]>>, ConsumerError> {
+    let chunk_error = |source| ConsumerError::Chunk {
+        topic: message.topic().to_string(),
+        partition: message.partition(),
+        offset: message.offset(),
+        source,
+    };
+
+    let chunk_header = match message.headers() {
+        Some(headers) => {
+            ChunkHeader::from_headers((0..headers.count()).filter_map(|i| headers.get(i)))
+                .map_err(chunk_error)?
+        }
+        None => None,
+    };
+
+    let payload = message.payload().unwrap_or_default();
+    match chunk_header {
+        Some(chunk_header) => Ok(reassembler
+            .push(message.topic(), message.partition(), chunk_header, payload)
+            .map_err(chunk_error)?
+            .map(Cow::Owned)),
+        None => Ok(Some(Cow::Borrowed(payload))),
+    }
+}
+
+/// An item of the merged stream of Kafka messages and expiry ticks
+enum Input<M> {
+    Message(KafkaResult<M>),
+    ExpireChunks,
+}
+
+/// Ticks often enough that incomplete chunk sets are reported soon after the timeout,
+/// even when no more messages arrive
+fn expiry_ticks<M>(chunk_timeout: Duration) -> impl Stream<Item = Input<M>> {
+    let period = (chunk_timeout / 2).max(MIN_EXPIRY_PERIOD);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    stream::unfold(interval, |mut interval| async move {
+        interval.tick().await;
+        Some((Input::ExpireChunks, interval))
+    })
+}
+
+fn process<T, M, F>(
+    input: Input<M>,
+    state: &mut StreamState,
+    decode: F,
+) -> Vec<Result<ConsumedMessage<T>, ConsumerError>>
+where
+    M: Message,
+    F: Fn(&M, &[u8], &mut DeltaDecoder) -> Result<ConsumedMessage<T>, ConsumerError>,
+{
+    match input {
+        Input::ExpireChunks => state
+            .reassembler
+            .expire()
+            .into_iter()
+            .map(|incomplete| {
+                Err(ConsumerError::IncompleteMessage {
+                    topic: incomplete.topic,
+                    partition: incomplete.partition,
+                    id: incomplete.id,
+                    received: incomplete.received,
+                    count: incomplete.count,
+                })
+            })
+            .collect(),
+        Input::Message(Ok(message)) => match reassemble(&message, &mut state.reassembler) {
+            Ok(Some(payload)) => vec![decode(&message, &payload, &mut state.delta_decoder)],
+            Ok(None) => Vec::new(),
+            Err(e) => vec![Err(e)],
+        },
+        Input::Message(Err(e)) => vec![Err(e.into())],
+    }
+}
+
+/// The messages of the consumer merged with the expiry ticks
+fn inputs<C>(
+    consumer: &StreamConsumer<C>,
+    chunk_timeout: Duration,
+) -> impl Stream<Item = Input<BorrowedMessage<'_>>>
+where
+    C: ConsumerContext + 'static,
+{
+    stream::select(
+        consumer.stream().map(Input::Message),
+        expiry_ticks(chunk_timeout),
+    )
+}
+
+impl<T> ConsumedMessage<T> {
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> ConsumedMessage<U> {
+        ConsumedMessage {
+            topic: self.topic,
+            partition: self.partition,
+            offset: self.offset,
+            key: self.key,
+            timestamp: self.timestamp,
+            message: f(self.message),
+        }
+    }
+}
+
+/// Stream of messages of a single type, for consumers subscribed to one topic.
+/// Chunked messages are reassembled, chunk sets that stay incomplete for longer
+/// than `chunk_timeout` are reported as errors, whether or not more messages arrive,
+/// so the stream has to be polled from a Tokio runtime. Delta encoded account data is restored.
+/// Errors are yielded per message, so the stream can be continued after a decode error.
+pub fn typed_stream<T, C>(
+    consumer: &StreamConsumer<C>,
+    chunk_timeout: Duration,
+) -> impl Stream<Item = Result<ConsumedMessage<T>, ConsumerError>> + '_
+where
+    T: ConsumerEvent + 'static,
+    C: ConsumerContext + 'static,
+{
+    let mut state = StreamState::new(chunk_timeout);
+    inputs(consumer, chunk_timeout)
+        .flat_map(move |input| stream::iter(process(input, &mut state, decode_payload)))
+}
+
+/// Stream of all the plugin messages, decoded according to the topic they were read from
+pub fn event_stream<'a, C>(
+    consumer: &'a StreamConsumer<C>,
+    topics: &'a Topics,
+    chunk_timeout: Duration,
+) -> impl Stream<Item = Result<ConsumedMessage<GeyserEvent>, ConsumerError>> + 'a
+where
+    C: ConsumerContext + 'static,
+{
+    let mut state = StreamState::new(chunk_timeout);
+    inputs(consumer, chunk_timeout).flat_map(move |input| {
+        stream::iter(process(
+            input,
+            &mut state,
+            |message, payload, delta_decoder| decode_event(message, payload, delta_decoder, topics),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use rdkafka::message::{OwnedHeaders, OwnedMessage};
+
+    use super::*;
+    use crate::{
+        chunking,
+        delta::{DataDelta, DeltaEncoder, DEFAULT_MAX_BYTES},
+        kafka_structs::{
+            KafkaReplicaAccountInfo, KafkaReplicaAccountInfoVersions, KafkaSlotStatus,
+        },
+    };
+
+    const ACCOUNT_TOPIC: &str = "update_account";
+    const SLOT_TOPIC: &str = "update_slot";
+
+    fn topics() -> Topics {
+        Topics {
+            update_account_topic: ACCOUNT_TOPIC.to_string(),
+            update_slot_topic: SLOT_TOPIC.to_string(),
+            notify_transaction_topic: "notify_transaction".to_string(),
+            notify_block_topic: "notify_block".to_string(),
+        }
+    }
+
+    fn message(
+        topic: &str,
+        offset: i64,
+        payload: &[u8],
+        headers: Option<OwnedHeaders>,
+    ) -> Input<OwnedMessage> {
+        Input::Message(Ok(OwnedMessage::new(
+            Some(payload.to_vec()),
+            Some(b"key".to_vec()),
+            topic.to_string(),
+            Timestamp::CreateTime(1000),
+            0,
+            offset,
+            headers,
+        )))
+    }
+
+    fn retrieved_time() -> NaiveDateTime {
+        NaiveDateTime::from_timestamp_opt(0, 0).unwrap()
+    }
+
+    fn slot_status(slot: u64) -> Vec<u8> {
+        serde_json::to_vec(&UpdateSlotStatus {
+            slot,
+            parent: Some(slot - 1),
+            status: KafkaSlotStatus::Confirmed,
+            retrieved_time: retrieved_time(),
+        })
+        .unwrap()
+    }
+
+    fn update_account(write_version: u64, data: Vec<u8>) -> UpdateAccount {
+        UpdateAccount {
+            account: KafkaReplicaAccountInfoVersions::V0_0_1(KafkaReplicaAccountInfo {
+                pubkey: vec![1; 32],
+                lamports: 1,
+                owner: vec![2; 32],
+                executable: false,
+                rent_epoch: 0,
+                data,
+                write_version,
+            }),
+            slot: write_version,
+            is_startup: false,
+            retrieved_time: retrieved_time(),
+            data_compression: None,
+            data_delta: None,
+        }
+    }
+
+    fn process_typed<T: ConsumerEvent>(
+        state: &mut StreamState,
+        input: Input<OwnedMessage>,
+    ) -> Vec<Result<ConsumedMessage<T>, ConsumerError>> {
+        process(input, state, decode_payload::<T, OwnedMessage>)
+    }
+
+    #[test]
+    fn decodes_typed_messages() {
+        let mut state = StreamState::new(Duration::from_secs(10));
+
+        let mut decoded = process_typed::<UpdateSlotStatus>(
+            &mut state,
+            message(SLOT_TOPIC, 7, &slot_status(5), None),
+        );
+        let decoded = decoded.pop().unwrap().unwrap();
+        assert_eq!(decoded.topic, SLOT_TOPIC);
+        assert_eq!(decoded.offset, 7);
+        assert_eq!(decoded.key, Some(b"key".to_vec()));
+        assert_eq!(decoded.timestamp, Some(1000));
+        assert_eq!(decoded.message.slot, 5);
+
+        let mut errors =
+            process_typed::<UpdateSlotStatus>(&mut state, message(SLOT_TOPIC, 8, b"{", None));
+        assert!(matches!(
+            errors.pop().unwrap(),
+            Err(ConsumerError::Decode { offset: 8, .. })
+        ));
+        let mut errors =
+            process_typed::<UpdateSlotStatus>(&mut state, message(SLOT_TOPIC, 9, b"", None));
+        assert!(matches!(
+            errors.pop().unwrap(),
+            Err(ConsumerError::EmptyPayload { offset: 9, .. })
+        ));
+    }
+
+    #[test]
+    fn decodes_events_by_topic() {
+        let mut state = StreamState::new(Duration::from_secs(10));
+        let topics = topics();
+        let decode = |message: &OwnedMessage, payload: &[u8], delta_decoder: &mut DeltaDecoder| {
+            decode_event(message, payload, delta_decoder, &topics)
+        };
+
+        let account = serde_json::to_vec(&update_account(1, vec![3; 10])).unwrap();
+        let events: Vec<_> = [
+            message(ACCOUNT_TOPIC, 0, &account, None),
+            message(SLOT_TOPIC, 0, &slot_status(5), None),
+            message("other", 0, &slot_status(5), None),
+        ]
+        .into_iter()
+        .flat_map(|input| process(input, &mut state, decode))
+        .collect();
+
+        assert!(matches!(
+            &events[0],
+            Ok(ConsumedMessage { message: GeyserEvent::UpdateAccount(update), .. })
+                if update.account.data() == [3; 10]
+        ));
+        assert!(matches!(
+            &events[1],
+            Ok(ConsumedMessage {
+                message: GeyserEvent::UpdateSlotStatus(UpdateSlotStatus { slot: 5, .. }),
+                ..
+            })
+        ));
+        assert!(matches!(
+            &events[2],
+            Err(ConsumerError::UnknownTopic { .. })
+        ));
+    }
+
+    #[test]
+    fn reassembles_chunked_messages() {
+        let mut state = StreamState::new(Duration::from_secs(10));
+        let payload = serde_json::to_vec(&update_account(1, vec![3; 4096])).unwrap();
+        let chunks = chunking::split(&payload, chunking::MIN_CHUNK_SIZE);
+        let count = chunks.len() as u32;
+        assert!(count > 2);
+
+        let mut decoded = Vec::new();
+        for (index, chunk) in chunks.iter().enumerate().rev() {
+            let headers = ChunkHeader {
+                id: "a".to_string(),
+                index: index as u32,
+                count,
+            }
+            .to_headers()
+            .into_iter()
+            .fold(OwnedHeaders::new(), |headers, (name, value)| {
+                headers.add(name, value.as_str())
+            });
+            decoded.extend(process_typed::<UpdateAccount>(
+                &mut state,
+                message(ACCOUNT_TOPIC, index as i64, chunk, Some(headers)),
+            ));
+        }
+
+        assert_eq!(decoded.len(), 1);
+        let decoded = decoded.pop().unwrap().unwrap();
+        assert_eq!(decoded.offset, 0);
+        assert_eq!(decoded.message.account.data(), [3; 4096]);
+    }
+
+    #[test]
+    fn reports_incomplete_chunk_sets() {
+        let mut state = StreamState::new(Duration::ZERO);
+        let headers = ChunkHeader {
+            id: "a".to_string(),
+            index: 0,
+            count: 2,
+        }
+        .to_headers()
+        .into_iter()
+        .fold(OwnedHeaders::new(), |headers, (name, value)| {
+            headers.add(name, value.as_str())
+        });
+
+        assert!(process_typed::<UpdateAccount>(
+            &mut state,
+            message(ACCOUNT_TOPIC, 0, b"{", Some(headers))
+        )
+        .is_empty());
+        let mut errors = process_typed::<UpdateAccount>(&mut state, Input::ExpireChunks);
+        assert!(matches!(
+            errors.pop().unwrap(),
+            Err(ConsumerError::IncompleteMessage {
+                received: 1,
+                count: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn restores_delta_encoded_accounts() {
+        let mut state = StreamState::new(Duration::from_secs(10));
+        let mut encoder = DeltaEncoder::new(10, 16, 100, DEFAULT_MAX_BYTES);
+        let mut data = vec![0; 1024];
+
+        for write_version in 0..3 {
+            data[write_version as usize * 100] = 1;
+            let mut update = update_account(write_version, data.clone());
+            encoder.encode(&mut update);
+            assert_eq!(
+                matches!(update.data_delta, Some(DataDelta::Delta { .. })),
+                write_version > 0
+            );
+
+            let payload = serde_json::to_vec(&update).unwrap();
+            let mut decoded = process_typed::<UpdateAccount>(
+                &mut state,
+                message(ACCOUNT_TOPIC, write_version as i64, &payload, None),
+            );
+            let decoded = decoded.pop().unwrap().unwrap();
+            assert_eq!(decoded.message.account.data(), data.as_slice());
+            assert_eq!(decoded.message.data_delta, None);
+        }
+
+        // The base of the diff was never consumed
+        let mut state = StreamState::new(Duration::from_secs(10));
+        data[500] = 1;
+        let mut update = update_account(3, data);
+        encoder.encode(&mut update);
+        let payload = serde_json::to_vec(&update).unwrap();
+        let mut errors =
+            process_typed::<UpdateAccount>(&mut state, message(ACCOUNT_TOPIC, 3, &payload, None));
+        assert!(matches!(
+            errors.pop().unwrap(),
+            Err(ConsumerError::Delta {
+                source: DeltaError::MissingBase { .. },
+                ..
+            })
+        ));
+    }
+}
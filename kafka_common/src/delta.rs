@@ -0,0 +1,588 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    error, fmt, mem,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{compression::MAX_DECOMPRESSED_SIZE, kafka_structs::UpdateAccount};
+
+/// Unchanged runs shorter than this are included in a segment instead of starting a new one,
+/// since every segment costs 8 bytes of offset and length
+const MIN_GAP: usize = 8;
+
+/// Default limit of the account data kept by a `DeltaEncoder` or a `DeltaDecoder`
+pub const DEFAULT_MAX_BYTES: usize = 1024 * 1024 * 1024;
+
+/// Marks how the data of an account update is encoded relative to the previous update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataDelta {
+    /// The update carries the full account data, decoders should remember it
+    Keyframe,
+    /// The update carries a binary diff against the data of the update with `base_write_version`
+    Delta { base_write_version: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaError {
+    /// The diff is truncated or refers to bytes outside of the data
+    Malformed,
+    /// The decoder has not seen the update the diff is based on
+    MissingBase {
+        pubkey: Vec<u8>,
+        base_write_version: u64,
+        known_write_version: Option<u64>,
+    },
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeltaError::Malformed => write!(f, "Malformed account data diff"),
+            DeltaError::MissingBase {
+                pubkey,
+                base_write_version,
+                known_write_version,
+            } => write!(
+                f,
+                "Diff for account {pubkey:?} is based on write_version {base_write_version}, \
+                 known write_version is {known_write_version:?}"
+            ),
+        }
+    }
+}
+
+impl error::Error for DeltaError {}
+
+/// Encodes the difference between two versions of account data.
+///
+/// The diff consists of the new data length followed by segments of changed bytes,
+/// every segment is its offset, its length and the bytes themselves.
+/// All numbers are little-endian u32.
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    result.extend_from_slice(&(new.len() as u32).to_le_bytes());
+
+    let changed = |i: usize| i >= old.len() || old[i] != new[i];
+    let mut i = 0;
+    while i < new.len() {
+        if !changed(i) {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        let mut end = i + 1;
+        let mut unchanged = 0;
+        i += 1;
+        while i < new.len() && unchanged < MIN_GAP {
+            if changed(i) {
+                end = i + 1;
+                unchanged = 0;
+            } else {
+                unchanged += 1;
+            }
+            i += 1;
+        }
+
+        result.extend_from_slice(&(start as u32).to_le_bytes());
+        result.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        result.extend_from_slice(&new[start..end]);
+        i = end;
+    }
+
+    result
+}
+
+fn read_u32(delta: &[u8], position: &mut usize) -> Result<usize, DeltaError> {
+    let bytes = delta
+        .get(*position..*position + 4)
+        .ok_or(DeltaError::Malformed)?;
+    *position += 4;
+    Ok(u32::from_le_bytes(bytes.try_into().expect("Slice has 4 bytes")) as usize)
+}
+
+/// Rebuilds the new data from the old data and the output of `diff`.
+/// Diffs to more than `MAX_DECOMPRESSED_SIZE` bytes are rejected before anything is allocated
+pub fn apply(old: &[u8], delta: &[u8]) -> Result<Vec<u8>, DeltaError> {
+    let mut position = 0;
+    let new_len = read_u32(delta, &mut position)?;
+    if new_len > MAX_DECOMPRESSED_SIZE {
+        return Err(DeltaError::Malformed);
+    }
+
+    let mut result = old.to_vec();
+    result.resize(new_len, 0);
+    let mut covered = old.len().min(new_len);
+
+    while position < delta.len() {
+        let offset = read_u32(delta, &mut position)?;
+        let len = read_u32(delta, &mut position)?;
+        let bytes = delta
+            .get(position..position + len)
+            .ok_or(DeltaError::Malformed)?;
+        result
+            .get_mut(offset..offset + len)
+            .ok_or(DeltaError::Malformed)?
+            .copy_from_slice(bytes);
+        position += len;
+
+        if offset <= covered {
+            covered = covered.max(offset + len);
+        }
+    }
+
+    // Bytes past the end of the old data must all come from the diff
+    if covered < new_len {
+        return Err(DeltaError::Malformed);
+    }
+
+    Ok(result)
+}
+
+struct CachedAccount {
+    write_version: u64,
+    data: Vec<u8>,
+    deltas_since_keyframe: u32,
+    // Position in the order of the updates, the least recently updated account is dropped first
+    sequence: u64,
+}
+
+/// The last data of the accounts, within a limit of bytes
+struct AccountCache {
+    accounts: HashMap<Vec<u8>, CachedAccount>,
+    order: BTreeMap<u64, Vec<u8>>,
+    next_sequence: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl AccountCache {
+    fn new(max_bytes: usize) -> Self {
+        AccountCache {
+            accounts: HashMap::new(),
+            order: BTreeMap::new(),
+            next_sequence: 0,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn get(&self, pubkey: &[u8]) -> Option<&CachedAccount> {
+        self.accounts.get(pubkey)
+    }
+
+    /// Replaces the data of the account, then drops the least recently updated accounts
+    /// until the data is within the limit. The account itself is kept even if it is larger
+    fn insert(&mut self, pubkey: Vec<u8>, write_version: u64, data: Vec<u8>, deltas: u32) {
+        self.remove(&pubkey);
+
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.bytes += pubkey.len() + data.len();
+        self.order.insert(sequence, pubkey.clone());
+        self.accounts.insert(
+            pubkey,
+            CachedAccount {
+                write_version,
+                data,
+                deltas_since_keyframe: deltas,
+                sequence,
+            },
+        );
+
+        while self.bytes > self.max_bytes && self.accounts.len() > 1 {
+            let oldest = match self.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, pubkey: &[u8]) {
+        if let Some(account) = self.accounts.remove(pubkey) {
+            self.order.remove(&account.sequence);
+            self.bytes -= pubkey.len() + account.data.len();
+        }
+    }
+}
+
+/// Replaces account data with diffs against the previous update of the same account.
+/// Updates must be passed in the order they are published.
+pub struct DeltaEncoder {
+    accounts: AccountCache,
+    keyframe_interval: u32,
+    min_size: usize,
+    max_accounts: usize,
+}
+
+impl DeltaEncoder {
+    /// * `keyframe_interval` - maximum number of diffs between two full updates of an account
+    /// * `min_size` - accounts with less data are always sent in full and are not tracked
+    /// * `max_accounts` - maximum number of accounts whose last data is kept
+    /// * `max_bytes` - maximum size of the kept data, the least recently updated accounts
+    ///   are forgotten first and their next update is sent as a keyframe
+    pub fn new(
+        keyframe_interval: u32,
+        min_size: usize,
+        max_accounts: usize,
+        max_bytes: usize,
+    ) -> Self {
+        DeltaEncoder {
+            accounts: AccountCache::new(max_bytes),
+            keyframe_interval,
+            min_size,
+            max_accounts,
+        }
+    }
+
+    /// Encodes the account data in place and sets `data_delta` accordingly
+    pub fn encode(&mut self, update_account: &mut UpdateAccount) {
+        let pubkey = update_account.account.pubkey().to_vec();
+        let write_version = update_account.account.write_version();
+        let data = update_account.account.data_mut();
+
+        if data.len() < self.min_size {
+            self.accounts.remove(&pubkey);
+            return;
+        }
+
+        match self.accounts.get(&pubkey) {
+            Some(previous) if previous.deltas_since_keyframe < self.keyframe_interval => {
+                let delta = diff(&previous.data, data);
+                if delta.len() < data.len() {
+                    let base_write_version = previous.write_version;
+                    let deltas = previous.deltas_since_keyframe + 1;
+                    let data = mem::replace(data, delta);
+                    self.accounts.insert(pubkey, write_version, data, deltas);
+                    update_account.data_delta = Some(DataDelta::Delta { base_write_version });
+                    return;
+                }
+            }
+            Some(_) => (),
+            None if self.accounts.accounts.len() >= self.max_accounts => return,
+            None => (),
+        }
+
+        self.accounts.insert(pubkey, write_version, data.clone(), 0);
+        update_account.data_delta = Some(DataDelta::Keyframe);
+    }
+
+    /// Forgets the data of an account, so its next update is sent as a keyframe.
+    /// Called when an update could not be delivered, since the later diffs would be based on it
+    pub fn remove(&mut self, pubkey: &[u8]) {
+        self.accounts.remove(pubkey);
+    }
+
+    /// Number of accounts whose last data is kept
+    pub fn tracked_accounts(&self) -> usize {
+        self.accounts.accounts.len()
+    }
+
+    /// Size of the kept data and pubkeys
+    pub fn tracked_bytes(&self) -> usize {
+        self.accounts.bytes
+    }
+}
+
+/// Restores the full account data on the consumer side.
+/// Keeps the last data of the accounts that have been sent as a keyframe, up to a limit of bytes.
+/// The limit should not be lower than the one of the encoder, the diffs of an account which
+/// has been dropped fail with `MissingBase` until its next keyframe.
+pub struct DeltaDecoder {
+    accounts: AccountCache,
+}
+
+impl Default for DeltaDecoder {
+    fn default() -> Self {
+        Self::with_max_bytes(DEFAULT_MAX_BYTES)
+    }
+}
+
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Once the kept data is larger than `max_bytes`, the least recently updated accounts are dropped
+    pub fn with_max_bytes(max_bytes: usize) -> Self {
+        DeltaDecoder {
+            accounts: AccountCache::new(max_bytes),
+        }
+    }
+
+    /// Replaces a diff with the full account data and clears `data_delta`.
+    /// Updates without `data_delta` are left as is.
+    pub fn decode(&mut self, update_account: &mut UpdateAccount) -> Result<(), DeltaError> {
+        let data_delta = match update_account.data_delta {
+            Some(data_delta) => data_delta,
+            None => return Ok(()),
+        };
+
+        let pubkey = update_account.account.pubkey().to_vec();
+        let write_version = update_account.account.write_version();
+        let data = update_account.account.data_mut();
+
+        if let DataDelta::Delta { base_write_version } = data_delta {
+            match self.accounts.get(&pubkey) {
+                Some(known) if known.write_version == base_write_version => {
+                    *data = apply(&known.data, data)?;
+                }
+                known => {
+                    return Err(DeltaError::MissingBase {
+                        known_write_version: known.map(|known| known.write_version),
+                        pubkey,
+                        base_write_version,
+                    });
+                }
+            }
+        }
+
+        self.accounts.insert(pubkey, write_version, data.clone(), 0);
+        update_account.data_delta = None;
+        Ok(())
+    }
+
+    /// Forgets the data of an account, e.g. after it has been closed
+    pub fn remove(&mut self, pubkey: &[u8]) {
+        self.accounts.remove(pubkey);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+    use crate::kafka_structs::{KafkaReplicaAccountInfo, KafkaReplicaAccountInfoVersions};
+
+    const PUBKEY: [u8; 32] = [1; 32];
+
+    fn update_account(write_version: u64, data: Vec<u8>) -> UpdateAccount {
+        UpdateAccount {
+            account: KafkaReplicaAccountInfoVersions::V0_0_1(KafkaReplicaAccountInfo {
+                pubkey: PUBKEY.to_vec(),
+                lamports: 1,
+                owner: vec![2; 32],
+                executable: false,
+                rent_epoch: 0,
+                data,
+                write_version,
+            }),
+            slot: write_version,
+            is_startup: false,
+            retrieved_time: NaiveDateTime::from_timestamp_opt(0, 0).unwrap(),
+            data_compression: None,
+            data_delta: None,
+        }
+    }
+
+    fn versions() -> Vec<Vec<u8>> {
+        let mut data = vec![0; 1024];
+        (0..5)
+            .map(|i| {
+                data[i * 100] = i as u8 + 1;
+                data.clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_round_trip() {
+        let old: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let mut changed = old.clone();
+        changed[10] = 0;
+        changed[150] = 0;
+
+        for new in [
+            changed,
+            old[..100].to_vec(),
+            [old.clone(), vec![7; 50]].concat(),
+        ] {
+            assert_eq!(apply(&old, &diff(&old, &new)).unwrap(), new);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_diff() {
+        let old = vec![0; 10];
+        assert_eq!(apply(&old, &[1, 0]), Err(DeltaError::Malformed));
+        // Grows the data without providing the new bytes
+        assert_eq!(
+            apply(&old, &20u32.to_le_bytes()),
+            Err(DeltaError::Malformed)
+        );
+        assert_eq!(
+            apply(&old, &u32::MAX.to_le_bytes()),
+            Err(DeltaError::Malformed)
+        );
+    }
+
+    #[test]
+    fn encoder_decoder_round_trip() {
+        let mut encoder = DeltaEncoder::new(10, 16, 100, DEFAULT_MAX_BYTES);
+        let mut decoder = DeltaDecoder::new();
+
+        for (write_version, data) in versions().into_iter().enumerate() {
+            let mut update = update_account(write_version as u64, data.clone());
+            encoder.encode(&mut update);
+            if write_version == 0 {
+                assert_eq!(update.data_delta, Some(DataDelta::Keyframe));
+            } else {
+                assert_eq!(
+                    update.data_delta,
+                    Some(DataDelta::Delta {
+                        base_write_version: write_version as u64 - 1
+                    })
+                );
+                assert!(update.account.data().len() < data.len());
+            }
+
+            decoder.decode(&mut update).unwrap();
+            assert_eq!(update.account.data(), data.as_slice());
+            assert_eq!(update.data_delta, None);
+        }
+    }
+
+    #[test]
+    fn sends_keyframe_after_interval() {
+        let mut encoder = DeltaEncoder::new(2, 16, 100, DEFAULT_MAX_BYTES);
+        let kinds: Vec<_> = versions()
+            .into_iter()
+            .enumerate()
+            .map(|(write_version, data)| {
+                let mut update = update_account(write_version as u64, data);
+                encoder.encode(&mut update);
+                matches!(update.data_delta, Some(DataDelta::Keyframe))
+            })
+            .collect();
+
+        assert_eq!(kinds, [true, false, false, true, false]);
+    }
+
+    #[test]
+    fn skips_small_and_untracked_accounts() {
+        let mut encoder = DeltaEncoder::new(10, 2048, 100, DEFAULT_MAX_BYTES);
+        let mut update = update_account(0, vec![0; 1024]);
+        encoder.encode(&mut update);
+        assert_eq!(update.data_delta, None);
+
+        let mut encoder = DeltaEncoder::new(10, 16, 0, DEFAULT_MAX_BYTES);
+        let mut update = update_account(0, vec![0; 1024]);
+        encoder.encode(&mut update);
+        assert_eq!(update.data_delta, None);
+        assert_eq!(encoder.tracked_accounts(), 0);
+    }
+
+    #[test]
+    fn out_of_order_delta_is_missing_base() {
+        let mut encoder = DeltaEncoder::new(10, 16, 100, DEFAULT_MAX_BYTES);
+        let mut decoder = DeltaDecoder::new();
+
+        let mut updates: Vec<_> = versions()
+            .into_iter()
+            .take(3)
+            .enumerate()
+            .map(|(write_version, data)| {
+                let mut update = update_account(write_version as u64, data);
+                encoder.encode(&mut update);
+                update
+            })
+            .collect();
+
+        decoder.decode(&mut updates[0]).unwrap();
+        // The diff against write_version 1 arrives before write_version 1 itself
+        assert_eq!(
+            decoder.decode(&mut updates[2]),
+            Err(DeltaError::MissingBase {
+                pubkey: PUBKEY.to_vec(),
+                base_write_version: 1,
+                known_write_version: Some(0),
+            })
+        );
+
+        let mut decoder = DeltaDecoder::new();
+        assert!(matches!(
+            decoder.decode(&mut updates[1]),
+            Err(DeltaError::MissingBase {
+                known_write_version: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn removed_account_is_sent_as_keyframe() {
+        let mut encoder = DeltaEncoder::new(10, 16, 100, DEFAULT_MAX_BYTES);
+        let mut decoder = DeltaDecoder::new();
+        let versions = versions();
+
+        let mut first = update_account(0, versions[0].clone());
+        encoder.encode(&mut first);
+        decoder.decode(&mut first).unwrap();
+
+        // The second update is lost, so the encoder forgets the account
+        let mut lost = update_account(1, versions[1].clone());
+        encoder.encode(&mut lost);
+        encoder.remove(&PUBKEY);
+
+        let mut third = update_account(2, versions[2].clone());
+        encoder.encode(&mut third);
+        assert_eq!(third.data_delta, Some(DataDelta::Keyframe));
+        decoder.decode(&mut third).unwrap();
+        assert_eq!(third.account.data(), versions[2].as_slice());
+    }
+
+    fn account(pubkey: u8, write_version: u64, data: Vec<u8>) -> UpdateAccount {
+        let mut update = update_account(write_version, data);
+        if let KafkaReplicaAccountInfoVersions::V0_0_1(account_info) = &mut update.account {
+            account_info.pubkey = vec![pubkey; 32];
+        }
+        update
+    }
+
+    #[test]
+    fn encoder_forgets_the_least_recently_updated_accounts() {
+        // Room for the data and pubkeys of two accounts
+        let mut encoder = DeltaEncoder::new(10, 16, 100, 2 * (32 + 1024));
+
+        for pubkey in [1, 2, 1, 3] {
+            encoder.encode(&mut account(pubkey, 0, vec![pubkey; 1024]));
+        }
+        assert_eq!(encoder.tracked_accounts(), 2);
+        assert_eq!(encoder.tracked_bytes(), 2 * (32 + 1024));
+
+        // Account 2 has been dropped, so its next update is a keyframe
+        let mut update = account(2, 1, vec![2; 1024]);
+        encoder.encode(&mut update);
+        assert_eq!(update.data_delta, Some(DataDelta::Keyframe));
+        let mut update = account(1, 1, vec![1; 1024]);
+        encoder.encode(&mut update);
+        assert_eq!(update.data_delta, Some(DataDelta::Keyframe));
+    }
+
+    #[test]
+    fn decoder_forgets_the_least_recently_updated_accounts() {
+        let mut encoder = DeltaEncoder::new(10, 16, 100, DEFAULT_MAX_BYTES);
+        let mut decoder = DeltaDecoder::with_max_bytes(32 + 1024);
+        let versions = versions();
+
+        let mut first = account(1, 0, versions[0].clone());
+        encoder.encode(&mut first);
+        decoder.decode(&mut first).unwrap();
+        let mut other = account(2, 0, versions[0].clone());
+        encoder.encode(&mut other);
+        decoder.decode(&mut other).unwrap();
+
+        let mut second = account(1, 1, versions[1].clone());
+        encoder.encode(&mut second);
+        assert!(matches!(
+            decoder.decode(&mut second),
+            Err(DeltaError::MissingBase {
+                known_write_version: None,
+                ..
+            })
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{fmt, io};
 
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
@@ -19,6 +19,9 @@ use solana_sdk::{clock::UnixTimestamp, signature::Signature};
 use solana_transaction_status::{InnerInstructions, Reward};
 use solana_transaction_status::{Rewards, TransactionStatusMeta, TransactionTokenBalance};
 
+use crate::compression::DataCompression;
+use crate::delta::DataDelta;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 /// Information about an account being updated
 pub struct KafkaReplicaAccountInfo {
@@ -368,6 +371,43 @@ pub enum KafkaReplicaAccountInfoVersions {
     V0_0_2(KafkaReplicaAccountInfoV2),
 }
 
+impl KafkaReplicaAccountInfoVersions {
+    pub fn pubkey(&self) -> &[u8] {
+        match self {
+            KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => &account_info.pubkey,
+            KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => &account_info.pubkey,
+        }
+    }
+
+    pub fn owner(&self) -> &[u8] {
+        match self {
+            KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => &account_info.owner,
+            KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => &account_info.owner,
+        }
+    }
+
+    pub fn write_version(&self) -> u64 {
+        match self {
+            KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => account_info.write_version,
+            KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => account_info.write_version,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match self {
+            KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => &account_info.data,
+            KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => &account_info.data,
+        }
+    }
+
+    pub fn data_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            KafkaReplicaAccountInfoVersions::V0_0_1(account_info) => &mut account_info.data,
+            KafkaReplicaAccountInfoVersions::V0_0_2(account_info) => &mut account_info.data,
+        }
+    }
+}
+
 impl From<ReplicaAccountInfoVersions<'_>> for KafkaReplicaAccountInfoVersions {
     fn from(account_info: ReplicaAccountInfoVersions) -> Self {
         match account_info {
@@ -417,12 +457,51 @@ impl From<&ReplicaBlockInfo<'_>> for KafkaReplicaBlockInfo {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from = "UpdateAccountEnvelope")]
 pub struct UpdateAccount {
     #[serde(flatten)]
     pub account: KafkaReplicaAccountInfoVersions,
     pub slot: u64,
     pub is_startup: bool,
     pub retrieved_time: NaiveDateTime,
+    /// Codec the account data is compressed with, if any.
+    /// The data is decompressed transparently on deserialization.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_compression: Option<DataCompression>,
+    /// Set if the account data is a keyframe or a diff for delta decoding
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_delta: Option<DataDelta>,
+}
+
+/// Wire representation of `UpdateAccount` before the account data is decompressed
+#[derive(Deserialize)]
+struct UpdateAccountEnvelope {
+    #[serde(flatten)]
+    account: KafkaReplicaAccountInfoVersions,
+    slot: u64,
+    is_startup: bool,
+    retrieved_time: NaiveDateTime,
+    #[serde(default)]
+    data_compression: Option<DataCompression>,
+    #[serde(default)]
+    data_delta: Option<DataDelta>,
+}
+
+impl TryFrom<UpdateAccountEnvelope> for UpdateAccount {
+    type Error = io::Error;
+
+    fn try_from(envelope: UpdateAccountEnvelope) -> Result<Self, Self::Error> {
+        let mut update_account = UpdateAccount {
+            account: envelope.account,
+            slot: envelope.slot,
+            is_startup: envelope.is_startup,
+            retrieved_time: envelope.retrieved_time,
+            data_compression: envelope.data_compression,
+            data_delta: envelope.data_delta,
+        };
+        update_account.decompress_data()?;
+        Ok(update_account)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
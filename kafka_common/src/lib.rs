@@ -1,3 +1,8 @@
+pub mod chunking;
+pub mod compression;
+#[cfg(feature = "consumer")]
+pub mod consumer;
+pub mod delta;
 pub mod hash;
 pub mod kafka_structs;
 pub mod message_type;